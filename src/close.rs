@@ -1,224 +1,166 @@
-use std::{str::FromStr, sync::Arc, time::Duration};
+use std::str::FromStr;
 
-use anyhow::{Context, Result};
-use futures::future::join_all;
-use solana_client::{nonblocking::rpc_client::RpcClient, rpc_request::TokenAccountsFilter};
-use solana_sdk::{
-    instruction::Instruction,
-    pubkey::{self, Pubkey},
-    signature::{read_keypair_file, Keypair},
-    signer::Signer,
-    system_instruction,
-    transaction::Transaction,
-};
+use log::{error, info};
+use solana_client::rpc_request::TokenAccountsFilter;
+use solana_sdk::{instruction::Instruction, pubkey::Pubkey, signature::Signature};
 use spl_associated_token_account::get_associated_token_address;
 use spl_token::instruction::close_account;
-use std::fs;
-use tokio::{sync::Semaphore, time::sleep};
 
+use crate::error::Result;
+use crate::outcome::Failure;
+use crate::report::{join_entries, CloseEntry, CloseReport, Status};
+use crate::rpc_pool::RpcPool;
+use crate::send_and_check::sign_transaction;
+use crate::wallet_set::{SharedSigner, WalletSet, WalletSigner};
 use crate::Tool;
-impl Tool {
-    pub async fn close(
-        &self,
-        sub_keypair_folder: String,
-        main_key_file: String,
-        token_mint: Option<String>,
-    ) -> Result<()> {
-        match token_mint {
-            Some(token_mint_address) => {
-                let main_keypair = Arc::new(
-                    read_keypair_file(main_key_file)
-                        .expect("Failed to generate the main keypair from file"),
-                );
-                let token_mint_pubkey = Pubkey::from_str(&token_mint_address)?;
-                let semaphore = Arc::new(Semaphore::new(20));
-                match fs::read_dir(sub_keypair_folder) {
-                    Ok(folder) => {
-                        let mut tasks = vec![];
-                        for file in folder {
-                            let file_entry = file.unwrap();
-                            let file_path = file_entry.path();
-                            match read_keypair_file(file_path.clone()) {
-                                Ok(sub_keypair) => {
-                                    let associated_token_address = get_associated_token_address(
-                                        &sub_keypair.pubkey(),
-                                        &token_mint_pubkey,
-                                    );
-                                    let main_keypair_clone = main_keypair.clone();
-                                    let rpc_client_clone = self.rpc_client.clone();
-                                    let semaphore_clone = semaphore.clone();
-                                    let task = tokio::spawn(async move {
-                                        let _permit = semaphore_clone.acquire().await.unwrap();
-                                        let close_account_ins = close_account(
-                                            &spl_token::id(),
-                                            &associated_token_address,
-                                            &sub_keypair.pubkey(),
-                                            &sub_keypair.pubkey(),
-                                            &[&sub_keypair.pubkey()],
-                                        )
-                                        .unwrap();
-                                        if check_token_account_exist(
-                                            &rpc_client_clone,
-                                            &associated_token_address,
-                                        )
-                                        .await
-                                        {
-                                            let _ = send_txn(
-                                                &rpc_client_clone,
-                                                close_account_ins,
-                                                &main_keypair_clone,
-                                                &sub_keypair,
-                                            )
-                                            .await;
-                                        }
-                                        sleep(Duration::from_millis(50)).await;
-                                    });
-                                    tasks.push(task);
-                                }
-                                Err(e) => {
-                                    eprintln!("{}", e)
-                                }
-                            }
-                        }
-                        let results = join_all(tasks).await;
-                        for result in results {
-                            match result {
-                                Ok(_) => {
-                                    // 任务成功完成
-                                    println!("Task completed successfully");
-                                }
-                                Err(e) => {
-                                    // tokio::spawn 本身失败
-                                    println!("Task panicked: {:?}", e);
-                                }
-                            }
-                        }
-                    }
-                    Err(e) => {
-                        eprintln!("{}", e)
-                    }
+
+/// Close the empty token accounts of every wallet of a set, the rent goes
+/// back to the wallet itself.
+pub struct CloseAccounts {
+    payer: SharedSigner,
+    mint: Option<Pubkey>,
+}
+
+impl CloseAccounts {
+    /// `payer` pays the fee of every close.
+    pub fn new(payer: SharedSigner) -> Self {
+        Self { payer, mint: None }
+    }
+    /// Only close the associated token account of this mint, by default
+    /// every token account of the wallet is looked at.
+    pub fn mint(mut self, mint: Pubkey) -> Self {
+        self.mint = Some(mint);
+        self
+    }
+    pub async fn run(&self, tool: &Tool, wallets: &WalletSet) -> Result<CloseReport> {
+        let mut report = CloseReport::default();
+        let mut tasks = vec![];
+        for wallet in wallets.iter() {
+            let sub_pubkey = wallet.pubkey();
+            let token_accounts = match self.mint {
+                Some(ref token_mint_pubkey) => {
+                    vec![get_associated_token_address(&sub_pubkey, token_mint_pubkey)]
                 }
-            }
-            None => {
-                let main_keypair = Arc::new(
-                    read_keypair_file(main_key_file)
-                        .expect("Failed to generate the main keypair from file"),
-                );
-                let semaphore = Arc::new(Semaphore::new(20));
-                let mut tasks = vec![];
-                match fs::read_dir(sub_keypair_folder) {
-                    Ok(folder) => {
-                        for file in folder {
-                            let file_entry = file.unwrap();
-                            let file_path = file_entry.path();
-                            match read_keypair_file(file_path.clone()) {
-                                Ok(sub_keypair) => {
-                                    let ata_accounts = self
-                                        .rpc_client
-                                        .get_token_accounts_by_owner(
-                                            &sub_keypair.pubkey(),
-                                            TokenAccountsFilter::ProgramId(spl_token::id()),
-                                        )
-                                        .await
-                                        .context("Failed to get all ata account")?;
-                                    for ata_account in ata_accounts.iter() {
-                                        let ata_pubkey = Pubkey::from_str(&ata_account.pubkey)
-                                            .context("Failed to get pubkey")?;
-                                        if check_token_account_exist(&self.rpc_client, &ata_pubkey)
-                                            .await
-                                        {
-                                            let main_keypair_clone = main_keypair.clone();
-                                            let sub_keypair_clone = sub_keypair.insecure_clone();
-                                            let rpc_client_clone = self.rpc_client.clone();
-                                            let semaphore_clone = semaphore.clone();
-                                            let task = tokio::spawn(async move {
-                                                let _permit =
-                                                    semaphore_clone.acquire().await.unwrap();
-                                                let close_account_ins = close_account(
-                                                    &spl_token::id(),
-                                                    &ata_pubkey,
-                                                    &sub_keypair_clone.pubkey(),
-                                                    &sub_keypair_clone.pubkey(),
-                                                    &[&sub_keypair_clone.pubkey()],
-                                                )
-                                                .unwrap();
-                                                let _ = send_txn(
-                                                    &rpc_client_clone,
-                                                    close_account_ins,
-                                                    &main_keypair_clone,
-                                                    &sub_keypair_clone,
-                                                )
-                                                .await;
-                                                sleep(Duration::from_millis(50)).await;
-                                            });
-                                            tasks.push(task);
-                                        }
-                                    }
-                                }
-                                Err(e) => {
-                                    eprintln!("Failed to read sub keypair file with error: {:?}", e)
-                                }
-                            }
-                        }
-                        let results = join_all(tasks).await;
-                        for result in results {
-                            match result {
-                                Ok(_) => {
-                                    // 任务成功完成
-                                    println!("Task completed successfully");
-                                }
-                                Err(e) => {
-                                    // tokio::spawn 本身失败
-                                    println!("Task panicked: {:?}", e);
-                                }
-                            }
+                None => {
+                    let ata_accounts = tool
+                        .rpc_pool
+                        .call(|client| async move {
+                            client
+                                .get_token_accounts_by_owner(
+                                    &sub_pubkey,
+                                    TokenAccountsFilter::ProgramId(spl_token::id()),
+                                )
+                                .await
+                        })
+                        .await;
+                    match ata_accounts {
+                        Ok(ata_accounts) => ata_accounts
+                            .iter()
+                            .filter_map(|ata_account| Pubkey::from_str(&ata_account.pubkey).ok())
+                            .collect(),
+                        Err(e) => {
+                            error!("Failed to get all ata account with error: {}", e);
+                            report.failures.push(Failure::new(sub_pubkey, e.into()));
+                            continue;
                         }
                     }
-                    Err(e) => {
-                        eprintln!("Failed to open sub wallet folder with error: {:?}", e)
-                    }
                 }
+            };
+            for ata_pubkey in token_accounts {
+                let main_keypair_clone = self.payer.clone();
+                let sub_keypair_clone = wallet.signer.clone();
+                let tool = tool.clone();
+                let task = tokio::spawn(async move {
+                    if !check_token_account_exist(&tool.rpc_pool, &ata_pubkey).await {
+                        return CloseEntry {
+                            wallet: sub_pubkey,
+                            account: ata_pubkey,
+                            status: Status::Skipped,
+                        };
+                    }
+                    let result = match close_account(
+                        &spl_token::id(),
+                        &ata_pubkey,
+                        &sub_keypair_clone.pubkey(),
+                        &sub_keypair_clone.pubkey(),
+                        &[&sub_keypair_clone.pubkey()],
+                    ) {
+                        Ok(close_account_ins) => {
+                            tool.send_txn(
+                                close_account_ins,
+                                &*main_keypair_clone,
+                                &*sub_keypair_clone,
+                            )
+                            .await
+                        }
+                        Err(e) => Err(e.into()),
+                    };
+                    let entry = CloseEntry {
+                        wallet: sub_pubkey,
+                        account: ata_pubkey,
+                        status: Status::of(ata_pubkey, result),
+                    };
+                    tool.output.emit(entry.record());
+                    entry
+                });
+                tasks.push(((sub_pubkey, ata_pubkey), task));
             }
         }
-        Ok(())
+        report.accounts = join_entries(tasks, |(wallet, account), failure| CloseEntry {
+            wallet,
+            account,
+            status: Status::Failed(failure),
+        })
+        .await;
+        Ok(report)
     }
 }
-async fn check_token_account_exist(rpc_client: &RpcClient, associate_account: &Pubkey) -> bool {
-    match rpc_client
-        .get_token_account_balance(&associate_account)
+async fn check_token_account_exist(rpc_pool: &RpcPool, associate_account: &Pubkey) -> bool {
+    let associate_account = *associate_account;
+    match rpc_pool
+        .call(|client| async move { client.get_token_account_balance(&associate_account).await })
         .await
     {
-        Ok(balance) => {
-            if balance.ui_amount.unwrap() == 0.0 {
-                true
-            } else {
-                false
-            }
-        }
+        Ok(balance) => balance.amount == "0",
         Err(_) => false,
     }
 }
-async fn send_txn(
-    rpc_client: &RpcClient,
-    ins: Instruction,
-    main_keypair: &Keypair,
-    sub_keypair: &Keypair,
-) -> Result<()> {
-    let recent_blockhash = rpc_client.get_latest_blockhash().await?;
-    let transation = Transaction::new_signed_with_payer(
-        &[ins],
-        Some(&main_keypair.pubkey()),
-        &[sub_keypair, main_keypair],
-        recent_blockhash,
-    );
-    match rpc_client.send_and_confirm_transaction(&transation).await {
-        Ok(sig) => {
-            println!("send transaction successful this is the sig: {}", &sig);
-            return Ok(());
+impl Tool {
+    async fn send_txn(
+        &self,
+        ins: Instruction,
+        main_keypair: &WalletSigner,
+        sub_keypair: &WalletSigner,
+    ) -> Result<Signature> {
+        let recent_blockhash = self
+            .rpc_pool
+            .call(|client| async move { client.get_latest_blockhash().await })
+            .await?;
+        let mut instructions = self
+            .priority_fee_instructions(std::slice::from_ref(&ins))
+            .await;
+        instructions.push(ins);
+        let transation =
+            sign_transaction(&instructions, main_keypair, sub_keypair, recent_blockhash)?;
+        let result = self
+            .rpc_pool
+            .send_and_confirm_transaction(&transation)
+            .await;
+        if let Some(ref ledger) = self.ledger {
+            ledger.record(&self.rpc_pool, &transation, &result);
         }
-        Err(e) => {
-            eprintln!("Failed to send transaction with error: {}", e)
+        match result {
+            Ok(sig) => {
+                info!(
+                    "send transaction successful, check the info: {}",
+                    self.explorer_link(&sig)
+                );
+                Ok(sig)
+            }
+            Err(e) => {
+                error!("Failed to send transaction with error: {}", e);
+                Err(e.into())
+            }
         }
     }
-    Ok(())
 }
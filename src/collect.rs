@@ -1,186 +1,654 @@
-use std::fs;
-use std::str::FromStr;
-use std::sync::Arc;
-use std::time::Duration;
+use std::collections::HashMap;
 
 use futures::future::join_all;
-use log::{error, info};
-use solana_sdk::signature::read_keypair_file;
-use solana_sdk::{pubkey::Pubkey, signer::Signer};
-use spl_token::instruction::transfer_checked;
-use tokio::sync::Semaphore;
-use tokio::time::sleep;
+use log::{error, info, warn};
+use solana_sdk::pubkey::Pubkey;
+use spl_associated_token_account::get_associated_token_address;
 
+use crate::error::{Error, Result};
+use crate::outcome::Failure;
+use crate::plan::RunSummary;
+use crate::report::{join_entries, Status, Transfer, TransferEntry, TransferReport};
+use crate::send_and_check::{token_sweep_instructions, token_transfer_instructions};
+use crate::wallet_set::{SharedSigner, WalletSet};
+use crate::wsol::{is_native, unwrap_instruction};
 use crate::Tool;
 
-impl Tool {
-    pub async fn collect(
-        &self,
-        sub_keypair_folder: String,
-        main_keypair_file: String,
-        token_address: Option<String>,
-        decimals: Option<u8>,
-    ) {
-        let main_keypair = Arc::new(
-            read_keypair_file(main_keypair_file)
-                .expect("can not generate the main keypair from file"),
-        );
-        let semaphore = Arc::new(Semaphore::new(20));
-        match fs::read_dir(sub_keypair_folder) {
-            Ok(folder) => {
-                let mut tasks = vec![];
-                for file in folder {
-                    let file_entry = file.unwrap();
-                    let file_path = file_entry.path();
-                    match read_keypair_file(file_path.clone()) {
-                        Ok(sub_keypair) => {
-                            let main_keypair_clone = main_keypair.clone();
-                            let rpc_client_clone = self.rpc_client.clone();
-                            match token_address {
-                                Some(ref coin_address) => {
-                                    let decimals =
-                                        decimals.expect("need put your spl token decimals");
-                                    let coin_pubkey = Pubkey::from_str(&coin_address).unwrap();
-                                    let sender_token_account_pubkey =
-                                        spl_associated_token_account::get_associated_token_address(
-                                            &sub_keypair.pubkey(),
-                                            &coin_pubkey,
-                                        );
-                                    // let rpc_clone_rec = rpc_client_clone.clone();
-                                    let recipient_token_account_address =
-                                        Tool::check_token_account(
-                                            &rpc_client_clone,
-                                            &sub_keypair,
-                                            &main_keypair_clone,
-                                            &coin_pubkey,
-                                        )
-                                        .await;
+/// Move all the sol, or all of a token, of every wallet of a set to one
+/// destination wallet.
+#[derive(Clone)]
+pub struct Sweep {
+    destination: SharedSigner,
+    token: Option<(Pubkey, u8)>,
+    sub_pays_fee: bool,
+}
+
+/// The transfers of a sweep, and the wallets settled without one.
+struct Prepared {
+    transfers: Vec<(SharedSigner, Transfer)>,
+    /// The token accounts each wallet sweeps and what they hold, empty for
+    /// sol.
+    sources: HashMap<Pubkey, Vec<(Pubkey, u64)>>,
+    /// Empty wallets, unreadable balances and wallets the sweep would leave
+    /// below the rent-exempt minimum.
+    settled: Vec<TransferEntry>,
+}
+
+/// A sweep worked out by `Sweep::plan`, `Sweep::run` sends exactly its
+/// transfers.
+pub struct SweepPlan {
+    /// What to confirm.
+    pub summary: RunSummary,
+    prepared: Prepared,
+}
+
+impl Prepared {
+    fn planned(&self) -> Vec<Transfer> {
+        self.transfers
+            .iter()
+            .map(|(_, transfer)| *transfer)
+            .collect()
+    }
+    fn refuse(&mut self, transfer: Transfer, e: Error) {
+        error!("Refuse to sweep {} with error: {}", transfer.from, e);
+        self.settled.push(TransferEntry {
+            transfer,
+            status: Status::of(transfer.from, Err(e)),
+        });
+    }
+}
 
-                                    let rpc_clone = rpc_client_clone.clone();
-                                    match Tool::get_spl_token_amount(
-                                        rpc_clone,
-                                        &sender_token_account_pubkey,
+impl Sweep {
+    /// `destination` receives everything and pays the fee of the sol
+    /// transfers.
+    pub fn new(destination: SharedSigner) -> Self {
+        Self {
+            destination,
+            token: None,
+            sub_pays_fee: false,
+        }
+    }
+    /// Let every wallet pay the fee of its own sol transfer, what it sends
+    /// is its balance less that fee.
+    pub fn sub_pays_fee(mut self) -> Self {
+        self.sub_pays_fee = true;
+        self
+    }
+    /// Sweep this spl token instead of sol.
+    pub fn token(mut self, mint: Pubkey, decimals: u8) -> Self {
+        self.token = Some((mint, decimals));
+        self
+    }
+    /// The sol balance of every wallet, or its token balance over all its
+    /// token accounts of the mint with the ones holding some.
+    async fn balances(
+        &self,
+        tool: &Tool,
+        wallets: &WalletSet,
+    ) -> Vec<Result<(u64, Vec<(Pubkey, u64)>)>> {
+        let mint = self.token.map(|(mint, _)| mint);
+        join_all(wallets.iter().map(|wallet| {
+            let owner = wallet.pubkey();
+            async move {
+                match mint {
+                    Some(mint) => {
+                        let holdings = tool.token_holdings(&owner, Some(&mint)).await?;
+                        let (frozen, sources): (Vec<_>, Vec<_>) = holdings
+                            .iter()
+                            .filter(|holding| holding.amount > 0)
+                            .partition(|holding| holding.frozen);
+                        match (frozen.first(), sources.is_empty()) {
+                            (Some(holding), true) => {
+                                return Err(Error::Frozen {
+                                    account: holding.address.to_string(),
+                                })
+                            }
+                            (Some(_), false) => warn!(
+                                "{} of the token accounts of {} are frozen, leave them",
+                                frozen.len(),
+                                owner
+                            ),
+                            (None, _) => {}
+                        }
+                        let sources: Vec<(Pubkey, u64)> = sources
+                            .iter()
+                            .map(|holding| (holding.address, holding.amount))
+                            .collect();
+                        Ok((sources.iter().map(|(_, amount)| amount).sum(), sources))
+                    }
+                    None => tool
+                        .rpc_pool
+                        .call(|client| async move { client.get_balance(&owner).await })
+                        .await
+                        .map(|balance| (balance, vec![]))
+                        .map_err(Error::from),
+                }
+            }
+        }))
+        .await
+    }
+    /// The lamports each sender holds and the lamports its transfer and fee
+    /// take from it. The transfers of a token land in any order, so each
+    /// sender may be the one paying the rent of a missing destination
+    /// account.
+    async fn sol_spent(
+        &self,
+        tool: &Tool,
+        transfers: &[(SharedSigner, Transfer)],
+    ) -> Result<Vec<(u64, u64)>> {
+        let (Some((mint, decimals)), Some((_, sample))) = (self.token, transfers.first()) else {
+            // the whole sol balance goes, the fee included when the wallet pays it
+            return Ok(transfers
+                .iter()
+                .map(|(_, transfer)| (transfer.amount, transfer.amount))
+                .collect());
+        };
+        let instructions = token_transfer_instructions(
+            &sample.from,
+            &sample.from,
+            &sample.to,
+            &mint,
+            sample.amount,
+            decimals,
+        )?;
+        let mut spent = tool.estimate_fee(instructions, &sample.from).await?;
+        if !is_native(&mint) {
+            let destination_account = get_associated_token_address(&sample.to, &mint);
+            let missing = tool.count_missing(&[destination_account]).await?;
+            spent += tool.token_account_rent(missing).await?;
+        }
+        let owners: Vec<Pubkey> = transfers
+            .iter()
+            .map(|(_, transfer)| transfer.from)
+            .collect();
+        let accounts = tool.get_accounts(&owners).await?;
+        Ok(accounts
+            .iter()
+            .map(|account| {
+                (
+                    account.as_ref().map_or(0, |account| account.lamports),
+                    spent,
+                )
+            })
+            .collect())
+    }
+    /// Read every balance and settle the wallets which get no transfer, so
+    /// the policy sees the whole sweep before a send.
+    async fn prepare(&self, tool: &Tool, wallets: &WalletSet) -> Result<Prepared> {
+        let mint = self.token.map(|(mint, _)| mint);
+        let mut prepared = Prepared {
+            transfers: vec![],
+            sources: HashMap::new(),
+            settled: vec![],
+        };
+        for (wallet, balance) in wallets.iter().zip(self.balances(tool, wallets).await) {
+            let transfer = Transfer {
+                from: wallet.pubkey(),
+                to: self.destination.pubkey(),
+                mint,
+                amount: balance
+                    .as_ref()
+                    .map(|(amount, _)| *amount)
+                    .unwrap_or_default(),
+            };
+            match balance {
+                Ok((0, _)) => prepared.settled.push(TransferEntry {
+                    transfer,
+                    status: Status::Skipped,
+                }),
+                Ok((_, sources)) => {
+                    prepared.sources.insert(transfer.from, sources);
+                    prepared.transfers.push((wallet.signer.clone(), transfer));
+                }
+                Err(e @ Error::Frozen { .. }) => prepared.refuse(transfer, e),
+                Err(e) => {
+                    error!(
+                        "Failed to get the balance of {} with error: {}",
+                        transfer.from, e
+                    );
+                    prepared.settled.push(TransferEntry {
+                        transfer,
+                        status: Status::of(transfer.from, Err(e)),
+                    });
+                }
+            }
+        }
+        if let Some((mint, _)) = self.token.filter(|(mint, _)| !is_native(mint)) {
+            let destination_account =
+                get_associated_token_address(&self.destination.pubkey(), &mint);
+            if !tool
+                .frozen_accounts(&[destination_account])
+                .await?
+                .is_empty()
+            {
+                for (_, transfer) in std::mem::take(&mut prepared.transfers) {
+                    prepared.refuse(
+                        transfer,
+                        Error::Frozen {
+                            account: destination_account.to_string(),
+                        },
+                    );
+                }
+            }
+        }
+        if let (None, true, Some((_, sample))) =
+            (self.token, self.sub_pays_fee, prepared.transfers.first())
+        {
+            let instruction =
+                solana_sdk::system_instruction::transfer(&sample.from, &sample.to, sample.amount);
+            let fee = tool.estimate_fee(vec![instruction], &sample.from).await?;
+            for (signer, mut transfer) in std::mem::take(&mut prepared.transfers) {
+                match transfer
+                    .amount
+                    .checked_sub(fee)
+                    .filter(|amount| *amount > 0)
+                {
+                    Some(amount) => {
+                        transfer.amount = amount;
+                        prepared.transfers.push((signer, transfer));
+                    }
+                    None => prepared.refuse(
+                        transfer,
+                        Error::InsufficientBalance {
+                            account: transfer.from.to_string(),
+                            balance: transfer.amount,
+                            needed: fee,
+                        },
+                    ),
+                }
+            }
+        }
+        let spent = self.sol_spent(tool, &prepared.transfers).await?;
+        let minimum = match spent.iter().any(|(lamports, spent)| lamports > spent) {
+            true => tool.minimum_balance(0).await?,
+            false => 0,
+        };
+        let transfers = std::mem::take(&mut prepared.transfers);
+        for ((signer, transfer), (lamports, spent)) in transfers.into_iter().zip(spent) {
+            // a wallet is either drained or stays rent exempt
+            let refused = match lamports.checked_sub(spent) {
+                None => Some(Error::InsufficientBalance {
+                    account: transfer.from.to_string(),
+                    balance: lamports,
+                    needed: spent,
+                }),
+                Some(left) if left > 0 && left < minimum => Some(Error::BelowRentExemption {
+                    account: transfer.from.to_string(),
+                    lamports: left,
+                    minimum,
+                }),
+                Some(_) => None,
+            };
+            match refused {
+                Some(e) => prepared.refuse(transfer, e),
+                None => prepared.transfers.push((signer, transfer)),
+            }
+        }
+        Ok(prepared)
+    }
+    /// Work out what `run` is going to send, nothing is sent.
+    pub async fn plan(&self, tool: &Tool, wallets: &WalletSet) -> Result<SweepPlan> {
+        let prepared = self.prepare(tool, wallets).await?;
+        let summary = self.summary(tool, &prepared).await?;
+        Ok(SweepPlan { summary, prepared })
+    }
+    async fn summary(&self, tool: &Tool, prepared: &Prepared) -> Result<RunSummary> {
+        let destination = self.destination.pubkey();
+        let senders: Vec<Pubkey> = prepared
+            .transfers
+            .iter()
+            .map(|(_, transfer)| transfer.from)
+            .collect();
+        let total_amount = prepared
+            .transfers
+            .iter()
+            .map(|(_, transfer)| transfer.amount)
+            .sum();
+        let mut summary = RunSummary {
+            cluster: tool.cluster().await?,
+            recipients: senders.len(),
+            total_amount,
+            mint: self.token.map(|(mint, _)| mint),
+            decimals: self.token.map_or(9, |(_, decimals)| decimals),
+            accounts_to_create: 0,
+            account_rent: 0,
+            estimated_fee: 0,
+            allocation: vec![],
+            transfers: prepared.planned(),
+            frozen: vec![],
+        };
+        let Some(sample) = senders.first().copied() else {
+            return Ok(summary);
+        };
+        let (instructions, payer) = match self.token {
+            Some((mint, decimals)) => {
+                if !is_native(&mint) {
+                    let destination_account = get_associated_token_address(&destination, &mint);
+                    summary.accounts_to_create = tool.count_missing(&[destination_account]).await?;
+                }
+                summary.account_rent = tool.token_account_rent(summary.accounts_to_create).await?;
+                let instructions = token_transfer_instructions(
+                    &sample,
+                    &sample,
+                    &destination,
+                    &mint,
+                    total_amount,
+                    decimals,
+                )?;
+                (instructions, sample)
+            }
+            None => {
+                let instruction =
+                    solana_sdk::system_instruction::transfer(&sample, &destination, total_amount);
+                let payer = match self.sub_pays_fee {
+                    true => sample,
+                    false => destination,
+                };
+                (vec![instruction], payer)
+            }
+        };
+        summary.estimated_fee +=
+            tool.estimate_fee(instructions, &payer).await? * summary.recipients as u64;
+        Ok(summary)
+    }
+    /// Send the transfers of `plan` as they were confirmed, a wallet which
+    /// holds more sol by then is refused.
+    pub async fn run(&self, tool: &Tool, plan: SweepPlan) -> Result<TransferReport> {
+        tool.authorize(&plan.summary.transfers, true)?;
+        Ok(self.send(tool, plan.prepared).await)
+    }
+    /// Send the transfers of a sweep the policy has authorized.
+    async fn send(&self, tool: &Tool, prepared: Prepared) -> TransferReport {
+        let Prepared {
+            transfers: planned,
+            mut sources,
+            settled,
+        } = prepared;
+        for entry in &settled {
+            if let Status::Failed(_) = entry.status {
+                tool.output.emit(entry.record());
+            }
+        }
+        let mut report = TransferReport {
+            transfers: settled,
+            failures: vec![],
+        };
+        let mut tasks = vec![];
+        for (sub_keypair, transfer) in planned {
+            let main_keypair_clone = self.destination.clone();
+            let tool = tool.clone();
+            match self.token {
+                Some((coin_pubkey, decimals)) => {
+                    let sources = sources.remove(&transfer.from).unwrap_or_default();
+                    let task = tokio::spawn(async move {
+                        let instructions = match is_native(&coin_pubkey) {
+                            // closing the wsol accounts unwraps them into the destination
+                            true => sources
+                                .iter()
+                                .map(|(source, _)| {
+                                    unwrap_instruction(
+                                        source,
+                                        &sub_keypair.pubkey(),
+                                        &main_keypair_clone.pubkey(),
                                     )
-                                    .await
-                                    {
-                                        Ok(balance) => {
-                                            let semaphore_clone = semaphore.clone();
-                                            if balance != 0 {
-                                                let task = tokio::spawn(async move {
-                                                    let _permit =
-                                                        semaphore_clone.acquire().await.unwrap();
-                                                    match transfer_checked(
-                                                        &spl_token::id(),
-                                                        &sender_token_account_pubkey,
-                                                        &coin_pubkey,
-                                                        &recipient_token_account_address,
-                                                        &sub_keypair.pubkey(),
-                                                        &[&sub_keypair.pubkey()],
-                                                        balance,
-                                                        decimals,
-                                                    ) {
-                                                        Ok(spl_transfer_instruction) => {
-                                                            match Tool::sendtxn_and_watch(
-                                                                &rpc_client_clone,
-                                                                spl_transfer_instruction,
-                                                                &sub_keypair,
-                                                                &sub_keypair,
-                                                            )
-                                                            .await
-                                                            {
-                                                                Ok(signature) => {
-                                                                    info!("Successfuly transfer spl token from {} to {}, check the info: https://solscan.io/tx/{}",&sub_keypair.pubkey(),&main_keypair_clone.pubkey(),signature)
-                                                                }
-                                                                Err(e) => {
-                                                                    error!("Failed to transfer spl token the error is {}",e)
-                                                                }
-                                                            }
-                                                        }
-                                                        Err(e) => {
-                                                            error!("failed the build the spl transfer instruction with error: {}",e)
-                                                        }
-                                                    }
-                                                    sleep(Duration::from_millis(50)).await;
-                                                });
-                                                tasks.push(task);
-                                            }
-                                        }
-                                        Err(e) => {
-                                            error!("Failed to get sender spl token balance with error: {}",e)
-                                        }
+                                })
+                                .collect(),
+                            false => token_sweep_instructions(
+                                &sub_keypair.pubkey(),
+                                &sub_keypair.pubkey(),
+                                &sources,
+                                &main_keypair_clone.pubkey(),
+                                &coin_pubkey,
+                                decimals,
+                            ),
+                        };
+                        let result = match instructions {
+                            Ok(instructions) => {
+                                let result = tool
+                                    .sendtxn_and_watch(instructions, &*sub_keypair, &*sub_keypair)
+                                    .await;
+                                match result {
+                                    Ok(ref signature) => {
+                                        info!("Successfuly transfer spl token from {} to {}, check the info: {}",&sub_keypair.pubkey(),&main_keypair_clone.pubkey(),tool.explorer_link(signature))
+                                    }
+                                    Err(ref e) => {
+                                        error!("Failed to transfer spl token the error is {}", e)
                                     }
                                 }
-                                None => {
-                                    let semaphore_clone = semaphore.clone();
-                                    let task = tokio::spawn(async move {
-                                        let _permit = semaphore_clone.acquire().await.unwrap();
-                                        // let rpc_clone = rpc_client_clone.clone();
-                                        // match Tool::get_sol_amount_except_gas(
-                                        //     rpc_client_clone,
-                                        //     &sub_keypair,
-                                        //     &main_keypair_clone,
-                                        // )
-                                        // .await
-                                        match rpc_client_clone
-                                            .get_balance(&sub_keypair.pubkey())
-                                            .await
-                                        {
-                                            Ok(balance) => {
-                                                if balance > 0 {
-                                                    let transfer_sol_instruction =
-                                                        solana_sdk::system_instruction::transfer(
-                                                            &sub_keypair.pubkey(),
-                                                            &main_keypair_clone.pubkey(),
-                                                            balance,
-                                                        );
-                                                    match Tool::sendtxn_and_watch(
-                                                        &rpc_client_clone,
-                                                        transfer_sol_instruction,
-                                                        &sub_keypair,
-                                                        &main_keypair_clone,
-                                                    )
-                                                    .await
-                                                    {
-                                                        Ok(signature) => {
-                                                            info!("Successfuly transfer sol from {} to {}, check the info: https://solscan.io/tx/{}",&sub_keypair.pubkey(),&main_keypair_clone.pubkey(),signature)
-                                                        }
-                                                        Err(e) => {
-                                                            error!("Failed to transfer sol from {} to {} with error: {}",&sub_keypair.pubkey(),&main_keypair_clone.pubkey(),e)
-                                                        }
-                                                    }
-                                                }
-                                            }
-                                            Err(e) => {
-                                                error!("Failed to calculate the gas for transfer sol from {} to {} with error: {}",&sub_keypair.pubkey(),&main_keypair_clone.pubkey(),e)
-                                            }
-                                        }
-                                        sleep(Duration::from_millis(50)).await;
-                                    });
-                                    tasks.push(task)
-                                }
+                                result
                             }
-                        }
-                        Err(e) => {
-                            error!(
-                                "can not read the keypair from the {:?}, the error is {}",
-                                &file_path, e
+                            Err(e) => {
+                                error!(
+                                    "failed the build the spl transfer instruction with error: {}",
+                                    e
+                                );
+                                Err(e)
+                            }
+                        };
+                        let entry = TransferEntry {
+                            transfer,
+                            status: Status::of(transfer.from, result),
+                        };
+                        tool.output.emit(entry.record());
+                        entry
+                    });
+                    tasks.push((transfer, task));
+                }
+                None => {
+                    let sub_pays_fee = self.sub_pays_fee;
+                    let task = tokio::spawn(async move {
+                        let mut transfer = transfer;
+                        let payer = match sub_pays_fee {
+                            true => sub_keypair.clone(),
+                            false => main_keypair_clone.clone(),
+                        };
+                        let result = tool
+                            .sweep_sol(
+                                &*sub_keypair,
+                                &main_keypair_clone.pubkey(),
+                                &*payer,
+                                transfer.amount,
                             )
+                            .await
+                            .map(|(signature, amount)| {
+                                transfer.amount = amount;
+                                signature
+                            });
+                        match result {
+                            Ok(ref signature) => {
+                                info!(
+                                    "Successfuly transfer sol from {} to {}, check the info: {}",
+                                    &sub_keypair.pubkey(),
+                                    &main_keypair_clone.pubkey(),
+                                    tool.explorer_link(signature)
+                                )
+                            }
+                            Err(ref e) => {
+                                error!(
+                                    "Failed to transfer sol from {} to {} with error: {}",
+                                    &sub_keypair.pubkey(),
+                                    &main_keypair_clone.pubkey(),
+                                    e
+                                )
+                            }
                         }
-                    }
+                        let entry = TransferEntry {
+                            transfer,
+                            status: Status::of(transfer.from, result),
+                        };
+                        tool.output.emit(entry.record());
+                        entry
+                    });
+                    tasks.push((transfer, task))
                 }
-                let results = join_all(tasks).await;
-                for result in results {
-                    match result {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!("task handle failed with error: {}", e)
-                        }
-                    }
+            }
+        }
+        report.transfers.extend(
+            join_entries(tasks, |transfer, failure| TransferEntry {
+                transfer,
+                status: Status::Failed(failure),
+            })
+            .await,
+        );
+        let sent = report.sent();
+        report.failures.extend(tool.record_spending(&sent));
+        report
+    }
+}
+
+/// Sweep every token the wallets hold to one destination, one `Sweep` per
+/// mint over the wallets which hold it.
+pub struct TokenSweep {
+    destination: SharedSigner,
+    exclude_mints: Vec<Pubkey>,
+    min_ui_value: Option<f64>,
+}
+
+/// The sweeps of every mint worked out by `TokenSweep::plan`.
+pub struct TokenSweepPlan {
+    /// What to confirm, one summary per mint.
+    pub summaries: Vec<RunSummary>,
+    sweeps: Vec<(Sweep, Prepared)>,
+    /// The wallets which could not be listed and the mints which could not
+    /// be worked out.
+    failures: Vec<Failure>,
+}
+
+/// The sweep of one mint and the wallets it runs over.
+struct MintSweep {
+    mint: Pubkey,
+    sweep: Sweep,
+    holders: WalletSet,
+}
+
+impl TokenSweep {
+    /// `destination` receives everything, its token accounts are created
+    /// where missing.
+    pub fn new(destination: SharedSigner) -> Self {
+        Self {
+            destination,
+            exclude_mints: vec![],
+            min_ui_value: None,
+        }
+    }
+    /// Leave this mint where it is.
+    pub fn exclude_mint(mut self, mint: Pubkey) -> Self {
+        self.exclude_mints.push(mint);
+        self
+    }
+    /// Leave the balances below this ui amount where they are.
+    pub fn min_ui_value(mut self, min_ui_value: f64) -> Self {
+        self.min_ui_value = Some(min_ui_value);
+        self
+    }
+    /// List the token accounts of every wallet and group the wallets with a
+    /// balance to sweep by mint, in the order the mints are first seen.
+    async fn mint_sweeps(
+        &self,
+        tool: &Tool,
+        wallets: &WalletSet,
+    ) -> (Vec<MintSweep>, Vec<Failure>) {
+        let listed = join_all(wallets.iter().map(|wallet| {
+            let owner = wallet.pubkey();
+            async move { tool.token_holdings(&owner, None).await }
+        }))
+        .await;
+        let mut mint_sweeps: Vec<MintSweep> = vec![];
+        let mut failures = vec![];
+        for (wallet, holdings) in wallets.iter().zip(listed) {
+            let holdings = match holdings {
+                Ok(holdings) => holdings,
+                Err(e) => {
+                    error!(
+                        "Failed to list the token accounts of {} with error: {}",
+                        wallet.pubkey(),
+                        e
+                    );
+                    failures.push(Failure::new(wallet.pubkey(), e));
+                    continue;
+                }
+            };
+            // what the wallet holds of each mint over all its token accounts
+            let mut totals: Vec<(Pubkey, u8, u64)> = vec![];
+            for holding in holdings {
+                match totals.iter_mut().find(|(mint, _, _)| *mint == holding.mint) {
+                    Some((_, _, amount)) => *amount += holding.amount,
+                    None => totals.push((holding.mint, holding.decimals, holding.amount)),
                 }
             }
-            Err(e) => {
-                error!("can not open the sub keypair folder with error: {}", e)
+            for (mint, decimals, amount) in totals {
+                if amount == 0
+                    || self.exclude_mints.contains(&mint)
+                    || self.min_ui_value.is_some_and(|min_ui_value| {
+                        spl_token::amount_to_ui_amount(amount, decimals) < min_ui_value
+                    })
+                {
+                    continue;
+                }
+                let index = match mint_sweeps
+                    .iter()
+                    .position(|mint_sweep| mint_sweep.sweep.token == Some((mint, decimals)))
+                {
+                    Some(index) => index,
+                    None => {
+                        mint_sweeps.push(MintSweep {
+                            mint,
+                            sweep: Sweep::new(self.destination.clone()).token(mint, decimals),
+                            holders: WalletSet::default(),
+                        });
+                        mint_sweeps.len() - 1
+                    }
+                };
+                mint_sweeps[index].holders.wallets.push(wallet.clone());
+            }
+        }
+        (mint_sweeps, failures)
+    }
+    /// Work out what `run` is going to send, one summary per mint.
+    pub async fn plan(&self, tool: &Tool, wallets: &WalletSet) -> Result<TokenSweepPlan> {
+        let (mint_sweeps, failures) = self.mint_sweeps(tool, wallets).await;
+        let mut plan = TokenSweepPlan {
+            summaries: vec![],
+            sweeps: vec![],
+            failures,
+        };
+        for MintSweep {
+            mint,
+            sweep,
+            holders,
+        } in mint_sweeps
+        {
+            match sweep.plan(tool, &holders).await {
+                Ok(SweepPlan { summary, prepared }) => {
+                    plan.summaries.push(summary);
+                    plan.sweeps.push((sweep, prepared));
+                }
+                Err(e) => {
+                    error!("Failed to plan the sweep of {} with error: {}", mint, e);
+                    plan.failures.push(Failure::new(mint, e));
+                }
             }
         }
+        Ok(plan)
+    }
+    /// Sweep the mints of `plan` one after the other, the policy sees the
+    /// transfers of every mint before the first is sent.
+    pub async fn run(&self, tool: &Tool, plan: TokenSweepPlan) -> Result<TransferReport> {
+        let planned: Vec<Transfer> = plan
+            .summaries
+            .iter()
+            .flat_map(|summary| summary.transfers.iter().copied())
+            .collect();
+        tool.authorize(&planned, true)?;
+        let mut report = TransferReport {
+            transfers: vec![],
+            failures: plan.failures,
+        };
+        for (sweep, prepared) in plan.sweeps {
+            let mint_report = sweep.send(tool, prepared).await;
+            report.transfers.extend(mint_report.transfers);
+            report.failures.extend(mint_report.failures);
+        }
+        Ok(report)
     }
 }
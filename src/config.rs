@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::{env, fs};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::Deserialize;
+use solana_sdk::commitment_config::CommitmentConfig;
+
+/// Where the transaction links point to when nothing else is configured.
+const MAINNET_EXPLORER: &str = "https://solscan.io/tx/{signature}";
+
+/// How much priority fee is added to every transaction.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PriorityFee {
+    #[default]
+    None,
+    /// A percentile of the recent fees paid for the accounts the transaction
+    /// writes to.
+    Auto,
+    /// A fixed compute unit price in micro-lamports.
+    Fixed(u64),
+}
+
+impl FromStr for PriorityFee {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "auto" => Ok(Self::Auto),
+            _ => s.parse::<u64>().map(Self::Fixed).map_err(|_| {
+                anyhow!(
+                    "invalid priority fee {}, use none, auto or a micro-lamports price",
+                    s
+                )
+            }),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PriorityFee {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Fixed(u64),
+            Text(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Fixed(price) => Ok(Self::Fixed(price)),
+            Raw::Text(text) => text.parse().map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+/// Everything a profile can set, each field falls back to the layer below
+/// it: command line, the selected profile, the solana cli config, then the
+/// built in defaults.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub rpc: Option<Vec<String>>,
+    pub ws: Option<String>,
+    pub commitment: Option<String>,
+    pub main_keypair_file: Option<String>,
+    pub sub_keypair_folder: Option<String>,
+    pub decimals: Option<u8>,
+    pub rps: Option<u32>,
+    pub concurrency: Option<usize>,
+    pub priority_fee: Option<PriorityFee>,
+    /// Transaction link template, `{signature}` is replaced.
+    pub explorer: Option<String>,
+    /// The spending policy file checked before any transfer, none by default.
+    pub spending_policy: Option<String>,
+}
+
+impl Profile {
+    /// Lay `over` on top of `self`.
+    pub fn merge(self, over: Profile) -> Profile {
+        // a websocket belongs to its rpc, do not pair a new rpc with the old one
+        let ws = match (over.ws, over.rpc.is_some()) {
+            (Some(ws), _) => Some(ws),
+            (None, true) => None,
+            (None, false) => self.ws,
+        };
+        Profile {
+            rpc: over.rpc.or(self.rpc),
+            ws,
+            commitment: over.commitment.or(self.commitment),
+            main_keypair_file: over.main_keypair_file.or(self.main_keypair_file),
+            sub_keypair_folder: over.sub_keypair_folder.or(self.sub_keypair_folder),
+            decimals: over.decimals.or(self.decimals),
+            rps: over.rps.or(self.rps),
+            concurrency: over.concurrency.or(self.concurrency),
+            priority_fee: over.priority_fee.or(self.priority_fee),
+            explorer: over.explorer.or(self.explorer),
+            spending_policy: over.spending_policy.or(self.spending_policy),
+        }
+    }
+    pub fn commitment(&self) -> Result<CommitmentConfig> {
+        match self.commitment {
+            Some(ref commitment) => CommitmentConfig::from_str(commitment)
+                .map_err(|_| anyhow!("invalid commitment {}", commitment)),
+            None => Ok(CommitmentConfig::confirmed()),
+        }
+    }
+    pub fn main_keypair_file(&self, cli: Option<String>) -> Result<String> {
+        cli.or_else(|| self.main_keypair_file.clone()).context(
+            "no main wallet, pass --main-keypair-file or set main_keypair_file in the profile",
+        )
+    }
+    pub fn sub_keypair_folder(&self, cli: Option<String>) -> Result<String> {
+        cli.or_else(|| self.sub_keypair_folder.clone()).context(
+            "no sub wallet folder, pass --sub-keypair-folder or set sub_keypair_folder in the profile",
+        )
+    }
+    pub fn decimals(&self, cli: Option<u8>) -> Option<u8> {
+        cli.or(self.decimals).or(Some(9))
+    }
+    /// The explorer template, worked out from the rpc url when it is not set.
+    pub fn explorer(&self, rpc_url: &str) -> String {
+        match self.explorer {
+            Some(ref explorer) => explorer.clone(),
+            None => default_explorer(rpc_url),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+    default_profile: Option<String>,
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+/// The few fields of the solana cli `config.yml` the tool picks up.
+#[derive(Debug, Default, Deserialize)]
+struct SolanaCliConfig {
+    #[serde(default)]
+    json_rpc_url: String,
+    #[serde(default)]
+    websocket_url: String,
+    #[serde(default)]
+    keypair_path: String,
+    #[serde(default)]
+    commitment: String,
+}
+
+/// Load the profile to run with. `config_path` is the tool's toml file, which
+/// may be missing unless it was given explicitly, `profile_name` falls back
+/// to its `default_profile`.
+pub fn load_profile(config_path: Option<&str>, profile_name: Option<&str>) -> Result<Profile> {
+    let config = match config_path {
+        Some(path) => read_config_file(path)?,
+        None => {
+            let path = default_config_path();
+            if fs::metadata(&path).is_ok() {
+                read_config_file(&path)?
+            } else {
+                ConfigFile::default()
+            }
+        }
+    };
+    let mut profile = solana_cli_profile();
+    let profile_name = profile_name
+        .map(str::to_string)
+        .or(config.default_profile.clone());
+    if let Some(name) = profile_name {
+        let builtin = builtin_profile(&name);
+        let configured = config.profiles.get(&name).cloned();
+        if builtin.is_none() && configured.is_none() {
+            bail!("unknown profile {}", name);
+        }
+        profile = profile
+            .merge(builtin.unwrap_or_default())
+            .merge(configured.unwrap_or_default());
+    }
+    Ok(profile)
+}
+
+/// `~/.solana_tool/config.toml`.
+pub fn default_config_path() -> String {
+    match env::var("HOME") {
+        Ok(home) => format!("{}/.solana_tool/config.toml", home),
+        Err(_) => "config.toml".to_string(),
+    }
+}
+
+fn read_config_file(path: &str) -> Result<ConfigFile> {
+    let content =
+        fs::read_to_string(path).with_context(|| format!("Failed to read the config {}", path))?;
+    toml::from_str(&content).with_context(|| format!("Failed to parse the config {}", path))
+}
+
+/// The defaults of the solana cli, an empty profile when it is not set up.
+fn solana_cli_profile() -> Profile {
+    let path = match env::var("HOME") {
+        Ok(home) => format!("{}/.config/solana/cli/config.yml", home),
+        Err(_) => return Profile::default(),
+    };
+    let config: SolanaCliConfig = match fs::read_to_string(&path) {
+        Ok(content) => match serde_yaml::from_str(&content) {
+            Ok(config) => config,
+            Err(e) => {
+                log::warn!(
+                    "Failed to parse the solana cli config {} with error: {}",
+                    path,
+                    e
+                );
+                return Profile::default();
+            }
+        },
+        Err(_) => return Profile::default(),
+    };
+    let non_empty = |value: String| if value.is_empty() { None } else { Some(value) };
+    Profile {
+        rpc: non_empty(config.json_rpc_url).map(|url| vec![url]),
+        ws: non_empty(config.websocket_url),
+        main_keypair_file: non_empty(config.keypair_path),
+        commitment: non_empty(config.commitment),
+        ..Profile::default()
+    }
+}
+
+fn builtin_profile(name: &str) -> Option<Profile> {
+    let rpc = match name {
+        "mainnet" => "https://api.mainnet-beta.solana.com",
+        "devnet" => "https://api.devnet.solana.com",
+        "localnet" => "http://127.0.0.1:8899",
+        _ => return None,
+    };
+    Some(Profile {
+        rpc: Some(vec![rpc.to_string()]),
+        ..Profile::default()
+    })
+}
+
+fn default_explorer(rpc_url: &str) -> String {
+    if rpc_url.contains("devnet") {
+        format!("{}?cluster=devnet", MAINNET_EXPLORER)
+    } else if rpc_url.contains("testnet") {
+        format!("{}?cluster=testnet", MAINNET_EXPLORER)
+    } else if rpc_url.contains("localhost") || rpc_url.contains("127.0.0.1") {
+        let url = rpc_url.split('#').next().unwrap_or(rpc_url);
+        format!(
+            "https://explorer.solana.com/tx/{{signature}}?cluster=custom&customUrl={}",
+            url.replace(':', "%3A").replace('/', "%2F")
+        )
+    } else {
+        MAINNET_EXPLORER.to_string()
+    }
+}
@@ -1,25 +1,73 @@
+use std::path::Path;
+
+use crate::error::{Error, Result};
+use crate::keystore::{keystore_password, write_keystore};
+use crate::outcome::{Failure, Outcome, Step};
+use crate::output::Record;
+use crate::utils::keypair_from_bs58;
+use crate::wallet_set::WalletSet;
 use crate::Tool;
-use log::{error, info};
-use solana_sdk::signature::{read_keypair_file, write_keypair_file, Keypair};
+use log::info;
+use solana_sdk::signature::{write_keypair_file, Signer};
 
 impl Tool {
-    pub async fn bs58_to_json(&self, bs58: String, output_file: String) {
-        let keypair = Keypair::from_base58_string(&bs58);
-        match write_keypair_file(&keypair, output_file) {
-            Ok(_) => {
-                info!("Successfully to write the json file")
-            }
-            Err(e) => {
-                error!("Failed to write the json file with error: {}", e)
-            }
+    pub async fn bs58_to_json(&self, bs58: String, output_file: String) -> Result<Outcome> {
+        let keypair = keypair_from_bs58(&bs58)?;
+        write_keypair_file(&keypair, &output_file).map_err(|e| Error::keypair(&output_file, e))?;
+        info!("Successfully to write the json file");
+        self.output.emit(Record::Wallet {
+            pubkey: keypair.pubkey().to_string(),
+            file: output_file,
+        });
+        let mut outcome = Outcome::default();
+        outcome.add(Step::Succeeded);
+        Ok(outcome)
+    }
+    pub async fn json_to_bs58(&self, wallets: &WalletSet) -> Result<Outcome> {
+        let mut outcome = Outcome::default();
+        for wallet in wallets.iter() {
+            let keypair = match wallet.secret() {
+                Ok(keypair) => keypair,
+                Err(e) => {
+                    outcome.add(Step::Failed(Failure::new(wallet.pubkey(), e)));
+                    continue;
+                }
+            };
+            info!(
+                "Successfully to convert to bs58: {}",
+                keypair.to_base58_string()
+            );
+            self.output.emit(Record::Keypair {
+                pubkey: wallet.pubkey().to_string(),
+                bs58: keypair.to_base58_string(),
+            });
+            outcome.add(Step::Succeeded);
         }
+        Ok(outcome)
     }
-    pub async fn json_to_bs58(&self, json_file: String) {
-        let keypair = read_keypair_file(&json_file)
-            .expect("Failed to read the keypair json file convert to keypair");
+    /// Encrypt the wallets into a keystore file, to be used as
+    /// `keystore://<file>`.
+    pub async fn to_keystore(&self, wallets: &WalletSet, keystore_file: String) -> Result<Outcome> {
+        let path = Path::new(&keystore_file);
+        let password = keystore_password(path, true)?;
+        let keypairs = wallets
+            .iter()
+            .map(|wallet| wallet.secret())
+            .collect::<Result<Vec<_>>>()?;
+        write_keystore(path, &password, keypairs)?;
         info!(
-            "Successfully to convert to bs58: {}",
-            keypair.to_base58_string()
-        )
+            "Successfully to write {} keypairs to the keystore {}",
+            wallets.len(),
+            keystore_file
+        );
+        let mut outcome = Outcome::default();
+        for wallet in wallets.iter() {
+            self.output.emit(Record::Wallet {
+                pubkey: wallet.pubkey().to_string(),
+                file: keystore_file.clone(),
+            });
+            outcome.add(Step::Succeeded);
+        }
+        Ok(outcome)
     }
 }
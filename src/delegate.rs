@@ -0,0 +1,428 @@
+use log::{error, info, warn};
+use solana_sdk::{
+    instruction::Instruction, program_option::COption, pubkey::Pubkey, signature::Signature,
+};
+use spl_associated_token_account::get_associated_token_address;
+use spl_token::instruction::{approve_checked, revoke};
+
+use crate::error::Result;
+use crate::outcome::{Failure, Outcome, Step};
+use crate::output::Record;
+use crate::plan::RunSummary;
+use crate::report::{join_entries, ApprovalEntry, ApprovalReport, Status, Transfer};
+use crate::wallet_set::{SharedSigner, WalletSet};
+use crate::Tool;
+
+/// How many revokes go in one transaction.
+const MAX_REVOKES_PER_TRANSACTION: usize = 20;
+
+/// An outstanding approval on a token account of a wallet.
+#[derive(Debug, Clone, Copy)]
+pub struct Delegation {
+    pub wallet: Pubkey,
+    pub account: Pubkey,
+    pub mint: Pubkey,
+    pub delegate: Pubkey,
+    /// The raw amount the delegate may still move.
+    pub delegated_amount: u64,
+}
+
+impl Delegation {
+    pub(crate) fn record(&self) -> Record {
+        Record::Delegation {
+            wallet: self.wallet.to_string(),
+            account: self.account.to_string(),
+            mint: self.mint.to_string(),
+            delegate: self.delegate.to_string(),
+            delegated_amount: self.delegated_amount,
+        }
+    }
+}
+
+impl Tool {
+    /// The token accounts of `owner` which have a delegate.
+    pub async fn delegations(&self, owner: &Pubkey) -> Result<Vec<Delegation>> {
+        let accounts = self.token_accounts(owner, None).await?;
+        Ok(accounts
+            .into_iter()
+            .filter_map(|(account, state)| match state.delegate {
+                COption::Some(delegate) => Some(Delegation {
+                    wallet: *owner,
+                    account,
+                    mint: state.mint,
+                    delegate,
+                    delegated_amount: state.delegated_amount,
+                }),
+                COption::None => None,
+            })
+            .collect())
+    }
+    /// Log and emit every delegation of every wallet of a set.
+    pub async fn show_delegations(&self, wallets: &WalletSet) -> Result<Outcome> {
+        let mut tasks = vec![];
+        for wallet in wallets.iter() {
+            let owner = wallet.pubkey();
+            let tool = self.clone();
+            tasks.push(tokio::spawn(async move {
+                let result = tool.delegations(&owner).await;
+                match result {
+                    Ok(ref delegations) => {
+                        for delegation in delegations {
+                            warn!(
+                                "{} of {}: {} may move {} of the mint {}",
+                                delegation.account,
+                                owner,
+                                delegation.delegate,
+                                delegation.delegated_amount,
+                                delegation.mint
+                            );
+                            tool.output.emit(delegation.record());
+                        }
+                    }
+                    Err(ref e) => error!(
+                        "Failed to get the delegations of {} with error: {}",
+                        owner, e
+                    ),
+                }
+                Step::of(owner, result)
+            }));
+        }
+        let mut outcome = Outcome::default();
+        outcome.join(futures::future::join_all(tasks).await);
+        Ok(outcome)
+    }
+}
+
+/// Revoke the delegations of every wallet of a set, all of them or the ones
+/// matching the filters. The revokes of a wallet are batched in as few
+/// transactions as possible.
+pub struct Revoke {
+    payer: SharedSigner,
+    delegate: Option<Pubkey>,
+    mint: Option<Pubkey>,
+    accounts: Vec<Pubkey>,
+}
+
+impl Revoke {
+    /// `payer` pays the fee of every transaction.
+    pub fn new(payer: SharedSigner) -> Self {
+        Self {
+            payer,
+            delegate: None,
+            mint: None,
+            accounts: vec![],
+        }
+    }
+    /// Only revoke what this delegate may move.
+    pub fn delegate(mut self, delegate: Pubkey) -> Self {
+        self.delegate = Some(delegate);
+        self
+    }
+    /// Only revoke the token accounts of this mint.
+    pub fn mint(mut self, mint: Pubkey) -> Self {
+        self.mint = Some(mint);
+        self
+    }
+    /// Only revoke this token account, can be given several times.
+    pub fn account(mut self, account: Pubkey) -> Self {
+        self.accounts.push(account);
+        self
+    }
+    fn selects(&self, delegation: &Delegation) -> bool {
+        self.delegate
+            .is_none_or(|delegate| delegate == delegation.delegate)
+            && self.mint.is_none_or(|mint| mint == delegation.mint)
+            && (self.accounts.is_empty() || self.accounts.contains(&delegation.account))
+    }
+    pub async fn run(&self, tool: &Tool, wallets: &WalletSet) -> Result<ApprovalReport> {
+        let mut report = ApprovalReport::default();
+        let mut tasks = vec![];
+        for wallet in wallets.iter() {
+            let owner = wallet.pubkey();
+            let delegations: Vec<Delegation> = match tool.delegations(&owner).await {
+                Ok(delegations) => delegations
+                    .into_iter()
+                    .filter(|delegation| self.selects(delegation))
+                    .collect(),
+                Err(e) => {
+                    error!(
+                        "Failed to get the delegations of {} with error: {}",
+                        owner, e
+                    );
+                    report.failures.push(Failure::new(owner, e));
+                    continue;
+                }
+            };
+            if delegations.is_empty() {
+                info!("{} has nothing to revoke", owner);
+                continue;
+            }
+            for chunk in delegations.chunks(MAX_REVOKES_PER_TRANSACTION) {
+                let chunk = chunk.to_vec();
+                let signer = wallet.signer.clone();
+                let payer = self.payer.clone();
+                let tool = tool.clone();
+                let task = tokio::spawn({
+                    let chunk = chunk.clone();
+                    async move {
+                        let instructions: Result<Vec<_>> = chunk
+                            .iter()
+                            .map(|delegation| {
+                                Ok(revoke(
+                                    &spl_token::id(),
+                                    &delegation.account,
+                                    &owner,
+                                    &[&owner],
+                                )?)
+                            })
+                            .collect();
+                        let result = match instructions {
+                            Ok(instructions) => {
+                                tool.sendtxn_and_watch(instructions, &*signer, &*payer)
+                                    .await
+                            }
+                            Err(e) => Err(e),
+                        };
+                        match result {
+                            Ok(ref signature) => info!(
+                                "Successfuly revoked {} delegations of {}, check the info: {}",
+                                chunk.len(),
+                                owner,
+                                tool.explorer_link(signature)
+                            ),
+                            Err(ref e) => error!(
+                                "Failed to revoke the delegations of {} with error: {}",
+                                owner, e
+                            ),
+                        }
+                        let entries = revoked_entries(&chunk, Status::of(owner, result));
+                        for entry in &entries {
+                            tool.output.emit(entry.record());
+                        }
+                        entries
+                    }
+                });
+                tasks.push((chunk, task));
+            }
+        }
+        report.approvals = join_entries(tasks, |chunk, failure| {
+            revoked_entries(&chunk, Status::Failed(failure))
+        })
+        .await
+        .into_iter()
+        .flatten()
+        .collect();
+        Ok(report)
+    }
+}
+
+/// The entries of `delegations`, revoked together in one transaction.
+fn revoked_entries(delegations: &[Delegation], status: Status) -> Vec<ApprovalEntry> {
+    delegations
+        .iter()
+        .map(|delegation| ApprovalEntry {
+            wallet: delegation.wallet,
+            account: delegation.account,
+            mint: delegation.mint,
+            delegate: None,
+            amount: 0,
+            status: status.clone(),
+        })
+        .collect()
+}
+
+/// The approvals worked out by `Approve::plan`, `Approve::run` sends exactly
+/// them.
+pub struct ApprovePlan {
+    /// What to confirm.
+    pub summary: RunSummary,
+    /// The wallets, their token account and signer.
+    pending: Vec<(Pubkey, Pubkey, SharedSigner)>,
+    /// The wallets without an account and those which could not be read.
+    settled: Vec<ApprovalEntry>,
+}
+
+/// Let one delegate move up to a bounded amount of a mint out of the
+/// associated token account of every wallet of a set.
+pub struct Approve {
+    payer: SharedSigner,
+    delegate: Pubkey,
+    mint: Pubkey,
+    decimals: u8,
+    amount: u64,
+}
+
+impl Approve {
+    /// `amount` is the raw amount allowed per wallet, `payer` pays the fee of
+    /// every approve.
+    pub fn new(
+        payer: SharedSigner,
+        delegate: Pubkey,
+        mint: Pubkey,
+        decimals: u8,
+        amount: u64,
+    ) -> Self {
+        Self {
+            payer,
+            delegate,
+            mint,
+            decimals,
+            amount,
+        }
+    }
+    fn entry(&self, wallet: Pubkey, account: Pubkey, status: Status) -> ApprovalEntry {
+        ApprovalEntry {
+            wallet,
+            account,
+            mint: self.mint,
+            delegate: Some(self.delegate),
+            amount: self.amount,
+            status,
+        }
+    }
+    /// The allowance given to the delegate, as the spending policy sees it.
+    fn transfer(&self, wallet: Pubkey) -> Transfer {
+        Transfer {
+            from: wallet,
+            to: self.delegate,
+            mint: Some(self.mint),
+            amount: self.amount,
+        }
+    }
+    fn instruction(&self, owner: &Pubkey, account: &Pubkey) -> Result<Instruction> {
+        Ok(approve_checked(
+            &spl_token::id(),
+            account,
+            &self.mint,
+            &self.delegate,
+            owner,
+            &[owner],
+            self.amount,
+            self.decimals,
+        )?)
+    }
+    /// Find the wallets with an account to approve on and work out what
+    /// `run` is going to send, nothing is sent.
+    pub async fn plan(&self, tool: &Tool, wallets: &WalletSet) -> Result<ApprovePlan> {
+        let mut settled = vec![];
+        let mut pending = vec![];
+        for wallet in wallets.iter() {
+            let owner = wallet.pubkey();
+            let account = get_associated_token_address(&owner, &self.mint);
+            match tool.token_holdings(&owner, Some(&self.mint)).await {
+                Ok(holdings) if holdings.iter().any(|holding| holding.address == account) => {
+                    pending.push((owner, account, wallet.signer.clone()))
+                }
+                Ok(_) => {
+                    warn!("{} has no token account of {}, skip it", owner, self.mint);
+                    settled.push(self.entry(owner, account, Status::Skipped));
+                }
+                Err(e) => {
+                    error!(
+                        "Failed to get the token accounts of {} with error: {}",
+                        owner, e
+                    );
+                    settled.push(self.entry(
+                        owner,
+                        account,
+                        Status::Failed(Failure::new(owner, e)),
+                    ));
+                }
+            }
+        }
+        let transfers: Vec<Transfer> = pending
+            .iter()
+            .map(|(owner, _, _)| self.transfer(*owner))
+            .collect();
+        let mut summary = RunSummary {
+            cluster: tool.cluster().await?,
+            recipients: transfers.len(),
+            total_amount: self.amount * transfers.len() as u64,
+            mint: Some(self.mint),
+            decimals: self.decimals,
+            accounts_to_create: 0,
+            account_rent: 0,
+            estimated_fee: 0,
+            allocation: vec![],
+            transfers,
+            frozen: vec![],
+        };
+        if let Some((owner, account, _)) = pending.first() {
+            let instruction = self.instruction(owner, account)?;
+            summary.estimated_fee = tool
+                .estimate_fee(vec![instruction], &self.payer.pubkey())
+                .await?
+                * pending.len() as u64;
+        }
+        Ok(ApprovePlan {
+            summary,
+            pending,
+            settled,
+        })
+    }
+    pub async fn run(&self, tool: &Tool, plan: ApprovePlan) -> Result<ApprovalReport> {
+        tool.authorize(&plan.summary.transfers, false)?;
+        for entry in &plan.settled {
+            tool.output.emit(entry.record());
+        }
+        let mut report = ApprovalReport {
+            approvals: plan.settled,
+            ..ApprovalReport::default()
+        };
+        let pending = plan.pending;
+        let mut tasks = vec![];
+        for (owner, account, signer) in pending {
+            let payer = self.payer.clone();
+            let tool = tool.clone();
+            let (delegate, amount) = (self.delegate, self.amount);
+            let instruction = self.instruction(&owner, &account);
+            let entry = self.entry(owner, account, Status::Skipped);
+            let task = tokio::spawn(async move {
+                let result: Result<Signature> = match instruction {
+                    Ok(instruction) => {
+                        tool.sendtxn_and_watch(vec![instruction], &*signer, &*payer)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                let status = match result {
+                    Ok(signature) => {
+                        info!(
+                            "Successfuly approved {} to move {} of {}, check the info: {}",
+                            delegate,
+                            amount,
+                            account,
+                            tool.explorer_link(&signature)
+                        );
+                        Status::Sent(signature)
+                    }
+                    Err(e) => {
+                        error!(
+                            "Failed to approve {} on {} with error: {}",
+                            delegate, account, e
+                        );
+                        Status::Failed(Failure::new(account, e))
+                    }
+                };
+                let entry = ApprovalEntry { status, ..entry };
+                tool.output.emit(entry.record());
+                entry
+            });
+            tasks.push(((owner, account), task));
+        }
+        report.approvals.extend(
+            join_entries(tasks, |(wallet, account), failure| {
+                self.entry(wallet, account, Status::Failed(failure))
+            })
+            .await,
+        );
+        let sent: Vec<Transfer> = report
+            .approvals
+            .iter()
+            .filter(|entry| matches!(entry.status, Status::Sent(_)))
+            .map(|entry| self.transfer(entry.wallet))
+            .collect();
+        report.failures.extend(tool.record_spending(&sent));
+        Ok(report)
+    }
+}
@@ -1,150 +1,529 @@
-use std::fs;
-use std::str::FromStr;
-use std::sync::Arc;
+use std::collections::HashMap;
 
+use anyhow::anyhow;
 use futures::future::join_all;
-use log::{error, info};
-use solana_sdk::signature::read_keypair_file;
-use solana_sdk::{pubkey::Pubkey, signer::Signer};
-use spl_token::instruction::transfer_checked;
+use log::{error, info, warn};
+use solana_sdk::{instruction::Instruction, pubkey::Pubkey};
+use spl_associated_token_account::get_associated_token_address;
 
+use crate::error::{Error, Result};
+use crate::plan::{FunderShare, RunSummary};
+use crate::report::{join_entries, Status, Transfer, TransferEntry, TransferReport};
+use crate::send_and_check::token_transfer_instructions;
+use crate::wallet_set::{SharedSigner, WalletSet};
+use crate::wsol::{is_native, wrap_instructions};
 use crate::Tool;
 
-impl Tool {
-    pub async fn distribute(
+/// Send the same amount of sol, or of a token, from one or more funders to
+/// every wallet of a set.
+pub struct BatchTransfer {
+    funders: Vec<SharedSigner>,
+    amount: u64,
+    token: Option<(Pubkey, u8)>,
+    bump_to_rent: bool,
+}
+
+/// Which funder pays which recipient.
+struct Allocation {
+    shares: Vec<FunderShare>,
+    /// The index in `shares` of the funder of each recipient.
+    assigned: Vec<usize>,
+}
+
+impl BatchTransfer {
+    /// `amount` is lamports, or the raw token amount once `token` is set.
+    pub fn new(funder: SharedSigner, amount: u64) -> Self {
+        Self {
+            funders: vec![funder],
+            amount,
+            token: None,
+            bump_to_rent: false,
+        }
+    }
+    /// Spread the recipients over these funders instead, each one gets a
+    /// share of them weighted by what it holds.
+    pub fn funders(mut self, funders: Vec<SharedSigner>) -> Self {
+        self.funders = funders;
+        self
+    }
+    /// Transfer this spl token instead of sol.
+    pub fn token(mut self, mint: Pubkey, decimals: u8) -> Self {
+        self.token = Some((mint, decimals));
+        self
+    }
+    /// Send the rent-exempt minimum instead of `amount` to the recipients
+    /// which do not exist yet, when `amount` is less than that. Without it
+    /// such a batch is refused.
+    pub fn bump_to_rent(mut self) -> Self {
+        self.bump_to_rent = true;
+        self
+    }
+    /// What each recipient gets, sol sent to a new account has to cover its
+    /// rent or the transfer fails on chain.
+    async fn amounts(&self, tool: &Tool, recipients: &WalletSet) -> Result<Vec<u64>> {
+        let amounts = vec![self.amount; recipients.len()];
+        if self.token.is_some() || recipients.is_empty() {
+            return Ok(amounts);
+        }
+        let minimum = tool.minimum_balance(0).await?;
+        if self.amount >= minimum {
+            return Ok(amounts);
+        }
+        let pubkeys: Vec<Pubkey> = recipients
+            .iter()
+            .map(|recipient| recipient.pubkey())
+            .collect();
+        let accounts = tool.get_accounts(&pubkeys).await?;
+        let fresh: Vec<usize> = accounts
+            .iter()
+            .enumerate()
+            .filter(|(_, account)| account.as_ref().is_none_or(|account| account.lamports == 0))
+            .map(|(index, _)| index)
+            .collect();
+        let Some(&first) = fresh.first() else {
+            return Ok(amounts);
+        };
+        if !self.bump_to_rent {
+            error!(
+                "{} of the recipients are new accounts which need at least {} lamports, pass --bump-to-rent to send them that instead",
+                fresh.len(),
+                minimum
+            );
+            return Err(Error::BelowRentExemption {
+                account: pubkeys[first].to_string(),
+                lamports: self.amount,
+                minimum,
+            });
+        }
+        let mut amounts = amounts;
+        for index in fresh {
+            amounts[index] = minimum;
+        }
+        Ok(amounts)
+    }
+    /// What each funder can spend, the sol or token balance. A funder with
+    /// a frozen token account has nothing to spend.
+    async fn funder_balances(&self, tool: &Tool) -> Result<Vec<u64>> {
+        let mint = self.token.map(|(mint, _)| mint);
+        join_all(self.funders.iter().map(|funder| {
+            let owner = funder.pubkey();
+            async move {
+                let balance = match mint {
+                    // wsol is wrapped from the funder's sol on the way
+                    Some(mint) if !is_native(&mint) => {
+                        let account = get_associated_token_address(&owner, &mint);
+                        tool.token_account(&account).await.map(|state| {
+                            if state.is_frozen() {
+                                warn!(
+                                    "The token account {} of the funder {} is frozen, it funds nothing",
+                                    account, owner
+                                );
+                                return 0;
+                            }
+                            state.amount
+                        })
+                    }
+                    _ => tool
+                        .rpc_pool
+                        .call(|client| async move { client.get_balance(&owner).await })
+                        .await
+                        .map_err(Error::from),
+                };
+                balance.map_err(|e| {
+                    error!(
+                        "Failed to get the balance of the funder {} with error: {}",
+                        owner, e
+                    );
+                    e
+                })
+            }
+        }))
+        .await
+        .into_iter()
+        .collect()
+    }
+    /// The recipients whose token account is frozen with that account, a
+    /// transfer to them fails on chain.
+    async fn frozen_recipients(
         &self,
-        sub_keypair_folder: String,
-        main_keypair_file: String,
-        lamports: u64,
-        token_address: Option<String>,
-        decimals: Option<u8>,
-    ) {
-        let main_keypair = Arc::new(
-            read_keypair_file(main_keypair_file)
-                .expect("can not generate the main keypair from file"),
-        );
-        match fs::read_dir(sub_keypair_folder) {
-            Ok(folder) => {
-                let mut tasks = vec![];
-                for file in folder {
-                    let file_entry = file.unwrap();
-                    let file_path = file_entry.path();
-                    match read_keypair_file(file_path.clone()) {
-                        Ok(sub_keypair) => {
-                            let main_keypair_clone = main_keypair.clone();
-                            let rpc_client_clone = self.rpc_client.clone();
-                            match token_address {
-                                Some(ref coin_address) => {
-                                    let decimals =
-                                        decimals.expect("need put your spl token decimals");
-                                    let coin_pubkey = Pubkey::from_str(&coin_address).unwrap();
-                                    let task = tokio::spawn(async move {
-                                        // let rpc_clone = rpc_client_clone.clone();
-                                        let recipient_token_account_address =
-                                            Tool::check_token_account(
-                                                &rpc_client_clone,
-                                                &main_keypair_clone,
-                                                &sub_keypair,
-                                                &coin_pubkey,
-                                            )
-                                            .await;
-                                        let sender_token_account_pubkey =
-                                        spl_associated_token_account::get_associated_token_address(
-                                            &main_keypair_clone.pubkey(),
-                                            &coin_pubkey,
-                                        );
-                                        match transfer_checked(
-                                            &spl_token::id(),
-                                            &sender_token_account_pubkey,
-                                            &coin_pubkey,
-                                            &recipient_token_account_address,
-                                            &main_keypair_clone.pubkey(),
-                                            &[&main_keypair_clone.pubkey()],
-                                            lamports,
-                                            decimals,
-                                        ) {
-                                            Ok(spl_transfer_instruction) => {
-                                                match Tool::sendtxn_and_watch(
-                                                    &rpc_client_clone,
-                                                    spl_transfer_instruction,
-                                                    &main_keypair_clone,
-                                                    &main_keypair_clone,
-                                                )
-                                                .await
-                                                {
-                                                    Ok(signature) => {
-                                                        info!("Successfuly to transfer from {} to {}, check the info: https://solscan.io/tx/{}",&main_keypair_clone.pubkey(),&sub_keypair.pubkey(),signature);
-                                                    }
-                                                    Err(e) => {
-                                                        error!(
-                                                            "Failed to transfer with error is {}",
-                                                            e
-                                                        )
-                                                    }
-                                                }
-                                            }
-                                            Err(e) => {
-                                                error!("failed the build the spl transfer instruction with error: {}",e)
-                                            }
-                                        }
-                                    });
-                                    tasks.push(task);
-                                }
-                                None => {
-                                    let task = tokio::spawn(async move {
-                                        let transfer_sol_instruction =
-                                            solana_sdk::system_instruction::transfer(
-                                                &main_keypair_clone.pubkey(),
-                                                &sub_keypair.pubkey(),
-                                                lamports,
-                                            );
-                                        match Tool::sendtxn_and_watch(
-                                            &rpc_client_clone,
-                                            transfer_sol_instruction,
-                                            &main_keypair_clone,
-                                            &main_keypair_clone,
-                                        )
-                                        .await
-                                        {
-                                            Ok(signature) => {
-                                                info!(
-                                                    "Successfuly to transfer sol from {} to {}, check the info: {}",
-                                                    &main_keypair_clone.pubkey(),
-                                                    &sub_keypair.pubkey(),
-                                                    // fee,
-                                                    signature
-                                                )
-                                            }
-                                            Err(e) => {
-                                                error!("Failed transfer sol from {} to {} with error: {}", &main_keypair_clone.pubkey(),&sub_keypair.pubkey(),e)
-                                            }
-                                        }
-                                    });
-                                    tasks.push(task)
+        tool: &Tool,
+        recipients: &WalletSet,
+    ) -> Result<HashMap<Pubkey, Pubkey>> {
+        let Some((mint, _)) = self.token.filter(|(mint, _)| !is_native(mint)) else {
+            return Ok(HashMap::new());
+        };
+        let accounts: Vec<Pubkey> = recipients
+            .iter()
+            .map(|recipient| get_associated_token_address(&recipient.pubkey(), &mint))
+            .collect();
+        let frozen = tool.frozen_accounts(&accounts).await?;
+        Ok(recipients
+            .iter()
+            .zip(&accounts)
+            .filter(|(_, account)| frozen.contains(account))
+            .map(|(recipient, account)| (recipient.pubkey(), *account))
+            .collect())
+    }
+    /// Give every recipient to the funder with the most left, so the
+    /// recipients follow the balances and no funder is asked for more than
+    /// it has.
+    async fn allocate(
+        &self,
+        tool: &Tool,
+        recipients: &WalletSet,
+        amounts: &[u64],
+    ) -> Result<Allocation> {
+        let balances = self.funder_balances(tool).await?;
+        // a sol funder also pays the fee of each of its transfers
+        let pays_in_sol = self.token.is_none_or(|(mint, _)| is_native(&mint));
+        let fee = match (pays_in_sol, recipients.iter().next()) {
+            (true, Some(sample)) => {
+                let funder = self.funders[0].pubkey();
+                let instructions =
+                    transfer_instructions(self.token, &funder, &sample.pubkey(), self.amount)?;
+                tool.estimate_fee(instructions, &funder).await?
+            }
+            _ => 0,
+        };
+        // and the rent of the wsol accounts it creates
+        let rents = match self.token {
+            Some((mint, _)) if is_native(&mint) => {
+                let accounts: Vec<Pubkey> = recipients
+                    .iter()
+                    .map(|recipient| get_associated_token_address(&recipient.pubkey(), &mint))
+                    .collect();
+                let rent = tool.token_account_rent(1).await?;
+                tool.get_accounts(&accounts)
+                    .await?
+                    .iter()
+                    .map(|account| if account.is_none() { rent } else { 0 })
+                    .collect()
+            }
+            _ => vec![0; amounts.len()],
+        };
+        let costs: Vec<u64> = amounts
+            .iter()
+            .zip(&rents)
+            .map(|(amount, rent)| amount + fee + rent)
+            .collect();
+        let mut shares: Vec<FunderShare> = self
+            .funders
+            .iter()
+            .zip(&balances)
+            .map(|(funder, balance)| FunderShare {
+                funder: funder.pubkey(),
+                balance: *balance,
+                recipients: 0,
+                amount: 0,
+            })
+            .collect();
+        // a sol funder is drained or stays rent exempt
+        let reserve = match pays_in_sol {
+            true => tool.minimum_balance(0).await?,
+            false => 0,
+        };
+        let assigned = match spread(&balances, &costs, reserve) {
+            Ok(assigned) => assigned,
+            Err(paid) => {
+                let needed: u64 = costs.iter().sum();
+                let needed = match balances.as_slice() {
+                    [balance] if *balance != needed => needed + reserve,
+                    _ => needed,
+                };
+                return Err(match (shares.as_slice(), self.token) {
+                    ([share], _) if pays_in_sol => Error::InsufficientBalance {
+                        account: share.funder.to_string(),
+                        balance: share.balance,
+                        needed,
+                    },
+                    ([share], Some((mint, _))) => anyhow!(
+                        "{} holds {} of the token {}, not enough to send {}",
+                        share.funder,
+                        share.balance,
+                        mint,
+                        needed
+                    )
+                    .into(),
+                    _ => anyhow!(
+                        "the {} funders can only pay {} of the {} wallets, each wallet has to be paid by a single funder",
+                        shares.len(),
+                        paid,
+                        recipients.len()
+                    )
+                    .into(),
+                });
+            }
+        };
+        for (index, amount) in assigned.iter().zip(amounts) {
+            shares[*index].recipients += 1;
+            shares[*index].amount += amount;
+        }
+        Ok(Allocation { shares, assigned })
+    }
+    /// Work out what `run` is going to send, nothing is sent. `run` sends
+    /// exactly the transfers of the summary, as confirmed.
+    pub async fn plan(&self, tool: &Tool, recipients: &WalletSet) -> Result<RunSummary> {
+        let funder = self.funders[0].pubkey();
+        let amounts = self.amounts(tool, recipients).await?;
+        let allocation = self.allocate(tool, recipients, &amounts).await?;
+        let frozen = self.frozen_recipients(tool, recipients).await?;
+        if !frozen.is_empty() {
+            warn!(
+                "{} of the recipients have a frozen token account, they get nothing",
+                frozen.len()
+            );
+        }
+        let (transfers, frozen): (Vec<Transfer>, Vec<(Transfer, Pubkey)>) = recipients
+            .iter()
+            .zip(allocation.assigned.iter().zip(&amounts))
+            .map(|(recipient, (funder, amount))| Transfer {
+                from: allocation.shares[*funder].funder,
+                to: recipient.pubkey(),
+                mint: self.token.map(|(mint, _)| mint),
+                amount: *amount,
+            })
+            .fold((vec![], vec![]), |(mut sent, mut refused), transfer| {
+                match frozen.get(&transfer.to) {
+                    Some(account) => refused.push((transfer, *account)),
+                    None => sent.push(transfer),
+                }
+                (sent, refused)
+            });
+        let mut summary = RunSummary {
+            cluster: tool.cluster().await?,
+            recipients: recipients.len(),
+            total_amount: amounts.iter().sum(),
+            mint: self.token.map(|(mint, _)| mint),
+            decimals: self.token.map_or(9, |(_, decimals)| decimals),
+            accounts_to_create: 0,
+            account_rent: 0,
+            estimated_fee: 0,
+            allocation: allocation.shares,
+            transfers,
+            frozen,
+        };
+        let Some(sample) = recipients.iter().next().map(|recipient| recipient.pubkey()) else {
+            return Ok(summary);
+        };
+        if let Some((mint, _)) = self.token {
+            // only the rent needs to know which accounts are missing
+            let accounts: Vec<Pubkey> = recipients
+                .iter()
+                .map(|recipient| get_associated_token_address(&recipient.pubkey(), &mint))
+                .collect();
+            summary.accounts_to_create = tool.count_missing(&accounts).await?;
+            summary.account_rent = tool.token_account_rent(summary.accounts_to_create).await?;
+        }
+        let instructions = transfer_instructions(self.token, &funder, &sample, self.amount)?;
+        summary.estimated_fee +=
+            tool.estimate_fee(instructions, &funder).await? * summary.recipients as u64;
+        Ok(summary)
+    }
+    /// Send the transfers `plan` worked out, the recipients with a frozen
+    /// account are refused.
+    pub async fn run(&self, tool: &Tool, plan: &RunSummary) -> Result<TransferReport> {
+        tool.authorize(&plan.transfers, false)?;
+        let funders: HashMap<Pubkey, SharedSigner> = self
+            .funders
+            .iter()
+            .map(|funder| (funder.pubkey(), funder.clone()))
+            .collect();
+        let mut refused = vec![];
+        for (transfer, account) in plan.frozen.iter().copied() {
+            let e = Error::Frozen {
+                account: account.to_string(),
+            };
+            error!("Refuse to transfer to {} with error: {}", transfer.to, e);
+            let entry = TransferEntry {
+                transfer,
+                status: Status::of(transfer.to, Err(e)),
+            };
+            tool.output.emit(entry.record());
+            refused.push(entry);
+        }
+        let mut tasks = vec![];
+        for transfer in plan.transfers.iter().copied() {
+            let Some(main_keypair_clone) = funders.get(&transfer.from).cloned() else {
+                return Err(anyhow!("{} is not one of the funders", transfer.from).into());
+            };
+            let recipient = transfer.to;
+            let tool = tool.clone();
+            let token = self.token;
+            let task = tokio::spawn(async move {
+                let result = match token {
+                    Some(_) => {
+                        match transfer_instructions(
+                            token,
+                            &main_keypair_clone.pubkey(),
+                            &recipient,
+                            transfer.amount,
+                        ) {
+                            Ok(instructions) => {
+                                let result = tool
+                                    .sendtxn_and_watch(
+                                        instructions,
+                                        &*main_keypair_clone,
+                                        &*main_keypair_clone,
+                                    )
+                                    .await;
+                                match result {
+                                    Ok(ref signature) => {
+                                        info!("Successfuly to transfer from {} to {}, check the info: {}",&main_keypair_clone.pubkey(),&recipient,tool.explorer_link(signature));
+                                    }
+                                    Err(ref e) => {
+                                        error!("Failed to transfer with error is {}", e)
+                                    }
                                 }
+                                result
+                            }
+                            Err(e) => {
+                                error!(
+                                    "failed the build the spl transfer instruction with error: {}",
+                                    e
+                                );
+                                Err(e)
                             }
-                        }
-                        Err(e) => {
-                            error!(
-                                "can not read the keypair from the {:?}, the error is {}",
-                                &file_path, e
-                            )
                         }
                     }
-                }
-                let results = join_all(tasks).await;
-                for result in results {
-                    match result {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!("task handle failed with error: {}", e)
+                    None => {
+                        let transfer_sol_instruction = solana_sdk::system_instruction::transfer(
+                            &main_keypair_clone.pubkey(),
+                            &recipient,
+                            transfer.amount,
+                        );
+                        let result = tool
+                            .sendtxn_and_watch(
+                                vec![transfer_sol_instruction],
+                                &*main_keypair_clone,
+                                &*main_keypair_clone,
+                            )
+                            .await;
+                        match result {
+                            Ok(ref signature) => {
+                                info!(
+                                    "Successfuly to transfer sol from {} to {}, check the info: {}",
+                                    &main_keypair_clone.pubkey(),
+                                    &recipient,
+                                    // fee,
+                                    tool.explorer_link(signature)
+                                )
+                            }
+                            Err(ref e) => {
+                                error!(
+                                    "Failed transfer sol from {} to {} with error: {}",
+                                    &main_keypair_clone.pubkey(),
+                                    &recipient,
+                                    e
+                                )
+                            }
                         }
+                        result
                     }
-                }
-            }
-            Err(e) => {
-                error!("can not open the sub keypair folder with error: {}", e)
-            }
+                };
+                let entry = TransferEntry {
+                    transfer,
+                    status: Status::of(transfer.to, result),
+                };
+                tool.output.emit(entry.record());
+                entry
+            });
+            tasks.push((transfer, task));
         }
+        let mut transfers = join_entries(tasks, |transfer, failure| TransferEntry {
+            transfer,
+            status: Status::Failed(failure),
+        })
+        .await;
+        transfers.extend(refused);
+        let mut report = TransferReport {
+            transfers,
+            failures: vec![],
+        };
+        let sent = report.sent();
+        report.failures.extend(tool.record_spending(&sent));
+        Ok(report)
+    }
+}
+
+/// What one recipient is sent: sol, a token out of the funder's associated
+/// account, or wsol wrapped from the funder's sol into the recipient's
+/// account.
+fn transfer_instructions(
+    token: Option<(Pubkey, u8)>,
+    funder: &Pubkey,
+    recipient: &Pubkey,
+    amount: u64,
+) -> Result<Vec<Instruction>> {
+    match token {
+        Some((mint, _)) if is_native(&mint) => wrap_instructions(funder, recipient, amount),
+        Some((mint, decimals)) => {
+            token_transfer_instructions(funder, funder, recipient, &mint, amount, decimals)
+        }
+        None => Ok(vec![solana_sdk::system_instruction::transfer(
+            funder, recipient, amount,
+        )]),
+    }
+}
+
+/// The funder of each recipient, always the one with the most left which
+/// can pay it, or how many recipients could be paid when the balances do not
+/// cover all the `costs`. A funder pays its whole balance or keeps at least
+/// `reserve` of it.
+fn spread(balances: &[u64], costs: &[u64], reserve: u64) -> std::result::Result<Vec<usize>, usize> {
+    let mut left = balances.to_vec();
+    let mut assigned = Vec::with_capacity(costs.len());
+    for cost in costs {
+        let fits = |remaining: u64| remaining == *cost || remaining >= cost + reserve;
+        let Some((index, _)) = left
+            .iter()
+            .copied()
+            .enumerate()
+            .filter(|(_, remaining)| fits(*remaining))
+            .max_by_key(|(_, remaining)| *remaining)
+        else {
+            return Err(assigned.len());
+        };
+        left[index] -= cost;
+        assigned.push(index);
+    }
+    Ok(assigned)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spreads_the_recipients_over_the_balances() {
+        assert_eq!(spread(&[100, 50], &[30, 30, 30], 0), Ok(vec![0, 0, 1]));
+        assert_eq!(spread(&[60, 100], &[30, 30, 30], 0), Ok(vec![1, 1, 0]));
+    }
+
+    #[test]
+    fn a_recipient_is_paid_by_a_single_funder() {
+        // 80 is left in total but no funder has 40 of it
+        assert_eq!(spread(&[50, 50], &[40, 40, 40], 0), Err(2));
+    }
+
+    #[test]
+    fn a_single_funder_pays_up_to_its_balance() {
+        assert_eq!(spread(&[90], &[30, 30, 30], 0), Ok(vec![0, 0, 0]));
+        assert_eq!(spread(&[89], &[30, 30, 30], 0), Err(2));
+        assert_eq!(spread(&[], &[1], 0), Err(0));
+    }
+
+    #[test]
+    fn a_funder_is_drained_or_stays_rent_exempt() {
+        // 100 less 60 leaves 40, below the reserve of 50
+        assert_eq!(spread(&[100], &[60], 50), Err(0));
+        assert_eq!(spread(&[100], &[50], 50), Ok(vec![0]));
+        assert_eq!(spread(&[100], &[30, 60], 50), Err(1));
+        // paying out the whole balance is fine
+        assert_eq!(spread(&[100], &[100], 50), Ok(vec![0]));
+        assert_eq!(spread(&[100], &[50, 50], 50), Ok(vec![0, 0]));
+        // the richer funder can not pay without going below it, the other can
+        assert_eq!(spread(&[100, 90], &[60], 50), Err(0));
+        assert_eq!(spread(&[150, 90], &[40, 60], 50), Ok(vec![0, 0]));
+        assert_eq!(spread(&[100, 60], &[60], 50), Ok(vec![1]));
     }
 }
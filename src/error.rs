@@ -0,0 +1,147 @@
+use std::io;
+
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    rpc_request::{RpcError, RpcResponseErrorData},
+};
+use solana_program::program_error::ProgramError;
+use solana_sdk::{pubkey::ParsePubkeyError, signer::SignerError};
+use thiserror::Error;
+
+use crate::outcome::FailureKind;
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Everything the `Tool` methods can fail with.
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("invalid address {input}: {source}")]
+    InvalidPubkey {
+        input: String,
+        source: ParsePubkeyError,
+    },
+    #[error("Failed to read the keypair {path}: {reason}")]
+    Keypair { path: String, reason: String },
+    #[error("invalid bs58 key: {0}")]
+    InvalidKey(String),
+    #[error("Failed to access {path}: {source}")]
+    Io { path: String, source: io::Error },
+    #[error("need put your spl token decimals")]
+    MissingDecimals,
+    #[error("{account} has {balance} lamports, not enough to pay {needed} lamports")]
+    InsufficientBalance {
+        account: String,
+        balance: u64,
+        needed: u64,
+    },
+    #[error(
+        "{account} would hold {lamports} lamports, less than the rent-exempt minimum of {minimum}"
+    )]
+    BelowRentExemption {
+        account: String,
+        lamports: u64,
+        minimum: u64,
+    },
+    #[error("{signature} landed but {account} still holds {lamports} lamports")]
+    NotDrained {
+        account: String,
+        lamports: u64,
+        signature: String,
+    },
+    #[error("{account} is not a token account: {source}")]
+    NotTokenAccount {
+        account: String,
+        source: ProgramError,
+    },
+    #[error("the token account {account} is frozen")]
+    Frozen { account: String },
+    #[error("{account} is not a mint: {source}")]
+    NotMint {
+        account: String,
+        source: ProgramError,
+    },
+    #[error("the spending policy refuses the run: {0}")]
+    Policy(String),
+    #[error("the run was not confirmed, type yes at the prompt or pass --yes")]
+    NotConfirmed,
+    #[error("Failed to sign the transaction: {0}")]
+    Signing(#[from] SignerError),
+    #[error("Failed to build the instruction: {0}")]
+    Instruction(#[from] ProgramError),
+    #[error("{}", describe(.0))]
+    Config(anyhow::Error),
+    #[error("{}", describe(.0))]
+    Ledger(anyhow::Error),
+    #[error(transparent)]
+    Rpc(Box<ClientError>),
+    #[error("{}", describe(.0))]
+    Other(#[from] anyhow::Error),
+}
+
+impl From<ClientError> for Error {
+    fn from(client_error: ClientError) -> Self {
+        Error::Rpc(Box::new(client_error))
+    }
+}
+
+impl Error {
+    pub fn keypair(path: impl ToString, reason: impl ToString) -> Self {
+        Error::Keypair {
+            path: path.to_string(),
+            reason: reason.to_string(),
+        }
+    }
+    pub fn io(path: impl ToString, source: io::Error) -> Self {
+        Error::Io {
+            path: path.to_string(),
+            source,
+        }
+    }
+    pub fn kind(&self) -> FailureKind {
+        match self {
+            Error::Rpc(client_error) => client_error_kind(client_error),
+            Error::Other(error) => error
+                .chain()
+                .find_map(|cause| cause.downcast_ref::<ClientError>())
+                .map_or(FailureKind::Input, client_error_kind),
+            Error::Frozen { .. } | Error::NotDrained { .. } => FailureKind::OnChain,
+            _ => FailureKind::Input,
+        }
+    }
+}
+
+fn client_error_kind(client_error: &ClientError) -> FailureKind {
+    match client_error.kind() {
+        ClientErrorKind::TransactionError(_) => FailureKind::OnChain,
+        ClientErrorKind::RpcError(RpcError::RpcResponseError {
+            data: RpcResponseErrorData::SendTransactionPreflightFailure(_),
+            ..
+        }) => FailureKind::OnChain,
+        _ => FailureKind::Rpc,
+    }
+}
+
+/// Parse a base58 address given on the command line.
+pub fn parse_pubkey(input: &str) -> Result<solana_sdk::pubkey::Pubkey> {
+    input.parse().map_err(|source| Error::InvalidPubkey {
+        input: input.to_string(),
+        source,
+    })
+}
+
+/// The error and its causes like `{:#}`, leaving out the causes the client
+/// errors already repeat in their own message.
+fn describe(error: &anyhow::Error) -> String {
+    let mut description = String::new();
+    for cause in error.chain() {
+        let cause = cause.to_string();
+        if description.contains(&cause) {
+            continue;
+        }
+        if !description.is_empty() {
+            description.push_str(": ");
+        }
+        description.push_str(&cause);
+    }
+    description
+}
@@ -0,0 +1,370 @@
+use std::fmt::{self, Display};
+use std::fs;
+use std::ops::Range;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use bip39::{Language, Mnemonic, Seed};
+use serde_json::Value;
+use solana_clap_utils::keypair::keypair_from_seed_phrase;
+use solana_sdk::derivation_path::DerivationPath;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{keypair_from_seed_and_derivation_path, Keypair};
+use solana_sdk::signer::Signer;
+
+use crate::error::{parse_pubkey, Error, Result};
+use crate::keystore::{keystore_password, read_keystore};
+use crate::outcome::Failure;
+use crate::signer::{default_socket_path, RemoteSigner};
+use crate::utils::{keypair_from_bs58, read_keypair};
+use crate::wallet_set::{SharedSigner, WalletSet};
+
+/// Somewhere keypairs can be read from, for the main wallet or the sub
+/// wallets of a command.
+pub trait KeySource: Display + Send + Sync {
+    /// Every wallet of the source, the entries which can not be read are
+    /// kept in `unreadable`.
+    fn load(&self) -> Result<WalletSet>;
+    /// The single wallet of a source, for the main wallet.
+    fn signer(&self) -> Result<SharedSigner> {
+        let mut wallet_set = self.load()?;
+        if let Some(failure) = wallet_set.unreadable.pop() {
+            return Err(Error::keypair(self, failure.error));
+        }
+        match wallet_set.wallets.len() {
+            1 => Ok(wallet_set.wallets.remove(0).signer),
+            count => Err(Error::keypair(
+                self,
+                format!("expected one keypair, found {}", count),
+            )),
+        }
+    }
+}
+
+/// Work out the key source from a `--main-keypair-file` or
+/// `--sub-keypair-folder` value:
+///
+/// - a folder of keypair json files, or one keypair json file
+/// - a `.jsonl` or `.csv` file with one bs58 secret key per line
+/// - `keystore://<file>`, an encrypted keystore made by `convert keystore`
+/// - `mnemonic://<file>?range=0..10`, the wallets `m/44'/501'/i'/0'` of the
+///   seed phrase in the file
+/// - `prompt://` (with `?key=0/0`) and `stdin://` like the solana cli
+/// - `remote-signer://<socket>` (with `?pubkey=<address>` for one of them),
+///   the wallets of a `signer serve` daemon
+pub fn parse_key_source(source: &str) -> Result<Box<dyn KeySource>> {
+    let (scheme, rest) = match source.split_once("://") {
+        Some((scheme, rest)) => (scheme, rest),
+        None => ("file", source),
+    };
+    let (location, query) = match rest.split_once('?') {
+        Some((location, query)) => (location, Some(query)),
+        None => (rest, None),
+    };
+    let param = |name: &str| {
+        query.and_then(|query| {
+            query
+                .split('&')
+                .filter_map(|pair| pair.split_once('='))
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| value)
+        })
+    };
+    match scheme {
+        "file" => {
+            let path = PathBuf::from(location);
+            let extension = path.extension().and_then(|extension| extension.to_str());
+            Ok(if path.is_dir() {
+                Box::new(KeypairFolder(path))
+            } else if extension == Some("jsonl") {
+                Box::new(SecretsFile::new(path, SecretsFormat::Jsonl))
+            } else if extension == Some("csv") {
+                Box::new(SecretsFile::new(path, SecretsFormat::Csv))
+            } else {
+                Box::new(KeypairFile(path))
+            })
+        }
+        "keystore" => Ok(Box::new(Keystore(PathBuf::from(location)))),
+        "mnemonic" => {
+            let range = match param("range") {
+                Some(range) => parse_range(range)
+                    .ok_or_else(|| Error::keypair(source, "the range should look like 0..10"))?,
+                None => 0..1,
+            };
+            Ok(Box::new(MnemonicFile {
+                path: PathBuf::from(location),
+                range,
+            }))
+        }
+        "prompt" => {
+            let derivation_path = param("key")
+                .map(DerivationPath::from_key_str)
+                .transpose()
+                .map_err(|e| Error::keypair(source, e))?;
+            Ok(Box::new(Prompt { derivation_path }))
+        }
+        "stdin" => Ok(Box::new(Stdin)),
+        "remote-signer" => Ok(Box::new(RemoteSigners {
+            socket: match location {
+                "" => PathBuf::from(default_socket_path()),
+                location => PathBuf::from(location),
+            },
+            pubkey: param("pubkey").map(parse_pubkey).transpose()?,
+        })),
+        _ => Err(Error::keypair(
+            source,
+            format!("unknown key source {}://", scheme),
+        )),
+    }
+}
+
+/// `3..7`, or `3` for only that index.
+fn parse_range(range: &str) -> Option<Range<u32>> {
+    match range.split_once("..") {
+        Some((start, end)) => Some(start.parse().ok()?..end.parse().ok()?),
+        None => {
+            let index: u32 = range.parse().ok()?;
+            Some(index..index + 1)
+        }
+    }
+}
+
+/// A folder of keypair json files, like `wallet crate` writes.
+pub struct KeypairFolder(pub PathBuf);
+
+impl KeySource for KeypairFolder {
+    fn load(&self) -> Result<WalletSet> {
+        WalletSet::load(&self.0)
+    }
+}
+
+impl Display for KeypairFolder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.display())
+    }
+}
+
+/// One keypair json file.
+pub struct KeypairFile(pub PathBuf);
+
+impl KeySource for KeypairFile {
+    fn load(&self) -> Result<WalletSet> {
+        let mut wallet_set = WalletSet::default();
+        wallet_set.push(self.0.display(), read_keypair(&self.0)?);
+        Ok(wallet_set)
+    }
+}
+
+impl Display for KeypairFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0.display())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum SecretsFormat {
+    /// A json string with the bs58 key, a byte array, or an object with a
+    /// `secret_key`, `private_key` or `secret` field per line.
+    Jsonl,
+    /// The first column which holds a bs58 key, a header line is skipped.
+    Csv,
+}
+
+/// A file with one secret key per line, like wallets and bots export them.
+pub struct SecretsFile {
+    pub path: PathBuf,
+    pub format: SecretsFormat,
+}
+
+impl SecretsFile {
+    pub fn new(path: PathBuf, format: SecretsFormat) -> Self {
+        Self { path, format }
+    }
+}
+
+impl KeySource for SecretsFile {
+    fn load(&self) -> Result<WalletSet> {
+        let content =
+            fs::read_to_string(&self.path).map_err(|e| Error::io(self.path.display(), e))?;
+        let mut wallet_set = WalletSet::default();
+        for (index, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let origin = format!("{}:{}", self.path.display(), index + 1);
+            let keypair = match self.format {
+                SecretsFormat::Jsonl => jsonl_keypair(line),
+                SecretsFormat::Csv => {
+                    let keypair = line
+                        .split(',')
+                        .find_map(|field| keypair_from_bs58(field.trim().trim_matches('"')).ok());
+                    match keypair {
+                        Some(keypair) => Ok(keypair),
+                        // the header
+                        None if index == 0 => continue,
+                        None => Err(Error::InvalidKey("no bs58 key in the line".to_string())),
+                    }
+                }
+            };
+            match keypair {
+                Ok(keypair) => wallet_set.push(origin, keypair),
+                Err(e) => wallet_set.unreadable.push(Failure::new(origin, e)),
+            }
+        }
+        Ok(wallet_set)
+    }
+}
+
+impl Display for SecretsFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.path.display())
+    }
+}
+
+fn jsonl_keypair(line: &str) -> Result<Keypair> {
+    let value: Value = serde_json::from_str(line).map_err(|e| Error::InvalidKey(e.to_string()))?;
+    let value = match value {
+        Value::Object(mut fields) => ["secret_key", "private_key", "secret"]
+            .iter()
+            .find_map(|name| fields.remove(*name))
+            .ok_or_else(|| Error::InvalidKey("no secret_key field".to_string()))?,
+        value => value,
+    };
+    match value {
+        Value::String(secret) => keypair_from_bs58(&secret),
+        Value::Array(_) => {
+            let bytes: Vec<u8> =
+                serde_json::from_value(value).map_err(|e| Error::InvalidKey(e.to_string()))?;
+            Keypair::from_bytes(&bytes).map_err(|e| Error::InvalidKey(e.to_string()))
+        }
+        _ => Err(Error::InvalidKey(
+            "expected a bs58 string or a byte array".to_string(),
+        )),
+    }
+}
+
+/// An encrypted keystore, the password comes from
+/// `SOLANA_TOOL_KEYSTORE_PASSWORD` or is asked for.
+pub struct Keystore(pub PathBuf);
+
+impl KeySource for Keystore {
+    fn load(&self) -> Result<WalletSet> {
+        let password = keystore_password(&self.0, false)?;
+        let mut wallet_set = WalletSet::default();
+        for (index, keypair) in read_keystore(&self.0, &password)?.into_iter().enumerate() {
+            wallet_set.push(format!("{}#{}", self, index), keypair);
+        }
+        Ok(wallet_set)
+    }
+}
+
+impl Display for Keystore {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "keystore://{}", self.0.display())
+    }
+}
+
+/// The wallets `m/44'/501'/i'/0'` of a seed phrase kept in a file, for
+/// every `i` of the range.
+pub struct MnemonicFile {
+    pub path: PathBuf,
+    pub range: Range<u32>,
+}
+
+impl KeySource for MnemonicFile {
+    fn load(&self) -> Result<WalletSet> {
+        let phrase =
+            fs::read_to_string(&self.path).map_err(|e| Error::io(self.path.display(), e))?;
+        let phrase = phrase.split_whitespace().collect::<Vec<_>>().join(" ");
+        let mnemonic = Mnemonic::from_phrase(&phrase, Language::English)
+            .map_err(|e| Error::keypair(self, e))?;
+        let seed = Seed::new(&mnemonic, "");
+        let mut wallet_set = WalletSet::default();
+        for index in self.range.clone() {
+            let derivation_path = DerivationPath::new_bip44(Some(index), Some(0));
+            let origin = format!("{}?key={}/0", self, index);
+            let keypair =
+                keypair_from_seed_and_derivation_path(seed.as_bytes(), Some(derivation_path))
+                    .map_err(|e| Error::keypair(&origin, e))?;
+            wallet_set.push(origin, keypair);
+        }
+        Ok(wallet_set)
+    }
+}
+
+impl Display for MnemonicFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "mnemonic://{}", self.path.display())
+    }
+}
+
+/// Ask for a seed phrase on the terminal like the solana cli `prompt://`.
+pub struct Prompt {
+    pub derivation_path: Option<DerivationPath>,
+}
+
+impl KeySource for Prompt {
+    fn load(&self) -> Result<WalletSet> {
+        let keypair =
+            keypair_from_seed_phrase("prompt", false, false, self.derivation_path.clone(), false)
+                .map_err(|e| Error::keypair(self, e))?;
+        let mut wallet_set = WalletSet::default();
+        wallet_set.push(self, keypair);
+        Ok(wallet_set)
+    }
+}
+
+impl Display for Prompt {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "prompt://")
+    }
+}
+
+/// A keypair json read from stdin.
+pub struct Stdin;
+
+impl KeySource for Stdin {
+    fn load(&self) -> Result<WalletSet> {
+        let keypair = solana_sdk::signature::read_keypair(&mut std::io::stdin())
+            .map_err(|e| Error::keypair(self, e))?;
+        let mut wallet_set = WalletSet::default();
+        wallet_set.push(self, keypair);
+        Ok(wallet_set)
+    }
+}
+
+impl Display for Stdin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "stdin://")
+    }
+}
+
+/// The wallets of a `signer serve` daemon, their keys never leave it.
+pub struct RemoteSigners {
+    pub socket: PathBuf,
+    /// Only this wallet of the daemon.
+    pub pubkey: Option<Pubkey>,
+}
+
+impl KeySource for RemoteSigners {
+    fn load(&self) -> Result<WalletSet> {
+        let signers = RemoteSigner::connect(&self.socket).map_err(|e| Error::keypair(self, e))?;
+        let mut wallet_set = WalletSet::default();
+        for signer in signers {
+            if self.pubkey.is_none_or(|pubkey| pubkey == signer.pubkey()) {
+                wallet_set.push_signer(
+                    format!("{}?pubkey={}", self, signer.pubkey()),
+                    Arc::new(signer),
+                );
+            }
+        }
+        Ok(wallet_set)
+    }
+}
+
+impl Display for RemoteSigners {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "remote-signer://{}", self.socket.display())
+    }
+}
@@ -0,0 +1,125 @@
+use std::fs;
+use std::path::Path;
+
+use aes_gcm_siv::aead::{Aead, NewAead};
+use aes_gcm_siv::{Aes256GcmSiv, Key, Nonce};
+use hmac::Hmac;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use solana_sdk::signature::Keypair;
+
+use crate::error::{Error, Result};
+use crate::utils::keypair_from_bs58;
+
+/// Read the keystore password from here before prompting for it.
+pub const PASSWORD_ENV: &str = "SOLANA_TOOL_KEYSTORE_PASSWORD";
+const KDF: &str = "pbkdf2-sha256";
+const ITERATIONS: u32 = 600_000;
+
+/// A keystore file: the bs58 secret keys of its wallets, as a json array,
+/// encrypted with aes-256-gcm-siv under a key derived from the password.
+#[derive(Serialize, Deserialize)]
+struct KeystoreFile {
+    version: u32,
+    kdf: String,
+    iterations: u32,
+    /// bs58 of the salt, the nonce and the encrypted secrets.
+    salt: String,
+    nonce: String,
+    ciphertext: String,
+}
+
+/// The keystore password, from `SOLANA_TOOL_KEYSTORE_PASSWORD` or asked on
+/// the terminal, twice when a new keystore is written.
+pub fn keystore_password(path: &Path, confirm: bool) -> Result<String> {
+    if let Ok(password) = std::env::var(PASSWORD_ENV) {
+        return Ok(password);
+    }
+    let prompt = format!("[{}] keystore password: ", path.display());
+    let password =
+        rpassword::prompt_password(prompt).map_err(|e| Error::keypair(path.display(), e))?;
+    if confirm {
+        let again = rpassword::prompt_password("Enter same password again: ")
+            .map_err(|e| Error::keypair(path.display(), e))?;
+        if again != password {
+            return Err(Error::keypair(path.display(), "the passwords do not match"));
+        }
+    }
+    Ok(password)
+}
+
+/// Decrypt the keypairs of a keystore file.
+pub fn read_keystore(path: &Path, password: &str) -> Result<Vec<Keypair>> {
+    let content = fs::read_to_string(path).map_err(|e| Error::io(path.display(), e))?;
+    let keystore: KeystoreFile = serde_json::from_str(&content)
+        .map_err(|e| Error::keypair(path.display(), format!("not a keystore file: {}", e)))?;
+    if keystore.version != 1 || keystore.kdf != KDF {
+        return Err(Error::keypair(
+            path.display(),
+            format!(
+                "unsupported keystore version {} with kdf {}",
+                keystore.version, keystore.kdf
+            ),
+        ));
+    }
+    let decode = |field: &str| {
+        bs58::decode(field)
+            .into_vec()
+            .map_err(|e| Error::keypair(path.display(), e))
+    };
+    let (salt, nonce, ciphertext) = (
+        decode(&keystore.salt)?,
+        decode(&keystore.nonce)?,
+        decode(&keystore.ciphertext)?,
+    );
+    let nonce = <[u8; 12]>::try_from(nonce.as_slice())
+        .map_err(|_| Error::keypair(path.display(), "the nonce is not 12 bytes"))?;
+    let cipher = cipher(password, &salt, keystore.iterations);
+    let secrets = cipher
+        .decrypt(&Nonce::from(nonce), ciphertext.as_ref())
+        .map_err(|_| Error::keypair(path.display(), "wrong password or a damaged keystore"))?;
+    let secrets: Vec<String> =
+        serde_json::from_slice(&secrets).map_err(|e| Error::keypair(path.display(), e))?;
+    secrets
+        .iter()
+        .map(|secret| keypair_from_bs58(secret))
+        .collect()
+}
+
+/// Encrypt the keypairs into a new keystore file.
+pub fn write_keystore<'a>(
+    path: &Path,
+    password: &str,
+    keypairs: impl IntoIterator<Item = &'a Keypair>,
+) -> Result<()> {
+    let secrets: Vec<String> = keypairs
+        .into_iter()
+        .map(Keypair::to_base58_string)
+        .collect();
+    let secrets = serde_json::to_vec(&secrets).map_err(|e| Error::keypair(path.display(), e))?;
+    let mut salt = [0u8; 16];
+    let mut nonce = [0u8; 12];
+    rand::thread_rng().fill_bytes(&mut salt);
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = cipher(password, &salt, ITERATIONS)
+        .encrypt(&Nonce::from(nonce), secrets.as_ref())
+        .map_err(|_| Error::keypair(path.display(), "failed to encrypt the keys"))?;
+    let keystore = KeystoreFile {
+        version: 1,
+        kdf: KDF.to_string(),
+        iterations: ITERATIONS,
+        salt: bs58::encode(salt).into_string(),
+        nonce: bs58::encode(nonce).into_string(),
+        ciphertext: bs58::encode(ciphertext).into_string(),
+    };
+    let content =
+        serde_json::to_string_pretty(&keystore).map_err(|e| Error::keypair(path.display(), e))?;
+    fs::write(path, content).map_err(|e| Error::io(path.display(), e))
+}
+
+fn cipher(password: &str, salt: &[u8], iterations: u32) -> Aes256GcmSiv {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(password.as_bytes(), salt, iterations, &mut key);
+    Aes256GcmSiv::new(&Key::from(key))
+}
@@ -0,0 +1,466 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::{env, fs};
+
+use anyhow::{Context, Result};
+use chrono::{NaiveDate, SecondsFormat, Utc};
+use futures::future::join_all;
+use log::{error, info, warn};
+use rusqlite::{params, params_from_iter, Connection};
+use serde::Serialize;
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind},
+    rpc_config::RpcTransactionConfig,
+};
+use solana_program::program_utils::limited_deserialize;
+use solana_sdk::{
+    packet::PACKET_DATA_SIZE, pubkey::Pubkey, signature::Signature,
+    system_instruction::SystemInstruction, system_program, transaction::Transaction,
+};
+use solana_transaction_status::UiTransactionEncoding;
+use spl_token::instruction::TokenInstruction;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, Duration};
+
+use crate::error::Error;
+use crate::outcome::Outcome;
+use crate::output::Record;
+use crate::rpc_pool::RpcPool;
+use crate::Tool;
+
+/// Tries to read a confirmed transaction back, the node may need a moment
+/// before `getTransaction` knows about it.
+const FETCH_TRANSACTION_RETRIES: usize = 5;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS transactions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TEXT NOT NULL,
+    command TEXT NOT NULL,
+    run_id TEXT NOT NULL,
+    signers TEXT NOT NULL,
+    recipients TEXT NOT NULL,
+    amounts TEXT NOT NULL,
+    mint TEXT,
+    signature TEXT NOT NULL,
+    slot INTEGER,
+    fee INTEGER,
+    status TEXT NOT NULL,
+    error TEXT
+);
+CREATE INDEX IF NOT EXISTS transactions_run_id ON transactions (run_id);
+CREATE INDEX IF NOT EXISTS transactions_created_at ON transactions (created_at);
+CREATE TRIGGER IF NOT EXISTS transactions_no_update BEFORE UPDATE ON transactions
+BEGIN SELECT RAISE(ABORT, 'the ledger is append-only'); END;
+CREATE TRIGGER IF NOT EXISTS transactions_no_delete BEFORE DELETE ON transactions
+BEGIN SELECT RAISE(ABORT, 'the ledger is append-only'); END;
+";
+
+/// One value moved by a transaction, decoded from its instructions.
+struct Movement {
+    recipient: Pubkey,
+    amount: Option<u64>,
+    mint: Option<Pubkey>,
+}
+
+/// A row of the ledger as `history` shows it.
+#[derive(Debug, Serialize)]
+pub struct LedgerRow {
+    pub created_at: String,
+    pub command: String,
+    pub run_id: String,
+    pub signers: String,
+    pub recipients: String,
+    pub amounts: String,
+    pub mint: Option<String>,
+    pub signature: String,
+    pub slot: Option<u64>,
+    pub fee: Option<u64>,
+    pub status: String,
+    pub error: Option<String>,
+}
+
+/// Filters of the `history` command, all of them optional.
+#[derive(Default)]
+pub struct LedgerQuery {
+    pub wallet: Option<String>,
+    pub mint: Option<String>,
+    pub run_id: Option<String>,
+    pub since: Option<NaiveDate>,
+    pub until: Option<NaiveDate>,
+    pub limit: u32,
+}
+
+/// Append-only sqlite record of every transaction the tool sent.
+pub struct Ledger {
+    conn: Mutex<Connection>,
+    command: String,
+    run_id: String,
+    /// Records still reading their transaction back.
+    pending: Mutex<Vec<JoinHandle<()>>>,
+}
+
+impl Ledger {
+    pub fn open(path: &str, command: &str) -> Result<Self> {
+        if let Some(parent) = Path::new(path).parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)
+                    .with_context(|| format!("Failed to create the ledger folder {:?}", parent))?;
+            }
+        }
+        let conn = Connection::open(path)
+            .with_context(|| format!("Failed to open the ledger {}", path))?;
+        conn.execute_batch(SCHEMA)
+            .context("Failed to create the ledger tables")?;
+        let run_id = format!(
+            "{}-{}",
+            Utc::now().format("%Y%m%d%H%M%S"),
+            std::process::id()
+        );
+        Ok(Self {
+            conn: Mutex::new(conn),
+            command: command.to_string(),
+            run_id,
+            pending: Mutex::new(vec![]),
+        })
+    }
+    /// `~/.solana_tool/ledger.sqlite3`, or the working folder without a home.
+    pub fn default_path() -> String {
+        match env::var("HOME") {
+            Ok(home) => format!("{}/.solana_tool/ledger.sqlite3", home),
+            Err(_) => "ledger.sqlite3".to_string(),
+        }
+    }
+    pub fn run_id(&self) -> &str {
+        &self.run_id
+    }
+    /// Write down the outcome of one sent transaction. Landed transactions
+    /// are read back first for their slot and fee, in a task of its own so
+    /// the send never waits on the bookkeeping, `flush` waits for them. A
+    /// ledger failure is only logged, it never fails the transfer itself.
+    pub fn record(
+        self: &Arc<Self>,
+        rpc_pool: &Arc<RpcPool>,
+        transaction: &Transaction,
+        result: &Result<Signature, ClientError>,
+    ) {
+        let (status, error) = match result {
+            Ok(_) => ("confirmed", None),
+            // a transaction error came back from the chain, the fee is paid
+            Err(e) if matches!(e.kind(), ClientErrorKind::TransactionError(_)) => {
+                ("failed", Some(e.to_string()))
+            }
+            Err(e) => ("dropped", Some(e.to_string())),
+        };
+        let ledger = self.clone();
+        let rpc_pool = rpc_pool.clone();
+        let transaction = transaction.clone();
+        let task =
+            tokio::spawn(async move { ledger.write(&rpc_pool, &transaction, status, error).await });
+        self.pending.lock().unwrap().push(task);
+    }
+    /// Wait until every recorded transaction is written.
+    pub async fn flush(&self) {
+        let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+        for task in join_all(pending).await {
+            if let Err(e) = task {
+                error!(
+                    "Failed to write a transaction to the ledger with error: {}",
+                    e
+                );
+            }
+        }
+    }
+    async fn write(
+        &self,
+        rpc_pool: &RpcPool,
+        transaction: &Transaction,
+        status: &str,
+        error: Option<String>,
+    ) {
+        let signature = transaction.signatures[0];
+        let mut movements = decode_movements(transaction);
+        let mut slot = None;
+        let mut fee = None;
+        if status != "dropped" {
+            match fetch_landed(rpc_pool, &signature).await {
+                Ok((landed_slot, landed_fee, token_accounts)) => {
+                    slot = Some(landed_slot);
+                    fee = Some(landed_fee);
+                    // show the wallet behind a token account, and fill in the
+                    // mint of a plain `transfer`
+                    for movement in movements.iter_mut() {
+                        if let Some((owner, mint)) = token_accounts.get(&movement.recipient) {
+                            movement.recipient = *owner;
+                            movement.mint = movement.mint.or(Some(*mint));
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to read back the transaction {} for the ledger with error: {}",
+                        signature, e
+                    )
+                }
+            }
+        }
+        let signers = transaction.message.account_keys
+            [..transaction.message.header.num_required_signatures as usize]
+            .iter()
+            .map(|pubkey| pubkey.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let recipients = movements
+            .iter()
+            .map(|movement| movement.recipient.to_string())
+            .collect::<Vec<_>>()
+            .join(",");
+        let amounts = movements
+            .iter()
+            .map(|movement| {
+                movement
+                    .amount
+                    .map(|amount| amount.to_string())
+                    .unwrap_or_default()
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+        let mint = movements
+            .iter()
+            .find_map(|movement| movement.mint)
+            .map(|mint| mint.to_string());
+        let conn = self.conn.lock().unwrap();
+        if let Err(e) = conn.execute(
+            "INSERT INTO transactions (created_at, command, run_id, signers, recipients, amounts, mint, signature, slot, fee, status, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+            params![
+                Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true),
+                self.command,
+                self.run_id,
+                signers,
+                recipients,
+                amounts,
+                mint,
+                signature.to_string(),
+                slot,
+                fee,
+                status,
+                error,
+            ],
+        ) {
+            error!(
+                "Failed to write the transaction {} to the ledger with error: {}",
+                signature, e
+            )
+        }
+    }
+    pub fn query(&self, query: &LedgerQuery) -> Result<Vec<LedgerRow>> {
+        let mut sql = "SELECT created_at, command, run_id, signers, recipients, amounts, mint, signature, slot, fee, status, error FROM transactions WHERE 1 = 1".to_string();
+        let mut values: Vec<String> = vec![];
+        if let Some(ref wallet) = query.wallet {
+            sql.push_str(" AND (instr(signers, ?) > 0 OR instr(recipients, ?) > 0)");
+            values.push(wallet.clone());
+            values.push(wallet.clone());
+        }
+        if let Some(ref mint) = query.mint {
+            sql.push_str(" AND mint = ?");
+            values.push(mint.clone());
+        }
+        if let Some(ref run_id) = query.run_id {
+            sql.push_str(" AND run_id = ?");
+            values.push(run_id.clone());
+        }
+        if let Some(since) = query.since {
+            sql.push_str(" AND created_at >= ?");
+            values.push(format!("{}T00:00:00Z", since));
+        }
+        if let Some(until) = query.until {
+            // the whole `until` day is included
+            sql.push_str(" AND created_at < ?");
+            values.push(format!("{}T00:00:00Z", until + chrono::Days::new(1)));
+        }
+        sql.push_str(&format!(" ORDER BY id DESC LIMIT {}", query.limit));
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(&sql).context("Failed to query the ledger")?;
+        let rows = statement
+            .query_map(params_from_iter(values.iter()), |row| {
+                Ok(LedgerRow {
+                    created_at: row.get(0)?,
+                    command: row.get(1)?,
+                    run_id: row.get(2)?,
+                    signers: row.get(3)?,
+                    recipients: row.get(4)?,
+                    amounts: row.get(5)?,
+                    mint: row.get(6)?,
+                    signature: row.get(7)?,
+                    slot: row.get(8)?,
+                    fee: row.get(9)?,
+                    status: row.get(10)?,
+                    error: row.get(11)?,
+                })
+            })
+            .context("Failed to query the ledger")?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .context("Failed to read the ledger rows")?;
+        Ok(rows)
+    }
+}
+
+impl Tool {
+    pub async fn history(&self, query: LedgerQuery) -> Result<Outcome, Error> {
+        let ledger = self
+            .ledger
+            .as_ref()
+            .context("the ledger is not available")
+            .map_err(Error::Ledger)?;
+        let rows = ledger
+            .query(&query)
+            .context("Failed to read the ledger")
+            .map_err(Error::Ledger)?;
+        let count = rows.len();
+        for row in rows.into_iter().rev() {
+            info!(
+                "{} {} run {} [{}] signers: {} recipients: {} amounts: {} mint: {} slot: {} fee: {} signature: {}{}",
+                row.created_at,
+                row.command,
+                row.run_id,
+                row.status,
+                row.signers,
+                row.recipients,
+                row.amounts,
+                row.mint.as_deref().unwrap_or("sol"),
+                row.slot.map(|slot| slot.to_string()).unwrap_or_default(),
+                row.fee.map(|fee| fee.to_string()).unwrap_or_default(),
+                row.signature,
+                row.error
+                    .as_ref()
+                    .map(|e| format!(" error: {}", e))
+                    .unwrap_or_default(),
+            );
+            self.output.emit(Record::Transaction(row));
+        }
+        info!("Found {} transactions", count);
+        Ok(Outcome {
+            succeeded: count,
+            ..Outcome::default()
+        })
+    }
+}
+
+/// Slot, fee and the owner and mint of every token account of a landed
+/// transaction.
+async fn fetch_landed(
+    rpc_pool: &RpcPool,
+    signature: &Signature,
+) -> Result<(u64, u64, HashMap<Pubkey, (Pubkey, Pubkey)>)> {
+    let signature = *signature;
+    let config = RpcTransactionConfig {
+        encoding: Some(UiTransactionEncoding::Base64),
+        commitment: Some(rpc_pool.commitment()),
+        max_supported_transaction_version: Some(0),
+    };
+    let mut retries = 0;
+    let landed = loop {
+        let landed =
+            rpc_pool
+                .call(|client| async move {
+                    client.get_transaction_with_config(&signature, config).await
+                })
+                .await;
+        match landed {
+            Ok(landed) => break landed,
+            Err(e) => {
+                retries += 1;
+                if retries >= FETCH_TRANSACTION_RETRIES {
+                    return Err(e).context("Failed to get the transaction");
+                }
+                sleep(Duration::from_secs(1)).await;
+            }
+        }
+    };
+    let meta = landed
+        .transaction
+        .meta
+        .context("the transaction has no status meta")?;
+    let account_keys = landed
+        .transaction
+        .transaction
+        .decode()
+        .map(|transaction| transaction.message.static_account_keys().to_vec())
+        .unwrap_or_default();
+    let mut token_accounts = HashMap::new();
+    let post_token_balances: Option<Vec<_>> = meta.post_token_balances.into();
+    for balance in post_token_balances.unwrap_or_default() {
+        let owner: Option<String> = balance.owner.into();
+        let account = account_keys.get(balance.account_index as usize);
+        if let (Some(account), Some(Ok(owner)), Ok(mint)) = (
+            account,
+            owner.map(|owner| owner.parse::<Pubkey>()),
+            balance.mint.parse::<Pubkey>(),
+        ) {
+            token_accounts.insert(*account, (owner, mint));
+        }
+    }
+    Ok((landed.slot, meta.fee, token_accounts))
+}
+
+/// The transfers, closes and account creations in the transaction.
+fn decode_movements(transaction: &Transaction) -> Vec<Movement> {
+    let message = &transaction.message;
+    let mut movements = vec![];
+    for instruction in &message.instructions {
+        let program_id = message.account_keys[instruction.program_id_index as usize];
+        let account = |index: usize| {
+            instruction
+                .accounts
+                .get(index)
+                .map(|key_index| message.account_keys[*key_index as usize])
+        };
+        if program_id == system_program::id() {
+            if let Ok(SystemInstruction::Transfer { lamports }) =
+                limited_deserialize(&instruction.data, PACKET_DATA_SIZE as u64)
+            {
+                if let Some(recipient) = account(1) {
+                    movements.push(Movement {
+                        recipient,
+                        amount: Some(lamports),
+                        mint: None,
+                    });
+                }
+            }
+        } else if program_id == spl_token::id() {
+            let movement = match TokenInstruction::unpack(&instruction.data) {
+                Ok(TokenInstruction::Transfer { amount }) => account(1).map(|recipient| Movement {
+                    recipient,
+                    amount: Some(amount),
+                    mint: None,
+                }),
+                Ok(TokenInstruction::TransferChecked { amount, .. }) => {
+                    account(2).map(|recipient| Movement {
+                        recipient,
+                        amount: Some(amount),
+                        mint: account(1),
+                    })
+                }
+                Ok(TokenInstruction::CloseAccount) => account(1).map(|recipient| Movement {
+                    recipient,
+                    amount: None,
+                    mint: None,
+                }),
+                _ => None,
+            };
+            movements.extend(movement);
+        } else if program_id == spl_associated_token_account::id() {
+            // create the associated account of wallet #2 for mint #3
+            if let Some(recipient) = account(2) {
+                movements.push(Movement {
+                    recipient,
+                    amount: None,
+                    mint: account(3),
+                });
+            }
+        }
+    }
+    movements
+}
@@ -0,0 +1,110 @@
+//! Batch sol and spl token operations over a set of sub wallets.
+//!
+//! ```no_run
+//! # async fn example(tool: solana_tool::Tool) -> solana_tool::Result<()> {
+//! use solana_tool::{parse_key_source, BatchTransfer};
+//!
+//! let funder = parse_key_source("main.json")?.signer()?;
+//! let wallets = parse_key_source("mnemonic://seed.txt?range=0..10")?.load()?;
+//! let batch = BatchTransfer::new(funder, 1_000_000);
+//! let plan = batch.plan(&tool, &wallets).await?;
+//! let report = batch.run(&tool, &plan).await?;
+//! println!("sent {} lamports", report.total_sent());
+//! # Ok(())
+//! # }
+//! ```
+mod close;
+mod collect;
+pub mod config;
+mod convert;
+mod delegate;
+mod distribute;
+pub mod error;
+pub mod key_source;
+pub mod keystore;
+pub mod ledger;
+pub mod outcome;
+pub mod output;
+pub mod plan;
+pub mod policy;
+pub mod rate_limiter;
+mod rebalance;
+pub mod report;
+pub mod rpc_pool;
+mod send_and_check;
+pub mod signer;
+mod stake;
+mod token;
+mod token_account;
+pub mod utils;
+mod wallet;
+pub mod wallet_set;
+mod watch;
+mod wsol;
+
+use std::sync::Arc;
+
+use config::PriorityFee;
+use ledger::Ledger;
+use output::Output;
+use policy::SpendingPolicy;
+use rpc_pool::RpcPool;
+
+pub use close::CloseAccounts;
+pub use collect::{Sweep, SweepPlan, TokenSweep, TokenSweepPlan};
+pub use delegate::{Approve, ApprovePlan, Delegation, Revoke};
+pub use distribute::BatchTransfer;
+pub use error::{Error, Result};
+pub use key_source::{parse_key_source, KeySource};
+pub use plan::{Cluster, FunderShare, RunSummary};
+pub use rebalance::Rebalance;
+pub use report::{
+    ApprovalEntry, ApprovalReport, CloseEntry, CloseReport, FreezeEntry, FreezeReport, StakeEntry,
+    StakeReport, Status, Transfer, TransferEntry, TransferReport,
+};
+pub use stake::{
+    Activation, CreateStake, CreateStakePlan, ManageStake, StakeAccount, StakeAction, StakeEpoch,
+};
+pub use token::{
+    read_recipients, Burn, BurnPlan, CreateMint, Freeze, MintInfo, MintTo, Recipients,
+};
+pub use token_account::TokenHolding;
+pub use wallet_set::{Wallet, WalletSet};
+pub use wsol::{is_native, UnwrapSol, WrapSol};
+
+/// Everything a command needs to talk to the cluster and report what it did.
+#[derive(Clone)]
+pub struct Tool {
+    pub rpc_pool: Arc<RpcPool>,
+    pub ledger: Option<Arc<Ledger>>,
+    /// Transaction link template, `{signature}` is replaced.
+    pub explorer: String,
+    pub priority_fee: PriorityFee,
+    pub output: Arc<Output>,
+    /// Checked before a batch sends anything, none without a policy file.
+    pub policy: Option<Arc<SpendingPolicy>>,
+}
+
+impl Tool {
+    pub fn new(
+        rpc_pool: Arc<RpcPool>,
+        ledger: Option<Arc<Ledger>>,
+        explorer: String,
+        priority_fee: PriorityFee,
+        output: Arc<Output>,
+    ) -> Self {
+        Self {
+            rpc_pool,
+            ledger,
+            explorer,
+            priority_fee,
+            output,
+            policy: None,
+        }
+    }
+    /// Enforce this spending policy on every batch transfer and sweep.
+    pub fn with_policy(mut self, policy: SpendingPolicy) -> Self {
+        self.policy = Some(Arc::new(policy));
+        self
+    }
+}
@@ -1,32 +1,144 @@
-mod close;
-mod collect;
-mod convert;
-mod distribute;
-mod send_and_check;
-mod utils;
-mod wallet;
-use clap::{command, Parser, Subcommand};
-use solana_client::nonblocking::rpc_client::RpcClient;
-use solana_sdk::commitment_config::CommitmentConfig;
+use anyhow::{anyhow, Context, Result};
+use chrono::NaiveDate;
+use clap::{Parser, Subcommand};
+use log::{error, info, warn};
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Keypair;
+use solana_tool::config::{load_profile, PriorityFee, Profile};
+use solana_tool::error::{parse_pubkey, Error};
+use solana_tool::key_source::Keystore;
+use solana_tool::ledger::{Ledger, LedgerQuery};
+use solana_tool::outcome::{Outcome, Step};
+use solana_tool::output::{Output, OutputFormat};
+use solana_tool::policy::SpendingPolicy;
+use solana_tool::rpc_pool::{connect_pubsub, websocket_url, EndpointSpec, RpcPool};
+use solana_tool::signer::{default_socket_path, SigningPolicy};
+use solana_tool::utils::setup_logger;
+use solana_tool::wallet_set::SharedSigner;
+use solana_tool::{
+    is_native, parse_key_source, read_recipients, Approve, BatchTransfer, Burn, CloseAccounts,
+    CreateMint, CreateStake, Freeze, KeySource, ManageStake, MintTo, Rebalance, Revoke, RunSummary,
+    StakeAction, Sweep, TokenSweep, Tool, UnwrapSol, WalletSet, WrapSol,
+};
+use spl_token::instruction::AuthorityType;
+use std::io;
+use std::path::PathBuf;
 use std::sync::Arc;
-use utils::setup_logger;
+use tokio::time::Duration;
 
-// #[derive(Debug)]
-struct Tool {
-    pub rpc_client: Arc<RpcClient>,
-}
+const DEFAULT_RPC: &str = "https://api.mainnet-beta.solana.com";
 
 #[derive(Parser, Debug)]
 #[command(about, version)]
 struct Args {
+    #[arg(
+        long,
+        value_name = "config_file",
+        help = "the toml file with the named profiles, default is ~/.solana_tool/config.toml",
+        global = true
+    )]
+    config: Option<String>,
+    #[arg(
+        long,
+        value_name = "profile",
+        help = "the profile to run with, mainnet, devnet, localnet or one from the config file, default is the config's default_profile",
+        global = true
+    )]
+    profile: Option<String>,
     #[arg(
         long,
         value_name = "RPC_URL",
-        help = "Network address of your RPC provider, default value will be the mainnet rpc server",
-        default_value = "https://api.mainnet-beta.solana.com",
+        value_delimiter = ',',
+        help = "Network address of your RPC provider, repeat it or split by comma for several endpoints, add #weight to spread more reads to one, default value will be the profile's rpc, then the solana cli config, then the mainnet rpc server",
+        global = true
+    )]
+    rpc: Vec<String>,
+    #[arg(
+        long,
+        value_name = "rpc_file",
+        help = "a file with one RPC_URL or RPC_URL#weight per line, used together with --rpc",
+        global = true
+    )]
+    rpc_file: Option<String>,
+    #[arg(
+        long,
+        value_name = "WS_URL",
+        help = "websocket address used to confirm transactions and watch balances, default is worked out from the first rpc url, polling is used when it can not be reached",
+        global = true
+    )]
+    ws: Option<String>,
+    #[arg(
+        long,
+        value_name = "ledger_file",
+        help = "the sqlite file every sent transaction is recorded in, default is ~/.solana_tool/ledger.sqlite3",
+        global = true
+    )]
+    ledger: Option<String>,
+    #[arg(
+        long,
+        value_name = "policy_file",
+        help = "a toml spending policy, a run breaking its limits is stopped before the first transaction",
+        global = true
+    )]
+    spending_policy: Option<String>,
+    #[arg(
+        long,
+        value_name = "slots",
+        default_value = "50",
+        help = "an endpoint more slots than this behind the best one is taken out of rotation",
+        global = true
+    )]
+    max_slot_lag: u64,
+    #[arg(
+        long,
+        value_name = "seconds",
+        default_value = "10",
+        help = "how often the rpc endpoints are health checked when there are several of them",
+        global = true
+    )]
+    health_check_interval: u64,
+    #[arg(
+        long,
+        value_name = "rps",
+        help = "the most rpc requests per second to each endpoint, it is lowered automatically while that endpoint is rate limiting, default is 20",
+        global = true
+    )]
+    rps: Option<u32>,
+    #[arg(
+        long,
+        value_name = "concurrency",
+        help = "the most rpc requests in flight at the same time to each endpoint, default is 20",
+        global = true
+    )]
+    concurrency: Option<usize>,
+    #[arg(
+        long,
+        value_name = "commitment",
+        help = "processed, confirmed or finalized, default is confirmed",
+        global = true
+    )]
+    commitment: Option<String>,
+    #[arg(
+        long,
+        value_name = "priority_fee",
+        help = "none, auto or a compute unit price in micro-lamports added to every transaction, default is none",
+        global = true
+    )]
+    priority_fee: Option<PriorityFee>,
+    #[arg(
+        long,
+        help = "send without asking, the run summary is still shown, needed to send on mainnet without a terminal",
+        global = true
+    )]
+    yes: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        help = "text only logs, json and jsonl print typed records and a summary to stdout and move the logs to stderr",
         global = true
     )]
-    rpc: Option<String>,
+    output: OutputFormat,
     #[command(subcommand)]
     commands: Commands,
 }
@@ -40,10 +152,22 @@ enum Commands {
 
     #[command(about = "collect all the sol or some token to main wallet")]
     Collect(CollectArgs),
+    #[command(about = "even out the sol or some token between the sub wallets")]
+    Rebalance(RebalanceArgs),
     #[command(about = "convert the wallet format")]
     Convert(ConvertArgs),
     #[command(about = "close the sub wallet's spl-token account")]
     Close(CloseSPLArgs),
+    #[command(about = "wrap or unwrap the sol of the sub wallets")]
+    Wsol(WsolArgs),
+    #[command(about = "stake the sol of the sub wallets and manage their stake accounts")]
+    Stake(StakeArgs),
+    #[command(about = "create and manage your own spl token")]
+    Token(TokenArgs),
+    #[command(about = "show the transactions recorded in the ledger")]
+    History(HistoryArgs),
+    #[command(about = "keep the keys in a local signing daemon")]
+    Signer(SignerArgs),
 }
 #[derive(Subcommand, Debug)]
 enum ConvertCommands {
@@ -51,6 +175,8 @@ enum ConvertCommands {
     Bs58(ConvertBs58Args),
     #[command(about = "bs58 to json string")]
     Json(ConvertJsonArgs),
+    #[command(about = "encrypt wallets into a keystore file")]
+    Keystore(ConvertKeystoreArgs),
 }
 #[derive(Parser, Debug)]
 struct ConvertArgs {
@@ -62,11 +188,26 @@ struct ConvertBs58Args {
     #[arg(
         long,
         value_name = "json_file",
-        help = "your keypair json file which want to convert to bs58"
+        help = "your keypair json file which want to convert to bs58, or any key source"
     )]
     pub json_file: String,
 }
 #[derive(Parser, Debug)]
+struct ConvertKeystoreArgs {
+    #[arg(
+        long,
+        value_name = "key_source",
+        help = "the wallets to encrypt, a keypair file, a folder or any key source"
+    )]
+    pub from: String,
+    #[arg(
+        long,
+        value_name = "keystore_file",
+        help = "the keystore file to write, use it later as keystore://<keystore_file>"
+    )]
+    pub keystore: String,
+}
+#[derive(Parser, Debug)]
 struct ConvertJsonArgs {
     #[arg(
         long,
@@ -74,8 +215,13 @@ struct ConvertJsonArgs {
         help = "your bs58 key which you want to convert"
     )]
     pub bs58: String,
-    #[arg(long, value_name = "output", help = "output keypair json file")]
-    pub output: String,
+    #[arg(
+        long,
+        short = 'o',
+        value_name = "output",
+        help = "output keypair json file"
+    )]
+    pub output_file: String,
 }
 #[derive(Subcommand, Debug)]
 enum WalletCommands {
@@ -83,6 +229,10 @@ enum WalletCommands {
     Crate(WalletCrateArgs),
     #[command(about = "check your sub wallet's balance")]
     Balance(WalletBalanceArgs),
+    #[command(about = "watch the live balance changes of your sub wallets")]
+    Watch(WalletWatchArgs),
+    #[command(about = "show the sol, tokens and stake accounts of your sub wallets")]
+    Portfolio(WalletPortfolioArgs),
 }
 #[derive(Parser, Debug)]
 struct WalletArgs {
@@ -103,16 +253,16 @@ struct WalletCrateArgs {
         value_name = "output_folder",
         help = "all the keypairs will be stored in that folder"
     )]
-    pub output: String,
+    pub output_folder: String,
 }
 #[derive(Parser, Debug)]
 struct WalletBalanceArgs {
     #[arg(
         long,
         value_name = "sub_keypair_folder",
-        help = "your sub keypair folder location"
+        help = "your sub keypair folder location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
     )]
-    pub sub_keypair_folder: String,
+    pub sub_keypair_folder: Option<String>,
     #[arg(
         long,
         value_name = "token_address",
@@ -120,27 +270,60 @@ struct WalletBalanceArgs {
     )]
     pub token_address: Option<String>,
 }
+#[derive(Parser, Debug)]
+struct WalletWatchArgs {
+    #[arg(
+        long,
+        value_name = "sub_keypair_folder",
+        help = "your sub keypair folder location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
+    )]
+    pub sub_keypair_folder: Option<String>,
+    #[arg(
+        long,
+        value_name = "token_address",
+        help = "this is the spl token address which your want to watch as well, default is only solana"
+    )]
+    pub token_address: Option<String>,
+    #[arg(
+        long,
+        value_name = "seconds",
+        default_value = "5",
+        help = "how often the balances are polled when the websocket is not available"
+    )]
+    pub interval: u64,
+}
 
 #[derive(Parser, Debug)]
 struct DistributeArgs {
     #[arg(
         long,
         value_name = "sub_keypair_folder",
-        help = "your sub keypair folder location"
+        help = "your sub keypair folder location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
     )]
-    pub sub_keypair_folder: String,
+    pub sub_keypair_folder: Option<String>,
     #[arg(
         long,
         value_name = "main_keypair_file",
-        help = "your main wallet keypair file's location"
+        help = "your main wallet keypair file's location, default is the profile's main_keypair_file, keystore://, mnemonic://, prompt:// or stdin:// work too"
     )]
-    pub main_keypair_file: String,
+    pub main_keypair_file: Option<String>,
     #[arg(
         long,
         value_name = "lamports",
         help = "how much token amount you want to transfer to each sub wallets"
     )]
     pub lamports: u64,
+    #[arg(
+        long,
+        value_name = "funders",
+        help = "a folder, or any other key source, of several funding wallets used instead of the main wallet, each recipient is paid by one of them weighted by their balance"
+    )]
+    pub funders: Option<String>,
+    #[arg(
+        long,
+        help = "send the rent-exempt minimum to the recipients which do not exist yet when --lamports is less, without it such a run is refused"
+    )]
+    pub bump_to_rent: bool,
     #[arg(
         long,
         value_name = "token_address",
@@ -150,7 +333,6 @@ struct DistributeArgs {
     #[arg(
         long,
         value_name = "decimals",
-        default_value = "9",
         help = "this is the spl token's decimals, defalut is 9"
     )]
     pub decimals: Option<u8>,
@@ -160,15 +342,15 @@ struct CollectArgs {
     #[arg(
         long,
         value_name = "sub_keypair_folder",
-        help = "your sub keypair folder's location"
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
     )]
-    pub sub_keypair_folder: String,
+    pub sub_keypair_folder: Option<String>,
     #[arg(
         long,
         value_name = "main_keypair_file",
-        help = "your main wallet keypair file's location"
+        help = "your main wallet keypair file's location, default is the profile's main_keypair_file, keystore://, mnemonic://, prompt:// or stdin:// work too"
     )]
-    pub main_keypair_file: String,
+    pub main_keypair_file: Option<String>,
     #[arg(
         long,
         value_name = "token_address",
@@ -178,7 +360,58 @@ struct CollectArgs {
     #[arg(
         long,
         value_name = "decimals",
-        default_value = "9",
+        help = "this is the spl token's decimals, defalut is 9"
+    )]
+    pub decimals: Option<u8>,
+    #[arg(
+        long,
+        help = "every sub wallet pays the fee of its own sol transfer instead of the main wallet, the wallets still end at zero"
+    )]
+    pub sub_pays_fee: bool,
+    #[arg(
+        long,
+        conflicts_with = "token_address",
+        help = "sweep every token the sub wallets hold instead of one, the destination's token accounts are created where missing"
+    )]
+    pub all_tokens: bool,
+    #[arg(
+        long,
+        value_name = "mint",
+        requires = "all_tokens",
+        help = "a token left out of --all-tokens, repeat it for several"
+    )]
+    pub exclude_mint: Vec<String>,
+    #[arg(
+        long,
+        value_name = "ui_amount",
+        requires = "all_tokens",
+        help = "with --all-tokens only sweep the balances of at least this ui amount"
+    )]
+    pub min_ui_value: Option<f64>,
+}
+#[derive(Parser, Debug)]
+struct RebalanceArgs {
+    #[arg(
+        long,
+        value_name = "sub_keypair_folder",
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
+    )]
+    pub sub_keypair_folder: Option<String>,
+    #[arg(
+        long,
+        value_name = "target",
+        help = "the lamports or raw token amount every wallet should end with, default is an equal share of what they hold together"
+    )]
+    pub target: Option<u64>,
+    #[arg(
+        long,
+        value_name = "token_address",
+        help = "this is the token address your want to rebalance, default is solana"
+    )]
+    pub token_address: Option<String>,
+    #[arg(
+        long,
+        value_name = "decimals",
         help = "this is the spl token's decimals, defalut is 9"
     )]
     pub decimals: Option<u8>,
@@ -188,15 +421,15 @@ struct CloseSPLArgs {
     #[arg(
         long,
         value_name = "sub_keypair_folder",
-        help = "your sub keypair folder's location"
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
     )]
-    pub sub_keypair_folder: String,
+    pub sub_keypair_folder: Option<String>,
     #[arg(
         long,
         value_name = "main_keypair_file",
-        help = "your main wallet keypair file's location"
+        help = "your main wallet keypair file's location, default is the profile's main_keypair_file, keystore://, mnemonic://, prompt:// or stdin:// work too"
     )]
-    pub main_keypair_file: String,
+    pub main_keypair_file: Option<String>,
     #[arg(
         long,
         value_name = "token_address",
@@ -205,65 +438,1125 @@ struct CloseSPLArgs {
     )]
     pub token_address: Option<String>,
 }
+#[derive(Subcommand, Debug)]
+enum WsolCommands {
+    #[command(about = "wrap some sol of every sub wallet into its wsol account")]
+    Wrap(WrapArgs),
+    #[command(about = "close every wsol account of the sub wallets, the sol goes back to them")]
+    Unwrap(UnwrapArgs),
+}
+#[derive(Parser, Debug)]
+struct WsolArgs {
+    #[command(subcommand)]
+    commands: WsolCommands,
+}
+#[derive(Parser, Debug)]
+struct WrapArgs {
+    #[arg(
+        long,
+        value_name = "sub_keypair_folder",
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
+    )]
+    pub sub_keypair_folder: Option<String>,
+    #[arg(
+        long,
+        value_name = "lamports",
+        help = "how many lamports each sub wallet wraps, it pays the fee and the account rent too"
+    )]
+    pub amount: u64,
+}
+#[derive(Parser, Debug)]
+struct UnwrapArgs {
+    #[arg(
+        long,
+        value_name = "sub_keypair_folder",
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
+    )]
+    pub sub_keypair_folder: Option<String>,
+}
+#[derive(Subcommand, Debug)]
+enum TokenCommands {
+    #[command(
+        about = "create a new mint, the main wallet pays and is the mint authority by default"
+    )]
+    CreateMint(CreateMintArgs),
+    #[command(about = "mint the token to the sub wallets or the addresses of a csv file")]
+    MintTo(MintToArgs),
+    #[command(about = "burn the token of the sub wallets")]
+    Burn(BurnArgs),
+    #[command(about = "hand the mint or freeze authority to another wallet, or give it up")]
+    SetAuthority(SetAuthorityArgs),
+    #[command(about = "show the supply, decimals and authorities of a mint")]
+    Info(TokenInfoArgs),
+    #[command(about = "list the token accounts of the sub wallets which have a delegate")]
+    Delegations(DelegationsArgs),
+    #[command(about = "revoke the delegates of the sub wallets, all of them or the selected ones")]
+    Revoke(RevokeArgs),
+    #[command(about = "let a delegate move up to an amount of the token of every sub wallet")]
+    Approve(ApproveArgs),
+    #[command(about = "freeze the token accounts of the sub wallets, needs the freeze authority")]
+    Freeze(FreezeArgs),
+    #[command(about = "thaw the frozen token accounts of the sub wallets")]
+    Thaw(FreezeArgs),
+}
+#[derive(Parser, Debug)]
+struct TokenArgs {
+    #[command(subcommand)]
+    commands: TokenCommands,
+}
+#[derive(Parser, Debug)]
+struct CreateMintArgs {
+    #[arg(
+        long,
+        value_name = "main_keypair_file",
+        help = "your main wallet keypair file's location, default is the profile's main_keypair_file, keystore://, mnemonic://, prompt:// or stdin:// work too"
+    )]
+    pub main_keypair_file: Option<String>,
+    #[arg(long, value_name = "decimals", help = "the decimals of the new token")]
+    pub decimals: u8,
+    #[arg(
+        long,
+        value_name = "mint_keypair",
+        help = "the keypair of the mint address, e.g. a vanity one, default is a new random one"
+    )]
+    pub mint_keypair: Option<String>,
+    #[arg(
+        long,
+        value_name = "pubkey",
+        help = "who may mint the token, default is the main wallet"
+    )]
+    pub mint_authority: Option<String>,
+    #[arg(
+        long,
+        value_name = "pubkey",
+        help = "who may freeze the token accounts, default is nobody"
+    )]
+    pub freeze_authority: Option<String>,
+}
+#[derive(Parser, Debug)]
+struct MintToArgs {
+    #[arg(
+        long,
+        value_name = "main_keypair_file",
+        help = "the mint authority, it pays the fees and the rent, default is the profile's main_keypair_file"
+    )]
+    pub main_keypair_file: Option<String>,
+    #[arg(long, value_name = "token_address", help = "the mint")]
+    pub token_address: String,
+    #[arg(
+        long,
+        value_name = "sub_keypair_folder",
+        conflicts_with = "csv",
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
+    )]
+    pub sub_keypair_folder: Option<String>,
+    #[arg(
+        long,
+        value_name = "csv_file",
+        help = "mint to the address,amount lines of this file instead of the sub wallets"
+    )]
+    pub csv: Option<String>,
+    #[arg(
+        long,
+        value_name = "amount",
+        help = "the raw amount each wallet gets, the csv lines without an amount get it too"
+    )]
+    pub amount: Option<u64>,
+}
+#[derive(Parser, Debug)]
+struct BurnArgs {
+    #[arg(
+        long,
+        value_name = "main_keypair_file",
+        help = "the wallet paying the fees, default is the profile's main_keypair_file"
+    )]
+    pub main_keypair_file: Option<String>,
+    #[arg(
+        long,
+        value_name = "sub_keypair_folder",
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
+    )]
+    pub sub_keypair_folder: Option<String>,
+    #[arg(long, value_name = "token_address", help = "the mint")]
+    pub token_address: String,
+    #[arg(
+        long,
+        value_name = "amount",
+        help = "the raw amount each wallet burns, default is all it holds"
+    )]
+    pub amount: Option<u64>,
+}
+#[derive(Clone, Copy, Debug, clap::ValueEnum)]
+enum MintAuthority {
+    Mint,
+    Freeze,
+}
+#[derive(Parser, Debug)]
+struct SetAuthorityArgs {
+    #[arg(
+        long,
+        value_name = "main_keypair_file",
+        help = "the current authority, it pays the fee, default is the profile's main_keypair_file"
+    )]
+    pub main_keypair_file: Option<String>,
+    #[arg(long, value_name = "token_address", help = "the mint")]
+    pub token_address: String,
+    #[arg(long, value_enum, help = "which authority of the mint to change")]
+    pub authority: MintAuthority,
+    #[arg(
+        long,
+        value_name = "pubkey",
+        required_unless_present = "disable",
+        help = "the new authority"
+    )]
+    pub new_authority: Option<String>,
+    #[arg(
+        long,
+        conflicts_with = "new_authority",
+        help = "give the authority up for good, no one can mint or freeze afterwards"
+    )]
+    pub disable: bool,
+}
+#[derive(Parser, Debug)]
+struct TokenInfoArgs {
+    #[arg(long, value_name = "token_address", help = "the mint")]
+    pub token_address: String,
+}
+#[derive(Parser, Debug)]
+struct DelegationsArgs {
+    #[arg(
+        long,
+        value_name = "sub_keypair_folder",
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
+    )]
+    pub sub_keypair_folder: Option<String>,
+}
+#[derive(Parser, Debug)]
+struct RevokeArgs {
+    #[arg(
+        long,
+        value_name = "main_keypair_file",
+        help = "the wallet paying the fees, default is the profile's main_keypair_file"
+    )]
+    pub main_keypair_file: Option<String>,
+    #[arg(
+        long,
+        value_name = "sub_keypair_folder",
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
+    )]
+    pub sub_keypair_folder: Option<String>,
+    #[arg(long, value_name = "pubkey", help = "only revoke this delegate")]
+    pub delegate: Option<String>,
+    #[arg(
+        long,
+        value_name = "token_address",
+        help = "only revoke the token accounts of this mint"
+    )]
+    pub token_address: Option<String>,
+    #[arg(
+        long,
+        value_name = "token_account",
+        help = "only revoke this token account, repeat it for several"
+    )]
+    pub account: Vec<String>,
+}
+#[derive(Parser, Debug)]
+struct ApproveArgs {
+    #[arg(
+        long,
+        value_name = "main_keypair_file",
+        help = "the wallet paying the fees, default is the profile's main_keypair_file"
+    )]
+    pub main_keypair_file: Option<String>,
+    #[arg(
+        long,
+        value_name = "sub_keypair_folder",
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
+    )]
+    pub sub_keypair_folder: Option<String>,
+    #[arg(long, value_name = "token_address", help = "the mint")]
+    pub token_address: String,
+    #[arg(long, value_name = "pubkey", help = "who may move the token")]
+    pub delegate: String,
+    #[arg(
+        long,
+        value_name = "amount",
+        help = "the raw amount the delegate may move out of each wallet"
+    )]
+    pub amount: u64,
+}
+#[derive(Parser, Debug)]
+struct FreezeArgs {
+    #[arg(
+        long,
+        value_name = "main_keypair_file",
+        help = "the freeze authority, it pays the fees, default is the profile's main_keypair_file"
+    )]
+    pub main_keypair_file: Option<String>,
+    #[arg(
+        long,
+        value_name = "sub_keypair_folder",
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
+    )]
+    pub sub_keypair_folder: Option<String>,
+    #[arg(long, value_name = "token_address", help = "the mint")]
+    pub token_address: String,
+}
+#[derive(Parser, Debug)]
+struct WalletPortfolioArgs {
+    #[arg(
+        long,
+        value_name = "sub_keypair_folder",
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
+    )]
+    pub sub_keypair_folder: Option<String>,
+}
+#[derive(Subcommand, Debug)]
+enum StakeCommands {
+    #[command(about = "create a stake account for every sub wallet out of its own sol")]
+    Create(StakeCreateArgs),
+    #[command(about = "delegate the inactive stake accounts to a vote account")]
+    Delegate(StakeDelegateArgs),
+    #[command(about = "deactivate the active stake accounts")]
+    Deactivate(StakeAccountArgs),
+    #[command(about = "withdraw the inactive stake accounts back to the sub wallets")]
+    Withdraw(StakeAccountArgs),
+    #[command(about = "list the stake accounts of the sub wallets with their activation state")]
+    List(StakeListArgs),
+}
+#[derive(Parser, Debug)]
+struct StakeArgs {
+    #[command(subcommand)]
+    commands: StakeCommands,
+}
+#[derive(Parser, Debug)]
+struct StakeCreateArgs {
+    #[arg(
+        long,
+        value_name = "sub_keypair_folder",
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
+    )]
+    pub sub_keypair_folder: Option<String>,
+    #[arg(
+        long,
+        value_name = "lamports",
+        help = "how many lamports each stake account gets, its rent included, the sub wallet pays the fee too"
+    )]
+    pub lamports: u64,
+    #[arg(
+        long,
+        value_name = "vote_account",
+        help = "delegate the new stake accounts to this vote account right away"
+    )]
+    pub vote_account: Option<String>,
+}
+#[derive(Parser, Debug)]
+struct StakeDelegateArgs {
+    #[arg(
+        long,
+        value_name = "sub_keypair_folder",
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
+    )]
+    pub sub_keypair_folder: Option<String>,
+    #[arg(
+        long,
+        value_name = "vote_account",
+        help = "the validator's vote account"
+    )]
+    pub vote_account: String,
+    #[arg(
+        long,
+        value_name = "stake_account",
+        help = "only delegate this stake account, default is every inactive one"
+    )]
+    pub stake_account: Option<String>,
+}
+#[derive(Parser, Debug)]
+struct StakeAccountArgs {
+    #[arg(
+        long,
+        value_name = "sub_keypair_folder",
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
+    )]
+    pub sub_keypair_folder: Option<String>,
+    #[arg(
+        long,
+        value_name = "stake_account",
+        help = "only this stake account, default is every one it applies to"
+    )]
+    pub stake_account: Option<String>,
+}
+#[derive(Parser, Debug)]
+struct StakeListArgs {
+    #[arg(
+        long,
+        value_name = "sub_keypair_folder",
+        help = "your sub keypair folder's location, default is the profile's sub_keypair_folder, a .jsonl/.csv secrets file, keystore://, mnemonic:// or prompt:// work too"
+    )]
+    pub sub_keypair_folder: Option<String>,
+}
+#[derive(Subcommand, Debug)]
+enum SignerCommands {
+    #[command(about = "unlock a keystore once and sign what the policy allows over a unix socket")]
+    Serve(SignerServeArgs),
+}
+#[derive(Parser, Debug)]
+struct SignerArgs {
+    #[command(subcommand)]
+    commands: SignerCommands,
+}
+#[derive(Parser, Debug)]
+struct SignerServeArgs {
+    #[arg(
+        long,
+        value_name = "keystore_file",
+        help = "the keystore made by convert keystore"
+    )]
+    pub keystore: String,
+    #[arg(
+        long,
+        value_name = "socket",
+        help = "the unix socket to listen on, default is ~/.solana_tool/signer.sock"
+    )]
+    pub socket: Option<String>,
+    #[arg(
+        long,
+        value_name = "policy_file",
+        help = "the toml file with the programs, amounts and destinations the signer allows"
+    )]
+    pub policy: Option<String>,
+}
+#[derive(Parser, Debug)]
+struct HistoryArgs {
+    #[arg(
+        long,
+        value_name = "wallet",
+        help = "only the transactions this wallet signed or received"
+    )]
+    pub wallet: Option<String>,
+    #[arg(long, value_name = "token_address", help = "only this token mint")]
+    pub token_address: Option<String>,
+    #[arg(
+        long,
+        value_name = "run_id",
+        help = "only the transactions of this run"
+    )]
+    pub run: Option<String>,
+    #[arg(
+        long,
+        value_name = "YYYY-MM-DD",
+        help = "only the transactions from this day on"
+    )]
+    pub since: Option<NaiveDate>,
+    #[arg(
+        long,
+        value_name = "YYYY-MM-DD",
+        help = "only the transactions up to and including this day"
+    )]
+    pub until: Option<NaiveDate>,
+    #[arg(
+        long,
+        value_name = "limit",
+        default_value = "100",
+        help = "show at most this many of the latest transactions"
+    )]
+    pub limit: u32,
+}
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    setup_logger().unwrap();
-    let cluster = args.rpc.unwrap();
-    let rpc_client = RpcClient::new_with_commitment(cluster, CommitmentConfig::confirmed());
+    setup_logger(args.output != OutputFormat::Text).unwrap();
+    let exit_code = match run(args).await {
+        Ok(exit_code) => exit_code,
+        Err(e) => {
+            error!("{:#}", e);
+            e.kind().exit_code()
+        }
+    };
+    std::process::exit(exit_code);
+}
+/// Run the command, the exit code tells whether and how any of it failed.
+async fn run(args: Args) -> Result<i32, Error> {
+    let profile = load_profile(args.config.as_deref(), args.profile.as_deref())
+        .context("Failed to load the profile")
+        .map_err(Error::Config)?
+        .merge(cli_profile(&args));
+    let mut rpc_pool = build_rpc_pool(&args, &profile)
+        .context("Failed to set up the rpc endpoints")
+        .map_err(Error::Config)?;
+    if uses_websocket(&args.commands) {
+        let ws_url = profile
+            .ws
+            .clone()
+            .unwrap_or_else(|| websocket_url(rpc_pool.primary_url()));
+        if let Some(pubsub) = connect_pubsub(&ws_url).await {
+            rpc_pool = rpc_pool.with_pubsub(pubsub);
+        }
+    }
+    let explorer = profile.explorer(rpc_pool.primary_url());
+    let rpc_pool = Arc::new(rpc_pool);
+    rpc_pool.spawn_health_check(Duration::from_secs(args.health_check_interval));
+    let ledger = match command_name(&args.commands) {
+        Some(command) => {
+            let ledger_file = args.ledger.clone().unwrap_or_else(Ledger::default_path);
+            match Ledger::open(&ledger_file, command) {
+                Ok(ledger) => {
+                    if !matches!(args.commands, Commands::History(_)) {
+                        info!(
+                            "Recording this run as {} in {}",
+                            ledger.run_id(),
+                            ledger_file
+                        );
+                    }
+                    Some(Arc::new(ledger))
+                }
+                Err(e) => {
+                    warn!("Failed to open the ledger with error: {:#}", e);
+                    None
+                }
+            }
+        }
+        None => None,
+    };
 
-    let tool = Arc::new(Tool::new(Arc::new(rpc_client)));
+    let command = command_label(&args.commands);
+    let mut tool = Tool::new(
+        rpc_pool,
+        ledger,
+        explorer,
+        profile.priority_fee.unwrap_or_default(),
+        Arc::new(Output::new(args.output)),
+    );
+    if let Some(ref policy_file) = profile.spending_policy {
+        tool = tool.with_policy(SpendingPolicy::load(policy_file)?);
+    }
 
-    match args.commands {
+    let yes = args.yes;
+    let outcome = match args.commands {
         Commands::Wallet(args) => match args.commands {
-            WalletCommands::Crate(args) => tool.generate_wallet(args.amount, args.output).await,
+            WalletCommands::Crate(args) => {
+                tool.generate_wallet(args.amount, args.output_folder).await
+            }
             WalletCommands::Balance(args) => {
-                tool.check_wallet_balance(args.sub_keypair_folder, args.token_address)
-                    .await
+                let wallets =
+                    parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?
+                        .load()?;
+                let token = args
+                    .token_address
+                    .as_deref()
+                    .map(parse_pubkey)
+                    .transpose()?;
+                let outcome = tool.check_wallet_balance(&wallets, token).await?;
+                Ok(with_unreadable(outcome, wallets))
+            }
+            WalletCommands::Watch(args) => {
+                let wallets =
+                    parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?
+                        .load()?;
+                let token = args
+                    .token_address
+                    .as_deref()
+                    .map(parse_pubkey)
+                    .transpose()?;
+                let outcome = tool
+                    .watch_wallet_balance(&wallets, token, args.interval)
+                    .await?;
+                Ok(with_unreadable(outcome, wallets))
+            }
+            WalletCommands::Portfolio(args) => {
+                let wallets =
+                    parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?
+                        .load()?;
+                let outcome = tool.wallet_portfolio(&wallets).await?;
+                Ok(with_unreadable(outcome, wallets))
             }
         },
         Commands::Distribute(args) => {
-            tool.distribute(
-                args.sub_keypair_folder,
-                args.main_keypair_file,
-                args.lamports,
-                args.token_address,
-                args.decimals,
-            )
-            .await
+            let wallets =
+                parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?.load()?;
+            let (mut batch, funders) = match args.funders {
+                Some(ref folder) => {
+                    let funders = parse_key_source(folder)?.load()?;
+                    let signers: Vec<_> = funders.iter().map(|f| f.signer.clone()).collect();
+                    let first = signers
+                        .first()
+                        .cloned()
+                        .ok_or_else(|| anyhow!("no funder wallet in {}", folder))?;
+                    let batch = BatchTransfer::new(first, args.lamports).funders(signers);
+                    (batch, Some(funders))
+                }
+                None => {
+                    let funder =
+                        parse_key_source(&profile.main_keypair_file(args.main_keypair_file)?)?
+                            .signer()?;
+                    (BatchTransfer::new(funder, args.lamports), None)
+                }
+            };
+            if args.bump_to_rent {
+                batch = batch.bump_to_rent();
+            }
+            if let Some(ref token_address) = args.token_address {
+                let (mint, decimals) = token_decimals(&profile, token_address, args.decimals)?;
+                batch = batch.token(mint, decimals);
+            }
+            let plan = batch.plan(&tool, &wallets).await?;
+            confirm(&tool, std::slice::from_ref(&plan), yes)?;
+            let report = batch.run(&tool, &plan).await?;
+            let outcome = with_unreadable(report.outcome(), wallets);
+            Ok(match funders {
+                Some(funders) => with_unreadable(outcome, funders),
+                None => outcome,
+            })
         }
         Commands::Collect(args) => {
-            tool.collect(
-                args.sub_keypair_folder,
-                args.main_keypair_file,
-                args.token_address,
-                args.decimals,
-            )
-            .await
+            let destination =
+                parse_key_source(&profile.main_keypair_file(args.main_keypair_file)?)?.signer()?;
+            let wallets =
+                parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?.load()?;
+            let report = if args.all_tokens {
+                let mut sweep = TokenSweep::new(destination);
+                for mint in &args.exclude_mint {
+                    sweep = sweep.exclude_mint(parse_pubkey(mint)?);
+                }
+                if let Some(min_ui_value) = args.min_ui_value {
+                    sweep = sweep.min_ui_value(min_ui_value);
+                }
+                let plan = sweep.plan(&tool, &wallets).await?;
+                confirm(&tool, &plan.summaries, yes)?;
+                sweep.run(&tool, plan).await?
+            } else {
+                let mut sweep = Sweep::new(destination);
+                if args.sub_pays_fee {
+                    sweep = sweep.sub_pays_fee();
+                }
+                if let Some(ref token_address) = args.token_address {
+                    let (mint, decimals) = token_decimals(&profile, token_address, args.decimals)?;
+                    sweep = sweep.token(mint, decimals);
+                }
+                let plan = sweep.plan(&tool, &wallets).await?;
+                confirm(&tool, std::slice::from_ref(&plan.summary), yes)?;
+                sweep.run(&tool, plan).await?
+            };
+            Ok(with_unreadable(report.outcome(), wallets))
+        }
+        Commands::Rebalance(args) => {
+            let wallets =
+                parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?.load()?;
+            let mut rebalance = Rebalance::new();
+            if let Some(target) = args.target {
+                rebalance = rebalance.target(target);
+            }
+            if let Some(ref token_address) = args.token_address {
+                let (mint, decimals) = token_decimals(&profile, token_address, args.decimals)?;
+                rebalance = rebalance.token(mint, decimals);
+            }
+            let plan = rebalance.plan(&tool, &wallets).await?;
+            confirm(&tool, std::slice::from_ref(&plan), yes)?;
+            let report = rebalance.run(&tool, &wallets, &plan).await?;
+            Ok(with_unreadable(report.outcome(), wallets))
         }
         Commands::Convert(args) => match args.commands {
-            ConvertCommands::Bs58(args) => tool.json_to_bs58(args.json_file).await,
-            ConvertCommands::Json(args) => tool.bs58_to_json(args.bs58, args.output).await,
+            ConvertCommands::Bs58(args) => {
+                let wallets = parse_key_source(&args.json_file)?.load()?;
+                let outcome = tool.json_to_bs58(&wallets).await?;
+                Ok(with_unreadable(outcome, wallets))
+            }
+            ConvertCommands::Json(args) => tool.bs58_to_json(args.bs58, args.output_file).await,
+            ConvertCommands::Keystore(args) => {
+                let wallets = parse_key_source(&args.from)?.load()?;
+                let outcome = tool.to_keystore(&wallets, args.keystore).await?;
+                Ok(with_unreadable(outcome, wallets))
+            }
         },
         Commands::Close(args) => {
-            match tool
-                .close(
-                    args.sub_keypair_folder,
-                    args.main_keypair_file,
-                    args.token_address,
+            let payer =
+                parse_key_source(&profile.main_keypair_file(args.main_keypair_file)?)?.signer()?;
+            let wallets =
+                parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?.load()?;
+            let mut close = CloseAccounts::new(payer);
+            if let Some(ref token_address) = args.token_address {
+                close = close.mint(parse_pubkey(token_address)?);
+            }
+            let report = close.run(&tool, &wallets).await?;
+            Ok(with_unreadable(report.outcome(), wallets))
+        }
+        Commands::Wsol(args) => match args.commands {
+            WsolCommands::Wrap(args) => {
+                let wallets =
+                    parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?
+                        .load()?;
+                let wrap = WrapSol::new(args.amount);
+                confirm(&tool, &[wrap.plan(&tool, &wallets).await?], yes)?;
+                let report = wrap.run(&tool, &wallets).await?;
+                Ok(with_unreadable(report.outcome(), wallets))
+            }
+            WsolCommands::Unwrap(args) => {
+                let wallets =
+                    parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?
+                        .load()?;
+                let report = UnwrapSol::new().run(&tool, &wallets).await?;
+                Ok(with_unreadable(report.outcome(), wallets))
+            }
+        },
+        Commands::Stake(args) => match args.commands {
+            StakeCommands::Create(args) => {
+                let wallets =
+                    parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?
+                        .load()?;
+                let mut create = CreateStake::new(args.lamports);
+                if let Some(ref vote_account) = args.vote_account {
+                    create = create.delegate_to(parse_pubkey(vote_account)?);
+                }
+                let plan = create.plan(&tool, &wallets).await?;
+                confirm(&tool, std::slice::from_ref(&plan.summary), yes)?;
+                let report = create.run(&tool, plan).await?;
+                Ok(with_unreadable(report.outcome(), wallets))
+            }
+            StakeCommands::Delegate(args) => {
+                let action = StakeAction::Delegate(parse_pubkey(&args.vote_account)?);
+                let only = StakeAccountArgs {
+                    sub_keypair_folder: args.sub_keypair_folder,
+                    stake_account: args.stake_account,
+                };
+                manage_stake(&tool, &profile, only, action).await
+            }
+            StakeCommands::Deactivate(args) => {
+                manage_stake(&tool, &profile, args, StakeAction::Deactivate).await
+            }
+            StakeCommands::Withdraw(args) => {
+                manage_stake(&tool, &profile, args, StakeAction::Withdraw).await
+            }
+            StakeCommands::List(args) => {
+                let wallets =
+                    parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?
+                        .load()?;
+                let outcome = tool.show_stake_accounts(&wallets).await?;
+                Ok(with_unreadable(outcome, wallets))
+            }
+        },
+        Commands::Token(args) => match args.commands {
+            TokenCommands::CreateMint(args) => {
+                let payer = parse_key_source(&profile.main_keypair_file(args.main_keypair_file)?)?
+                    .signer()?;
+                let mint: SharedSigner = match args.mint_keypair {
+                    Some(ref mint_keypair) => parse_key_source(mint_keypair)?.signer()?,
+                    None => Arc::new(Keypair::new()),
+                };
+                let mut create = CreateMint::new(args.decimals);
+                if let Some(ref authority) = args.mint_authority {
+                    create = create.mint_authority(parse_pubkey(authority)?);
+                }
+                if let Some(ref authority) = args.freeze_authority {
+                    create = create.freeze_authority(parse_pubkey(authority)?);
+                }
+                let result = create.run(&tool, &*payer, &*mint).await;
+                if let Ok(ref info) = result {
+                    tool.output.emit(info.record());
+                }
+                let mut outcome = Outcome::default();
+                outcome.add(Step::of(mint.pubkey(), result));
+                Ok(outcome)
+            }
+            TokenCommands::MintTo(args) => {
+                let authority =
+                    parse_key_source(&profile.main_keypair_file(args.main_keypair_file)?)?
+                        .signer()?;
+                let mint = parse_pubkey(&args.token_address)?;
+                let (recipients, unreadable) = match args.csv {
+                    Some(ref csv) => read_recipients(csv, args.amount)?,
+                    None => {
+                        let amount = args.amount.ok_or_else(|| {
+                            anyhow!("--amount is needed to mint to the sub wallets")
+                        })?;
+                        let wallets = parse_key_source(
+                            &profile.sub_keypair_folder(args.sub_keypair_folder)?,
+                        )?
+                        .load()?;
+                        let recipients = wallets
+                            .iter()
+                            .map(|wallet| (wallet.pubkey(), amount))
+                            .collect();
+                        (recipients, wallets.unreadable)
+                    }
+                };
+                let info = tool.mint_info(&mint).await?;
+                let mint_to = MintTo::new(authority, mint, info.decimals);
+                confirm(&tool, &[mint_to.plan(&tool, &recipients).await?], yes)?;
+                let mut outcome = mint_to.run(&tool, &recipients).await?.outcome();
+                outcome.merge(Outcome::from(unreadable));
+                Ok(outcome)
+            }
+            TokenCommands::Burn(args) => {
+                let payer = parse_key_source(&profile.main_keypair_file(args.main_keypair_file)?)?
+                    .signer()?;
+                let wallets =
+                    parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?
+                        .load()?;
+                let mint = parse_pubkey(&args.token_address)?;
+                let info = tool.mint_info(&mint).await?;
+                let mut burn = Burn::new(payer, mint, info.decimals);
+                if let Some(amount) = args.amount {
+                    burn = burn.amount(amount);
+                }
+                let plan = burn.plan(&tool, &wallets).await?;
+                confirm(&tool, std::slice::from_ref(&plan.summary), yes)?;
+                let report = burn.run(&tool, plan).await?;
+                Ok(with_unreadable(report.outcome(), wallets))
+            }
+            TokenCommands::SetAuthority(args) => {
+                let authority =
+                    parse_key_source(&profile.main_keypair_file(args.main_keypair_file)?)?
+                        .signer()?;
+                let mint = parse_pubkey(&args.token_address)?;
+                let new_authority = args
+                    .new_authority
+                    .as_deref()
+                    .map(parse_pubkey)
+                    .transpose()?;
+                let authority_type = match args.authority {
+                    MintAuthority::Mint => AuthorityType::MintTokens,
+                    MintAuthority::Freeze => AuthorityType::FreezeAccount,
+                };
+                let result = tool
+                    .set_mint_authority(&mint, authority_type, new_authority.as_ref(), &*authority)
+                    .await;
+                match result {
+                    Ok(ref signature) => info!(
+                        "Successfuly changed the {:?} authority of {}, check the info: {}",
+                        args.authority,
+                        mint,
+                        tool.explorer_link(signature)
+                    ),
+                    Err(ref e) => error!(
+                        "Failed to change the {:?} authority of {} with error: {}",
+                        args.authority, mint, e
+                    ),
+                }
+                let mut outcome = Outcome::default();
+                outcome.add(Step::of(mint, result));
+                Ok(outcome)
+            }
+            TokenCommands::Info(args) => {
+                let mint = parse_pubkey(&args.token_address)?;
+                tool.show_mint_info(&mint).await
+            }
+            TokenCommands::Delegations(args) => {
+                let wallets =
+                    parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?
+                        .load()?;
+                let outcome = tool.show_delegations(&wallets).await?;
+                Ok(with_unreadable(outcome, wallets))
+            }
+            TokenCommands::Revoke(args) => {
+                let payer = parse_key_source(&profile.main_keypair_file(args.main_keypair_file)?)?
+                    .signer()?;
+                let wallets =
+                    parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?
+                        .load()?;
+                let mut revoke = Revoke::new(payer);
+                if let Some(ref delegate) = args.delegate {
+                    revoke = revoke.delegate(parse_pubkey(delegate)?);
+                }
+                if let Some(ref token_address) = args.token_address {
+                    revoke = revoke.mint(parse_pubkey(token_address)?);
+                }
+                for account in &args.account {
+                    revoke = revoke.account(parse_pubkey(account)?);
+                }
+                let report = revoke.run(&tool, &wallets).await?;
+                Ok(with_unreadable(report.outcome(), wallets))
+            }
+            TokenCommands::Approve(args) => {
+                let payer = parse_key_source(&profile.main_keypair_file(args.main_keypair_file)?)?
+                    .signer()?;
+                let wallets =
+                    parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?
+                        .load()?;
+                let mint = parse_pubkey(&args.token_address)?;
+                let info = tool.mint_info(&mint).await?;
+                let approve = Approve::new(
+                    payer,
+                    parse_pubkey(&args.delegate)?,
+                    mint,
+                    info.decimals,
+                    args.amount,
+                );
+                let plan = approve.plan(&tool, &wallets).await?;
+                confirm(&tool, std::slice::from_ref(&plan.summary), yes)?;
+                let report = approve.run(&tool, plan).await?;
+                Ok(with_unreadable(report.outcome(), wallets))
+            }
+            TokenCommands::Freeze(args) => freeze(&tool, &profile, args, false).await,
+            TokenCommands::Thaw(args) => freeze(&tool, &profile, args, true).await,
+        },
+        Commands::Signer(args) => match args.commands {
+            SignerCommands::Serve(args) => {
+                let wallets = Keystore(PathBuf::from(&args.keystore)).load()?;
+                let policy = match args.policy {
+                    Some(ref policy) => SigningPolicy::load(policy)?,
+                    None => {
+                        warn!("No --policy, transfers may only go to the wallets the signer holds");
+                        SigningPolicy::default()
+                    }
+                };
+                tool.serve_signer(
+                    &wallets,
+                    policy,
+                    args.socket.unwrap_or_else(default_socket_path),
                 )
                 .await
-            {
-                Ok(_) => {}
-                Err(e) => {
-                    eprintln!("{}", e)
-                }
             }
+        },
+        Commands::History(args) => {
+            let query = LedgerQuery {
+                wallet: args.wallet,
+                mint: args.token_address,
+                run_id: args.run,
+                since: args.since,
+                until: args.until,
+                limit: args.limit,
+            };
+            tool.history(query).await
         }
+    };
+    // what was sent is in the ledger even when the command stopped early
+    if let Some(ref ledger) = tool.ledger {
+        ledger.flush().await;
+    }
+    let outcome = outcome?;
+    let exit_code = outcome.exit_code();
+    tool.output.finish(command, outcome);
+    Ok(exit_code)
+}
+/// Show what the run is about to send, on mainnet it only goes on once the
+/// user typed yes or passed --yes.
+fn confirm(tool: &Tool, summaries: &[RunSummary], yes: bool) -> Result<(), Error> {
+    for summary in summaries {
+        info!("About to send {}", summary);
+        if summary.allocation.len() > 1 {
+            for share in &summary.allocation {
+                info!(
+                    "Funder {} holds {} and sends {} to {} wallets",
+                    share.funder, share.balance, share.amount, share.recipients
+                );
+            }
+        }
+        for transfer in &summary.transfers {
+            info!(
+                "Move {} from {} to {}",
+                transfer.amount, transfer.from, transfer.to
+            );
+        }
+        for record in summary.records() {
+            tool.output.emit(record);
+        }
+    }
+    let Some(cluster) = summaries
+        .iter()
+        .find(|summary| summary.recipients > 0)
+        .map(|summary| summary.cluster)
+    else {
+        return Ok(());
+    };
+    if yes || !cluster.is_mainnet() {
+        return Ok(());
+    }
+    eprint!("This sends real funds on {}, type yes to go on: ", cluster);
+    let mut answer = String::new();
+    io::stdin()
+        .read_line(&mut answer)
+        .map_err(|e| Error::io("stdin", e))?;
+    if answer.trim() != "yes" {
+        return Err(Error::NotConfirmed);
     }
+    Ok(())
 }
-impl Tool {
-    pub fn new(rpc_client: Arc<RpcClient>) -> Self {
-        Self { rpc_client }
+/// The mint given on the command line and its decimals, wsol always has 9
+/// whatever the profile says.
+fn token_decimals(
+    profile: &Profile,
+    token_address: &str,
+    decimals: Option<u8>,
+) -> Result<(Pubkey, u8), Error> {
+    let mint = parse_pubkey(token_address)?;
+    if is_native(&mint) {
+        return Ok((mint, 9));
+    }
+    let decimals = profile.decimals(decimals).ok_or(Error::MissingDecimals)?;
+    Ok((mint, decimals))
+}
+/// Freeze, or thaw, the token accounts of the sub wallets.
+async fn freeze(
+    tool: &Tool,
+    profile: &Profile,
+    args: FreezeArgs,
+    thaw: bool,
+) -> Result<Outcome, Error> {
+    let authority =
+        parse_key_source(&profile.main_keypair_file(args.main_keypair_file)?)?.signer()?;
+    let wallets =
+        parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?.load()?;
+    let mut freeze = Freeze::new(authority, parse_pubkey(&args.token_address)?);
+    if thaw {
+        freeze = freeze.thaw();
+    }
+    let report = freeze.run(tool, &wallets).await?;
+    Ok(with_unreadable(report.outcome(), wallets))
+}
+/// Delegate, deactivate or withdraw the stake accounts of the sub wallets.
+async fn manage_stake(
+    tool: &Tool,
+    profile: &Profile,
+    args: StakeAccountArgs,
+    action: StakeAction,
+) -> Result<Outcome, Error> {
+    let wallets =
+        parse_key_source(&profile.sub_keypair_folder(args.sub_keypair_folder)?)?.load()?;
+    let mut manage = ManageStake::new(action);
+    if let Some(ref stake_account) = args.stake_account {
+        manage = manage.stake_account(parse_pubkey(stake_account)?);
+    }
+    let report = manage.run(tool, &wallets).await?;
+    Ok(with_unreadable(report.outcome(), wallets))
+}
+/// Count the files of the folder which were not a keypair as failed.
+fn with_unreadable(mut outcome: Outcome, wallets: WalletSet) -> Outcome {
+    outcome.merge(Outcome::from(wallets.unreadable));
+    outcome
+}
+/// The global flags given on the command line, laid over the profile.
+fn cli_profile(args: &Args) -> Profile {
+    let rpc_given = !args.rpc.is_empty() || args.rpc_file.is_some();
+    Profile {
+        rpc: if rpc_given {
+            Some(args.rpc.clone())
+        } else {
+            None
+        },
+        ws: args.ws.clone(),
+        commitment: args.commitment.clone(),
+        rps: args.rps,
+        concurrency: args.concurrency,
+        priority_fee: args.priority_fee,
+        spending_policy: args.spending_policy.clone(),
+        ..Profile::default()
+    }
+}
+/// Only the commands which send transactions or watch accounts need the
+/// websocket connection.
+fn uses_websocket(commands: &Commands) -> bool {
+    match commands {
+        Commands::Wallet(args) => matches!(args.commands, WalletCommands::Watch(_)),
+        Commands::Distribute(_)
+        | Commands::Collect(_)
+        | Commands::Rebalance(_)
+        | Commands::Close(_)
+        | Commands::Wsol(_) => true,
+        Commands::Stake(args) => !matches!(args.commands, StakeCommands::List(_)),
+        Commands::Token(args) => !matches!(
+            args.commands,
+            TokenCommands::Info(_) | TokenCommands::Delegations(_)
+        ),
+        Commands::Convert(_) | Commands::History(_) | Commands::Signer(_) => false,
+    }
+}
+/// The name of the command in the output summary.
+fn command_label(commands: &Commands) -> &'static str {
+    match commands {
+        Commands::Wallet(args) => match args.commands {
+            WalletCommands::Crate(_) => "wallet crate",
+            WalletCommands::Balance(_) => "wallet balance",
+            WalletCommands::Watch(_) => "wallet watch",
+            WalletCommands::Portfolio(_) => "wallet portfolio",
+        },
+        Commands::Convert(args) => match args.commands {
+            ConvertCommands::Bs58(_) => "convert bs58",
+            ConvertCommands::Json(_) => "convert json",
+            ConvertCommands::Keystore(_) => "convert keystore",
+        },
+        Commands::Distribute(_) => "distribute",
+        Commands::Collect(_) => "collect",
+        Commands::Rebalance(_) => "rebalance",
+        Commands::Close(_) => "close",
+        Commands::Wsol(args) => match args.commands {
+            WsolCommands::Wrap(_) => "wsol wrap",
+            WsolCommands::Unwrap(_) => "wsol unwrap",
+        },
+        Commands::Stake(args) => match args.commands {
+            StakeCommands::Create(_) => "stake create",
+            StakeCommands::Delegate(_) => "stake delegate",
+            StakeCommands::Deactivate(_) => "stake deactivate",
+            StakeCommands::Withdraw(_) => "stake withdraw",
+            StakeCommands::List(_) => "stake list",
+        },
+        Commands::Token(args) => match args.commands {
+            TokenCommands::CreateMint(_) => "token create-mint",
+            TokenCommands::MintTo(_) => "token mint-to",
+            TokenCommands::Burn(_) => "token burn",
+            TokenCommands::SetAuthority(_) => "token set-authority",
+            TokenCommands::Info(_) => "token info",
+            TokenCommands::Delegations(_) => "token delegations",
+            TokenCommands::Revoke(_) => "token revoke",
+            TokenCommands::Approve(_) => "token approve",
+            TokenCommands::Freeze(_) => "token freeze",
+            TokenCommands::Thaw(_) => "token thaw",
+        },
+        Commands::History(_) => "history",
+        Commands::Signer(_) => "signer serve",
+    }
+}
+/// The name a command is recorded under in the ledger, `None` for the ones
+/// which never touch it.
+fn command_name(commands: &Commands) -> Option<&'static str> {
+    match commands {
+        Commands::Distribute(_) => Some("distribute"),
+        Commands::Collect(_) => Some("collect"),
+        Commands::Rebalance(_) => Some("rebalance"),
+        Commands::Close(_) => Some("close"),
+        Commands::Wsol(args) => match args.commands {
+            WsolCommands::Wrap(_) => Some("wsol wrap"),
+            WsolCommands::Unwrap(_) => Some("wsol unwrap"),
+        },
+        Commands::Stake(args) => match args.commands {
+            StakeCommands::Create(_) => Some("stake create"),
+            StakeCommands::Delegate(_) => Some("stake delegate"),
+            StakeCommands::Deactivate(_) => Some("stake deactivate"),
+            StakeCommands::Withdraw(_) => Some("stake withdraw"),
+            StakeCommands::List(_) => None,
+        },
+        Commands::Token(args) => match args.commands {
+            TokenCommands::CreateMint(_) => Some("token create-mint"),
+            TokenCommands::MintTo(_) => Some("token mint-to"),
+            TokenCommands::Burn(_) => Some("token burn"),
+            TokenCommands::SetAuthority(_) => Some("token set-authority"),
+            TokenCommands::Info(_) | TokenCommands::Delegations(_) => None,
+            TokenCommands::Revoke(_) => Some("token revoke"),
+            TokenCommands::Approve(_) => Some("token approve"),
+            TokenCommands::Freeze(_) => Some("token freeze"),
+            TokenCommands::Thaw(_) => Some("token thaw"),
+        },
+        Commands::History(_) => Some("history"),
+        Commands::Wallet(_) | Commands::Convert(_) | Commands::Signer(_) => None,
+    }
+}
+fn build_rpc_pool(args: &Args, profile: &Profile) -> Result<RpcPool> {
+    let mut endpoints = profile
+        .rpc
+        .iter()
+        .flatten()
+        .map(|endpoint| endpoint.parse::<EndpointSpec>())
+        .collect::<Result<Vec<_>>>()?;
+    if let Some(ref rpc_file) = args.rpc_file {
+        endpoints.extend(RpcPool::load_endpoint_file(rpc_file)?);
+    }
+    if endpoints.is_empty() {
+        endpoints.push(DEFAULT_RPC.parse()?);
     }
+    RpcPool::new(
+        endpoints,
+        profile.commitment()?,
+        args.max_slot_lag,
+        profile.rps.unwrap_or(20),
+        profile.concurrency.unwrap_or(20),
+    )
 }
@@ -0,0 +1,161 @@
+use std::fmt::Display;
+
+use serde::Serialize;
+use tokio::task::JoinError;
+
+use crate::error::Error;
+
+/// What went wrong, decides the exit code of the process. The kinds are in
+/// the order they win the exit code, a crash of the tool over everything.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FailureKind {
+    /// Bad arguments, files or keys.
+    Input,
+    /// The rpc endpoints could not be reached or answered with an error.
+    Rpc,
+    /// The transaction landed, or was simulated, and failed.
+    OnChain,
+    /// A task of the tool itself died.
+    Internal,
+}
+
+impl FailureKind {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Self::Internal => 1,
+            Self::Input => 2,
+            Self::Rpc => 3,
+            Self::OnChain => 4,
+        }
+    }
+}
+
+/// One wallet, account or transfer which did not make it.
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    pub target: String,
+    pub kind: FailureKind,
+    pub error: String,
+}
+
+impl Failure {
+    pub fn new(target: impl Display, error: Error) -> Self {
+        Self {
+            target: target.to_string(),
+            kind: error.kind(),
+            error: error.to_string(),
+        }
+    }
+    /// A spawned task which panicked.
+    pub fn internal(error: JoinError) -> Self {
+        Self {
+            target: "task".to_string(),
+            kind: FailureKind::Internal,
+            error: error.to_string(),
+        }
+    }
+}
+
+/// The result of one unit of work of a command.
+pub enum Step {
+    Succeeded,
+    Skipped,
+    Failed(Failure),
+}
+
+impl Step {
+    pub fn of<T, E: Into<Error>>(target: impl Display, result: Result<T, E>) -> Self {
+        match result {
+            Ok(_) => Step::Succeeded,
+            Err(e) => Step::Failed(Failure::new(target, e.into())),
+        }
+    }
+}
+
+/// What a command did: how many units succeeded, failed or were skipped,
+/// and why the failed ones failed.
+#[derive(Debug, Default, Serialize)]
+pub struct Outcome {
+    pub succeeded: usize,
+    pub failed: usize,
+    pub skipped: usize,
+    pub failures: Vec<Failure>,
+}
+
+impl Outcome {
+    pub fn add(&mut self, step: Step) {
+        match step {
+            Step::Succeeded => self.succeeded += 1,
+            Step::Skipped => self.skipped += 1,
+            Step::Failed(failure) => {
+                self.failed += 1;
+                self.failures.push(failure);
+            }
+        }
+    }
+    /// Add the steps of the spawned tasks, a task which panicked counts as
+    /// an internal failure.
+    pub fn join(&mut self, results: Vec<Result<Step, JoinError>>) {
+        for result in results {
+            match result {
+                Ok(step) => self.add(step),
+                Err(e) => self.add(Step::Failed(Failure::internal(e))),
+            }
+        }
+    }
+    pub fn merge(&mut self, other: Outcome) {
+        self.succeeded += other.succeeded;
+        self.failed += other.failed;
+        self.skipped += other.skipped;
+        self.failures.extend(other.failures);
+    }
+    /// 0 when nothing failed, otherwise the code of the worst failure.
+    pub fn exit_code(&self) -> i32 {
+        self.failures
+            .iter()
+            .map(|failure| failure.kind)
+            .max()
+            .map_or(0, FailureKind::exit_code)
+    }
+}
+
+impl From<Vec<Failure>> for Outcome {
+    fn from(failures: Vec<Failure>) -> Self {
+        Outcome {
+            failed: failures.len(),
+            failures,
+            ..Outcome::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn failure(kind: FailureKind) -> Failure {
+        Failure {
+            target: "wallet".to_string(),
+            kind,
+            error: "failed".to_string(),
+        }
+    }
+
+    #[test]
+    fn the_worst_failure_decides_the_exit_code() {
+        assert_eq!(Outcome::default().exit_code(), 0);
+        let outcome = Outcome::from(vec![
+            failure(FailureKind::OnChain),
+            failure(FailureKind::Internal),
+            failure(FailureKind::Input),
+        ]);
+        assert_eq!(outcome.exit_code(), 1);
+        let outcome = Outcome::from(vec![
+            failure(FailureKind::Input),
+            failure(FailureKind::OnChain),
+            failure(FailureKind::Rpc),
+        ]);
+        assert_eq!(outcome.exit_code(), 4);
+    }
+}
@@ -0,0 +1,305 @@
+use std::fmt::Display;
+use std::io::{self, Write};
+use std::sync::Mutex;
+
+use clap::ValueEnum;
+use log::{error, info};
+use serde::Serialize;
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+use spl_token::state::AccountState;
+
+use crate::ledger::LedgerRow;
+use crate::outcome::Outcome;
+
+/// How the results of a command are reported.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Only the log lines.
+    #[default]
+    Text,
+    /// One json array with every record, printed when the command is done.
+    Json,
+    /// One json record per line, printed as soon as it is known.
+    Jsonl,
+}
+
+/// A typed result of a command, what the json modes print to stdout.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Record {
+    Wallet {
+        pubkey: String,
+        file: String,
+    },
+    Keypair {
+        pubkey: String,
+        bs58: String,
+    },
+    Balance {
+        wallet: String,
+        mint: Option<String>,
+        amount: u64,
+        error: Option<String>,
+    },
+    TokenAccount {
+        wallet: String,
+        account: String,
+        mint: String,
+        amount: u64,
+        /// `initialized` or `frozen`.
+        state: String,
+        delegate: Option<String>,
+        delegated_amount: u64,
+        close_authority: Option<String>,
+    },
+    BalanceChange {
+        wallet: String,
+        account: String,
+        mint: Option<String>,
+        before: u64,
+        after: u64,
+    },
+    Transfer {
+        from: String,
+        to: String,
+        mint: Option<String>,
+        amount: u64,
+        signature: Option<String>,
+        error: Option<String>,
+    },
+    Close {
+        wallet: String,
+        account: String,
+        signature: Option<String>,
+        error: Option<String>,
+    },
+    SignRequest {
+        wallet: String,
+        signature: Option<String>,
+        error: Option<String>,
+    },
+    Plan {
+        cluster: String,
+        recipients: usize,
+        total_amount: u64,
+        mint: Option<String>,
+        decimals: u8,
+        accounts_to_create: usize,
+        account_rent: u64,
+        estimated_fee: u64,
+    },
+    Allocation {
+        funder: String,
+        balance: u64,
+        recipients: usize,
+        amount: u64,
+    },
+    PlannedTransfer {
+        from: String,
+        to: String,
+        mint: Option<String>,
+        amount: u64,
+    },
+    Mint {
+        mint: String,
+        supply: u64,
+        decimals: u8,
+        mint_authority: Option<String>,
+        freeze_authority: Option<String>,
+    },
+    Delegation {
+        wallet: String,
+        account: String,
+        mint: String,
+        delegate: String,
+        delegated_amount: u64,
+    },
+    Approval {
+        wallet: String,
+        account: String,
+        mint: String,
+        delegate: Option<String>,
+        amount: u64,
+        signature: Option<String>,
+        error: Option<String>,
+    },
+    Freeze {
+        wallet: String,
+        account: String,
+        mint: String,
+        frozen: bool,
+        signature: Option<String>,
+        error: Option<String>,
+    },
+    StakeAccount {
+        wallet: String,
+        stake_account: String,
+        lamports: u64,
+        stake: u64,
+        vote_account: Option<String>,
+        activation: String,
+    },
+    Stake {
+        action: String,
+        wallet: String,
+        stake_account: Option<String>,
+        lamports: u64,
+        signature: Option<String>,
+        error: Option<String>,
+    },
+    Portfolio {
+        wallet: String,
+        lamports: u64,
+        tokens: Vec<PortfolioToken>,
+        /// The stake fields are `None` when the stake accounts could not be
+        /// read.
+        stake_accounts: Option<usize>,
+        /// Everything the stake accounts hold, rent included.
+        staked_lamports: Option<u64>,
+        active_stake: Option<u64>,
+    },
+    Transaction(LedgerRow),
+    Summary {
+        command: String,
+        total_amount: u64,
+        #[serde(flatten)]
+        outcome: Outcome,
+    },
+}
+
+/// A token account in a `Record::Portfolio`.
+#[derive(Debug, Serialize)]
+pub struct PortfolioToken {
+    pub account: String,
+    pub mint: String,
+    pub amount: u64,
+    pub decimals: u8,
+}
+
+impl Record {
+    pub fn balance<E: Display>(
+        wallet: &Pubkey,
+        mint: Option<&Pubkey>,
+        result: &Result<u64, E>,
+    ) -> Self {
+        Record::Balance {
+            wallet: wallet.to_string(),
+            mint: mint.map(Pubkey::to_string),
+            amount: *result.as_ref().unwrap_or(&0),
+            error: result.as_ref().err().map(|e| e.to_string()),
+        }
+    }
+    pub fn token_account(
+        wallet: &Pubkey,
+        address: &Pubkey,
+        account: &spl_token::state::Account,
+    ) -> Self {
+        let state = match account.state {
+            AccountState::Uninitialized => "uninitialized",
+            AccountState::Initialized => "initialized",
+            AccountState::Frozen => "frozen",
+        };
+        Record::TokenAccount {
+            wallet: wallet.to_string(),
+            account: address.to_string(),
+            mint: account.mint.to_string(),
+            amount: account.amount,
+            state: state.to_string(),
+            delegate: Option::from(account.delegate).map(|delegate: Pubkey| delegate.to_string()),
+            delegated_amount: account.delegated_amount,
+            close_authority: Option::from(account.close_authority)
+                .map(|authority: Pubkey| authority.to_string()),
+        }
+    }
+    /// The lamports or token amount a successful record moved or holds.
+    fn amount(&self) -> u64 {
+        match self {
+            Record::Balance {
+                amount,
+                error: None,
+                ..
+            }
+            | Record::Transfer {
+                amount,
+                error: None,
+                ..
+            } => *amount,
+            _ => 0,
+        }
+    }
+}
+
+/// The signature and the error of a sent transaction, one of them is set.
+pub fn split<E: Display>(result: &Result<Signature, E>) -> (Option<String>, Option<String>) {
+    match result {
+        Ok(signature) => (Some(signature.to_string()), None),
+        Err(e) => (None, Some(e.to_string())),
+    }
+}
+
+#[derive(Default)]
+struct Tally {
+    total_amount: u64,
+    records: Vec<Record>,
+}
+
+/// Collects the records of a run and writes them to stdout in the chosen
+/// format, the logs go to stderr in the json modes.
+pub struct Output {
+    format: OutputFormat,
+    tally: Mutex<Tally>,
+}
+
+impl Output {
+    pub fn new(format: OutputFormat) -> Self {
+        Self {
+            format,
+            tally: Mutex::default(),
+        }
+    }
+    pub fn emit(&self, record: Record) {
+        let mut tally = self.tally.lock().unwrap();
+        tally.total_amount += record.amount();
+        match self.format {
+            OutputFormat::Text => {}
+            OutputFormat::Json => tally.records.push(record),
+            OutputFormat::Jsonl => print_json(&record, false),
+        }
+    }
+    /// Emit the summary of the run and, in json mode, everything buffered.
+    pub fn finish(&self, command: &str, outcome: Outcome) {
+        if self.format == OutputFormat::Text {
+            info!(
+                "{} finished: {} succeeded, {} failed, {} skipped",
+                command, outcome.succeeded, outcome.failed, outcome.skipped
+            );
+            return;
+        }
+        let total_amount = self.tally.lock().unwrap().total_amount;
+        self.emit(Record::Summary {
+            command: command.to_string(),
+            total_amount,
+            outcome,
+        });
+        if self.format == OutputFormat::Json {
+            let records = std::mem::take(&mut self.tally.lock().unwrap().records);
+            print_json(&records, true);
+        }
+    }
+}
+
+fn print_json<T: Serialize>(value: &T, pretty: bool) {
+    let json = if pretty {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    };
+    match json {
+        Ok(json) => {
+            let mut stdout = io::stdout().lock();
+            let _ = writeln!(stdout, "{}", json);
+            let _ = stdout.flush();
+        }
+        Err(e) => error!("Failed to serialize the output with error: {}", e),
+    }
+}
@@ -0,0 +1,223 @@
+use std::fmt;
+
+use solana_program::program_pack::Pack;
+use solana_sdk::{
+    account::Account, hash::Hash, instruction::Instruction, message::Message, pubkey::Pubkey,
+};
+use spl_token::amount_to_ui_amount_string;
+
+use crate::error::Result;
+use crate::output::Record;
+use crate::report::Transfer;
+use crate::Tool;
+
+const MAINNET_GENESIS_HASH: &str = "5eykt4UsFv8P8NJdTREpY1vzqKqZKvdpKuc147dw2N9d";
+const DEVNET_GENESIS_HASH: &str = "EtWTRABZaYq6iMfeYKouRu166VU2xqa1wcaWoxPkrZBG";
+const TESTNET_GENESIS_HASH: &str = "4uhcVJyU9pJkvQyS88uRDiswHXSCkY3zQawwpjk2NsNY";
+
+/// The cluster the rpc endpoints belong to, told apart by the genesis hash.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cluster {
+    Mainnet,
+    Devnet,
+    Testnet,
+    /// A local validator or anything else.
+    Other(Hash),
+}
+
+impl Cluster {
+    pub fn from_genesis_hash(genesis_hash: &Hash) -> Self {
+        match genesis_hash.to_string().as_str() {
+            MAINNET_GENESIS_HASH => Cluster::Mainnet,
+            DEVNET_GENESIS_HASH => Cluster::Devnet,
+            TESTNET_GENESIS_HASH => Cluster::Testnet,
+            _ => Cluster::Other(*genesis_hash),
+        }
+    }
+    pub fn is_mainnet(&self) -> bool {
+        *self == Cluster::Mainnet
+    }
+}
+
+impl fmt::Display for Cluster {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Cluster::Mainnet => write!(f, "mainnet-beta"),
+            Cluster::Devnet => write!(f, "devnet"),
+            Cluster::Testnet => write!(f, "testnet"),
+            Cluster::Other(genesis_hash) => write!(f, "unknown cluster {}", genesis_hash),
+        }
+    }
+}
+
+/// The part of a batch one funder pays.
+#[derive(Debug, Clone)]
+pub struct FunderShare {
+    pub funder: Pubkey,
+    /// What it held when the batch was planned.
+    pub balance: u64,
+    pub recipients: usize,
+    /// Lamports, or the raw token amount, it sends.
+    pub amount: u64,
+}
+
+/// What a batch is about to send, worked out before the first transaction.
+#[derive(Debug, Clone)]
+pub struct RunSummary {
+    pub cluster: Cluster,
+    /// The wallets which get a transfer, empty ones of a sweep left out.
+    pub recipients: usize,
+    /// Lamports, or the raw token amount.
+    pub total_amount: u64,
+    /// `None` for sol.
+    pub mint: Option<Pubkey>,
+    pub decimals: u8,
+    /// Associated token accounts created on the way.
+    pub accounts_to_create: usize,
+    /// The rent those accounts lock up, in lamports.
+    pub account_rent: u64,
+    /// The fees of every transaction of the run, priority fee included.
+    pub estimated_fee: u64,
+    /// How the recipients are spread over the funders, empty for a sweep.
+    pub allocation: Vec<FunderShare>,
+    /// The transfers worked out up front, what a distribute or a rebalance
+    /// sends once confirmed.
+    pub transfers: Vec<Transfer>,
+    /// The transfers left out because the token account of the recipient is
+    /// frozen, with that account.
+    pub frozen: Vec<(Transfer, Pubkey)>,
+}
+
+impl RunSummary {
+    pub fn ui_amount(&self) -> String {
+        amount_to_ui_amount_string(self.total_amount, self.decimals)
+    }
+    /// The plan record followed by one allocation record per funder.
+    pub fn records(&self) -> Vec<Record> {
+        let mut records = vec![self.record()];
+        records.extend(self.allocation.iter().map(|share| Record::Allocation {
+            funder: share.funder.to_string(),
+            balance: share.balance,
+            recipients: share.recipients,
+            amount: share.amount,
+        }));
+        records.extend(
+            self.transfers
+                .iter()
+                .map(|transfer| Record::PlannedTransfer {
+                    from: transfer.from.to_string(),
+                    to: transfer.to.to_string(),
+                    mint: transfer.mint.map(|mint| mint.to_string()),
+                    amount: transfer.amount,
+                }),
+        );
+        records
+    }
+    fn record(&self) -> Record {
+        Record::Plan {
+            cluster: self.cluster.to_string(),
+            recipients: self.recipients,
+            total_amount: self.total_amount,
+            mint: self.mint.map(|mint| mint.to_string()),
+            decimals: self.decimals,
+            accounts_to_create: self.accounts_to_create,
+            account_rent: self.account_rent,
+            estimated_fee: self.estimated_fee,
+        }
+    }
+}
+
+impl fmt::Display for RunSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let asset = match self.mint {
+            Some(ref mint) => format!("token {} ({} decimals)", mint, self.decimals),
+            None => "sol".to_string(),
+        };
+        write!(
+            f,
+            "{} for {} wallets, {} in total on {}, {} token accounts to create for {} sol rent, about {} sol in fees",
+            asset,
+            self.recipients,
+            self.ui_amount(),
+            self.cluster,
+            self.accounts_to_create,
+            amount_to_ui_amount_string(self.account_rent, 9),
+            amount_to_ui_amount_string(self.estimated_fee, 9),
+        )
+    }
+}
+
+impl Tool {
+    pub async fn cluster(&self) -> Result<Cluster> {
+        let genesis_hash = self
+            .rpc_pool
+            .call(|client| async move { client.get_genesis_hash().await })
+            .await?;
+        Ok(Cluster::from_genesis_hash(&genesis_hash))
+    }
+    /// The fee of one transaction running `instructions`, paid by `payer`.
+    pub(crate) async fn estimate_fee(
+        &self,
+        instructions: Vec<Instruction>,
+        payer: &Pubkey,
+    ) -> Result<u64> {
+        let mut transaction_instructions = self.priority_fee_instructions(&instructions).await;
+        transaction_instructions.extend(instructions);
+        self.fee_for_message(&transaction_instructions, payer).await
+    }
+    /// The fee of a transaction of exactly `instructions`, compute budget
+    /// included.
+    pub(crate) async fn fee_for_message(
+        &self,
+        instructions: &[Instruction],
+        payer: &Pubkey,
+    ) -> Result<u64> {
+        let mut message = Message::new(instructions, Some(payer));
+        message.recent_blockhash = self
+            .rpc_pool
+            .call(|client| async move { client.get_latest_blockhash().await })
+            .await?;
+        let message = &message;
+        Ok(self
+            .rpc_pool
+            .call(|client| async move { client.get_fee_for_message(message).await })
+            .await?)
+    }
+    /// The accounts at `addresses`, `None` for the ones which do not exist.
+    pub(crate) async fn get_accounts(&self, addresses: &[Pubkey]) -> Result<Vec<Option<Account>>> {
+        let mut accounts = Vec::with_capacity(addresses.len());
+        // the most getMultipleAccounts takes at once
+        for chunk in addresses.chunks(100) {
+            let found = self
+                .rpc_pool
+                .call(|client| async move { client.get_multiple_accounts(chunk).await })
+                .await?;
+            accounts.extend(found);
+        }
+        Ok(accounts)
+    }
+    /// How many of `accounts` do not exist yet.
+    pub(crate) async fn count_missing(&self, accounts: &[Pubkey]) -> Result<usize> {
+        let found = self.get_accounts(accounts).await?;
+        Ok(found.iter().filter(|account| account.is_none()).count())
+    }
+    /// The lamports an account of `data_len` bytes needs to be rent exempt.
+    pub(crate) async fn minimum_balance(&self, data_len: usize) -> Result<u64> {
+        Ok(self
+            .rpc_pool
+            .call(|client| async move {
+                client
+                    .get_minimum_balance_for_rent_exemption(data_len)
+                    .await
+            })
+            .await?)
+    }
+    /// The rent of `count` new token accounts.
+    pub(crate) async fn token_account_rent(&self, count: usize) -> Result<u64> {
+        if count == 0 {
+            return Ok(0);
+        }
+        let rent = self.minimum_balance(spl_token::state::Account::LEN).await?;
+        Ok(rent * count as u64)
+    }
+}
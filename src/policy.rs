@@ -0,0 +1,334 @@
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::Local;
+use log::error;
+use serde::Deserialize;
+use solana_sdk::pubkey::Pubkey;
+
+use crate::error::{parse_pubkey, Error, Result};
+use crate::outcome::Failure;
+use crate::report::Transfer;
+use crate::Tool;
+
+/// The key of sol in the limits and the day totals, tokens use their mint.
+const SOL: &str = "sol";
+
+/// How much of one asset may go out, in lamports or the raw token amount.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Limits {
+    pub max_per_transfer: Option<u64>,
+    pub max_per_run: Option<u64>,
+    pub max_per_day: Option<u64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    allowed_mints: Vec<String>,
+    #[serde(default)]
+    allowed_destinations: Vec<String>,
+    state_file: Option<String>,
+    #[serde(default)]
+    sol: Limits,
+    /// For every mint without its own limits.
+    #[serde(default)]
+    token: Limits,
+    #[serde(default)]
+    mints: HashMap<String, Limits>,
+}
+
+/// The limits a run has to stay in, checked for the whole batch before the
+/// first transaction is sent.
+pub struct SpendingPolicy {
+    /// The tokens which may be sent, empty for any.
+    pub allowed_mints: Vec<Pubkey>,
+    /// Where a sweep may send to, empty for anywhere.
+    pub allowed_destinations: Vec<Pubkey>,
+    pub sol: Limits,
+    pub token: Limits,
+    pub mints: HashMap<Pubkey, Limits>,
+    /// Keeps what went out per day for `max_per_day`.
+    pub state_file: String,
+    /// What the batches of this run were allowed so far.
+    run_totals: Mutex<HashMap<String, u64>>,
+}
+
+impl SpendingPolicy {
+    /// `~/.solana_tool/spending.json`, or the working folder without a home.
+    pub fn default_state_path() -> String {
+        match env::var("HOME") {
+            Ok(home) => format!("{}/.solana_tool/spending.json", home),
+            Err(_) => "spending.json".to_string(),
+        }
+    }
+    pub fn load(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        let file: PolicyFile = toml::from_str(&content).map_err(|e| {
+            Error::Config(anyhow::anyhow!("invalid spending policy {}: {}", path, e))
+        })?;
+        let parse_all = |inputs: &[String]| {
+            inputs
+                .iter()
+                .map(|input| parse_pubkey(input))
+                .collect::<Result<Vec<_>>>()
+        };
+        let mints = file
+            .mints
+            .iter()
+            .map(|(mint, limits)| Ok((parse_pubkey(mint)?, *limits)))
+            .collect::<Result<HashMap<_, _>>>()?;
+        Ok(Self {
+            allowed_mints: parse_all(&file.allowed_mints)?,
+            allowed_destinations: parse_all(&file.allowed_destinations)?,
+            sol: file.sol,
+            token: file.token,
+            mints,
+            state_file: file.state_file.unwrap_or_else(Self::default_state_path),
+            run_totals: Mutex::new(HashMap::new()),
+        })
+    }
+    fn limits(&self, mint: Option<&Pubkey>) -> Limits {
+        match mint {
+            None => self.sol,
+            Some(mint) => self.mints.get(mint).copied().unwrap_or(self.token),
+        }
+    }
+    /// Check a whole batch, nothing of it may be sent when one transfer or
+    /// the totals break the policy. `sweep` also checks the destination.
+    pub fn authorize(&self, transfers: &[Transfer], sweep: bool) -> Result<()> {
+        let mut batch_totals: HashMap<Option<Pubkey>, u64> = HashMap::new();
+        for transfer in transfers {
+            if let Some(ref mint) = transfer.mint {
+                if !self.allowed_mints.is_empty() && !self.allowed_mints.contains(mint) {
+                    return Err(Error::Policy(format!("the mint {} is not allowed", mint)));
+                }
+            }
+            if sweep
+                && !self.allowed_destinations.is_empty()
+                && !self.allowed_destinations.contains(&transfer.to)
+            {
+                return Err(Error::Policy(format!(
+                    "the destination {} is not allowed",
+                    transfer.to
+                )));
+            }
+            let limits = self.limits(transfer.mint.as_ref());
+            if let Some(max) = limits.max_per_transfer {
+                if transfer.amount > max {
+                    return Err(Error::Policy(format!(
+                        "{} {} from {} to {} is over the limit of {} per transfer",
+                        transfer.amount,
+                        asset_name(transfer.mint.as_ref()),
+                        transfer.from,
+                        transfer.to,
+                        max
+                    )));
+                }
+            }
+            *batch_totals.entry(transfer.mint).or_default() += transfer.amount;
+        }
+        let day_totals = self.read_state()?;
+        let today = today();
+        let mut run_totals = self.run_totals.lock().unwrap();
+        for (mint, amount) in &batch_totals {
+            let key = asset_key(mint.as_ref());
+            let limits = self.limits(mint.as_ref());
+            let run_total = run_totals.get(&key).copied().unwrap_or_default() + amount;
+            if let Some(max) = limits.max_per_run {
+                if run_total > max {
+                    return Err(Error::Policy(format!(
+                        "{} {} in this run is over the limit of {} per run",
+                        run_total,
+                        asset_name(mint.as_ref()),
+                        max
+                    )));
+                }
+            }
+            let sent_today = day_totals
+                .get(&today)
+                .and_then(|totals| totals.get(&key))
+                .copied()
+                .unwrap_or_default();
+            if let Some(max) = limits.max_per_day {
+                if sent_today + amount > max {
+                    return Err(Error::Policy(format!(
+                        "{} {} already sent today, {} more is over the limit of {} per day",
+                        sent_today,
+                        asset_name(mint.as_ref()),
+                        amount,
+                        max
+                    )));
+                }
+            }
+        }
+        for (mint, amount) in batch_totals {
+            *run_totals.entry(asset_key(mint.as_ref())).or_default() += amount;
+        }
+        Ok(())
+    }
+    /// Add what a batch really sent to today's totals.
+    pub fn record(&self, sent: &[Transfer]) -> Result<()> {
+        let mut day_totals = self.read_state()?;
+        let totals = day_totals.entry(today()).or_default();
+        for transfer in sent {
+            *totals.entry(asset_key(transfer.mint.as_ref())).or_default() += transfer.amount;
+        }
+        // only the days a per-day cap still looks at
+        let today = today();
+        day_totals.retain(|day, _| *day == today);
+        let content =
+            serde_json::to_string_pretty(&day_totals).map_err(|e| Error::Policy(e.to_string()))?;
+        if let Some(parent) = Path::new(&self.state_file).parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent).map_err(|e| Error::io(parent.display(), e))?;
+            }
+        }
+        fs::write(&self.state_file, content).map_err(|e| Error::io(&self.state_file, e))
+    }
+    fn read_state(&self) -> Result<BTreeMap<String, HashMap<String, u64>>> {
+        match fs::read_to_string(&self.state_file) {
+            Ok(content) => serde_json::from_str(&content).map_err(|e| {
+                Error::Policy(format!(
+                    "the state file {} is damaged: {}",
+                    self.state_file, e
+                ))
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+            Err(e) => Err(Error::io(&self.state_file, e)),
+        }
+    }
+}
+
+impl Tool {
+    /// Check a batch against the spending policy, when there is one.
+    pub(crate) fn authorize(&self, transfers: &[Transfer], sweep: bool) -> Result<()> {
+        match self.policy {
+            Some(ref policy) => policy.authorize(transfers, sweep),
+            None => Ok(()),
+        }
+    }
+    /// Add what a batch sent to the spending policy's day totals. The daily
+    /// cap stops counting when this fails, so the failure is one of the run.
+    pub(crate) fn record_spending(&self, sent: &[Transfer]) -> Option<Failure> {
+        let policy = self.policy.as_ref()?;
+        let e = policy.record(sent).err()?;
+        error!("Failed to record the spending of this run: {}", e);
+        Some(Failure::new("spending policy", e))
+    }
+}
+
+fn today() -> String {
+    Local::now().format("%Y-%m-%d").to_string()
+}
+
+fn asset_key(mint: Option<&Pubkey>) -> String {
+    mint.map_or_else(|| SOL.to_string(), Pubkey::to_string)
+}
+
+fn asset_name(mint: Option<&Pubkey>) -> String {
+    match mint {
+        None => "lamports".to_string(),
+        Some(mint) => format!("of token {}", mint),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(name: &str) -> SpendingPolicy {
+        let state_file =
+            env::temp_dir().join(format!("solana_tool-{}-{}.json", name, std::process::id()));
+        let _ = fs::remove_file(&state_file);
+        SpendingPolicy {
+            allowed_mints: vec![],
+            allowed_destinations: vec![],
+            sol: Limits::default(),
+            token: Limits::default(),
+            mints: HashMap::new(),
+            state_file: state_file.to_string_lossy().to_string(),
+            run_totals: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn sol(amount: u64) -> Transfer {
+        Transfer {
+            from: Pubkey::new_unique(),
+            to: Pubkey::new_unique(),
+            mint: None,
+            amount,
+        }
+    }
+
+    #[test]
+    fn refuses_a_transfer_over_the_limit() {
+        let mut policy = policy("per-transfer");
+        policy.sol.max_per_transfer = Some(100);
+        assert!(policy.authorize(&[sol(100), sol(50)], false).is_ok());
+        assert!(matches!(
+            policy.authorize(&[sol(50), sol(101)], false),
+            Err(Error::Policy(_))
+        ));
+    }
+
+    #[test]
+    fn limits_of_a_mint_override_the_token_limits() {
+        let mut policy = policy("mints");
+        let (usdc, other) = (Pubkey::new_unique(), Pubkey::new_unique());
+        policy.token.max_per_transfer = Some(10);
+        policy.mints.insert(
+            usdc,
+            Limits {
+                max_per_transfer: Some(1_000),
+                ..Limits::default()
+            },
+        );
+        let token = |mint, amount| Transfer {
+            mint: Some(mint),
+            ..sol(amount)
+        };
+        assert!(policy.authorize(&[token(usdc, 1_000)], false).is_ok());
+        assert!(policy.authorize(&[token(other, 11)], false).is_err());
+        policy.allowed_mints = vec![usdc];
+        assert!(policy.authorize(&[token(other, 1)], false).is_err());
+    }
+
+    #[test]
+    fn checks_the_destination_of_a_sweep_only() {
+        let mut policy = policy("destination");
+        let main = Pubkey::new_unique();
+        policy.allowed_destinations = vec![main];
+        let elsewhere = sol(1);
+        assert!(policy.authorize(&[elsewhere], false).is_ok());
+        assert!(policy.authorize(&[elsewhere], true).is_err());
+        let to_main = Transfer { to: main, ..sol(1) };
+        assert!(policy.authorize(&[to_main], true).is_ok());
+    }
+
+    #[test]
+    fn adds_up_the_batches_of_a_run() {
+        let mut policy = policy("per-run");
+        policy.sol.max_per_run = Some(100);
+        assert!(policy.authorize(&[sol(40), sol(40)], false).is_ok());
+        assert!(policy.authorize(&[sol(30)], false).is_err());
+        // a refused batch does not count
+        assert!(policy.authorize(&[sol(20)], false).is_ok());
+    }
+
+    #[test]
+    fn counts_what_was_sent_today() {
+        let mut policy = policy("per-day");
+        policy.sol.max_per_day = Some(100);
+        assert!(policy.authorize(&[sol(80)], false).is_ok());
+        policy.record(&[sol(80)]).unwrap();
+        assert!(policy.authorize(&[sol(20)], false).is_ok());
+        assert!(policy.authorize(&[sol(21)], false).is_err());
+        let _ = fs::remove_file(&policy.state_file);
+    }
+}
@@ -0,0 +1,285 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use log::{info, warn};
+use solana_client::{
+    client_error::{
+        reqwest::{
+            self,
+            header::{CONTENT_TYPE, RETRY_AFTER},
+            StatusCode,
+        },
+        Result as ClientResult,
+    },
+    rpc_custom_error::{
+        NodeUnhealthyErrorData, JSON_RPC_SERVER_ERROR_NODE_UNHEALTHY,
+        JSON_RPC_SERVER_ERROR_SEND_TRANSACTION_PREFLIGHT_FAILURE,
+    },
+    rpc_request::{RpcError, RpcRequest, RpcResponseErrorData},
+    rpc_response::RpcSimulateTransactionResult,
+    rpc_sender::{RpcSender, RpcTransportStats},
+};
+use solana_rpc_client::http_sender::HttpSender;
+use tokio::sync::{Semaphore, SemaphorePermit};
+use tokio::time::{sleep_until, Duration, Instant};
+
+/// The rate never drops below this, so a long back off still makes progress.
+const MIN_RPS: f64 = 1.0;
+/// Successful requests in a row before the rate is raised again.
+const RECOVER_AFTER: u32 = 20;
+/// Pause used for a 429 which came back without a `Retry-After` header.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_millis(500);
+/// Same cap the http sender puts on `Retry-After`.
+const MAX_RETRY_AFTER: Duration = Duration::from_secs(120);
+/// How many times a request answered 429 is tried again, as the http sender
+/// does.
+const TOO_MANY_REQUESTS_RETRIES: u32 = 5;
+/// Same timeout the http sender uses.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+struct LimiterState {
+    rps: f64,
+    next_slot: Instant,
+    successes: u32,
+    /// The end of the last pause, the 429s of requests sent before it are
+    /// part of the same throttle.
+    paused_until: Instant,
+}
+
+/// One limiter per rpc endpoint, shared by every request sent to it. It caps
+/// the number of requests in flight and paces them to a requests-per-second rate, halving
+/// the rate when the endpoint answers 429 and slowly raising it back to the
+/// configured maximum while the endpoint is healthy.
+pub struct RateLimiter {
+    semaphore: Semaphore,
+    max_rps: f64,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new(max_rps: u32, max_concurrency: usize) -> Self {
+        let max_rps = (max_rps as f64).max(MIN_RPS);
+        Self {
+            semaphore: Semaphore::new(max_concurrency.max(1)),
+            max_rps,
+            state: Mutex::new(LimiterState {
+                rps: max_rps,
+                next_slot: Instant::now(),
+                successes: 0,
+                paused_until: Instant::now(),
+            }),
+        }
+    }
+    /// Wait for a free request slot, the permit must be held until the
+    /// request is answered.
+    pub async fn acquire(&self) -> SemaphorePermit<'_> {
+        let permit = self
+            .semaphore
+            .acquire()
+            .await
+            .expect("the rate limiter semaphore is never closed");
+        let slot = {
+            let mut state = self.state.lock().unwrap();
+            let slot = state.next_slot.max(Instant::now());
+            state.next_slot = slot + Duration::from_secs_f64(1.0 / state.rps);
+            slot
+        };
+        sleep_until(slot).await;
+        permit
+    }
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.successes += 1;
+        if state.successes >= RECOVER_AFTER && state.rps < self.max_rps {
+            state.successes = 0;
+            state.rps = (state.rps + self.max_rps / 10.0).min(self.max_rps);
+            if state.rps >= self.max_rps {
+                info!("Rpc rate is back to {:.1} requests per second", state.rps);
+            }
+        }
+    }
+    /// Halve the rate and hold every request back for `retry_after`. The
+    /// other 429s of the same pause leave the rate as it is, they come from
+    /// the same throttle.
+    pub fn back_off(&self, retry_after: Duration) {
+        let retry_after = retry_after.min(MAX_RETRY_AFTER);
+        let now = Instant::now();
+        let mut state = self.state.lock().unwrap();
+        state.successes = 0;
+        if now < state.paused_until {
+            return;
+        }
+        state.rps = (state.rps / 2.0).max(MIN_RPS);
+        state.paused_until = now + retry_after;
+        state.next_slot = state.next_slot.max(state.paused_until);
+        warn!(
+            "Rpc endpoint is rate limiting, slow down to {:.1} requests per second and pause for {:?}",
+            state.rps, retry_after
+        );
+    }
+}
+
+/// Http transport which sends every request through the shared limiter. It
+/// posts the requests itself instead of going through the http sender, which
+/// sleeps out a 429 on its own: here the 429 of a request is seen by that
+/// request and the limiter is the only one pausing for it.
+pub struct LimitedSender {
+    client: reqwest::Client,
+    url: String,
+    request_id: AtomicU64,
+    stats: Mutex<RpcTransportStats>,
+    limiter: Arc<RateLimiter>,
+}
+
+impl LimitedSender {
+    pub fn new(url: String, limiter: Arc<RateLimiter>) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .default_headers(HttpSender::default_headers())
+                .timeout(REQUEST_TIMEOUT)
+                .pool_idle_timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("build rpc client"),
+            url,
+            request_id: AtomicU64::new(0),
+            stats: Mutex::new(RpcTransportStats::default()),
+            limiter,
+        }
+    }
+    /// Post the request once, `Ok(Err(retry_after))` when the endpoint
+    /// answered 429.
+    async fn post(&self, body: &str) -> ClientResult<Result<serde_json::Value, Duration>> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(body.to_string())
+            .send()
+            .await?;
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response
+                .headers()
+                .get(RETRY_AFTER)
+                .and_then(|retry_after| retry_after.to_str().ok())
+                .and_then(|retry_after| retry_after.parse::<u64>().ok())
+                .map_or(DEFAULT_RETRY_AFTER, Duration::from_secs);
+            return Ok(Err(retry_after));
+        }
+        let response = response.error_for_status()?;
+        let mut json = response.json::<serde_json::Value>().await?;
+        if json["error"].is_object() {
+            return Err(rpc_error(&json["error"]).into());
+        }
+        Ok(Ok(json["result"].take()))
+    }
+}
+
+/// The error object of a JSON-RPC response, decoded the way the http sender
+/// does it so callers still see preflight failures and unhealthy nodes.
+fn rpc_error(error: &serde_json::Value) -> RpcError {
+    let (Some(code), Some(message)) = (error["code"].as_i64(), error["message"].as_str()) else {
+        return RpcError::RpcRequestError(format!(
+            "Failed to deserialize RPC error response: {}",
+            error
+        ));
+    };
+    let data = match code {
+        JSON_RPC_SERVER_ERROR_SEND_TRANSACTION_PREFLIGHT_FAILURE => {
+            serde_json::from_value::<RpcSimulateTransactionResult>(error["data"].clone())
+                .map_or(RpcResponseErrorData::Empty, |data| {
+                    RpcResponseErrorData::SendTransactionPreflightFailure(data)
+                })
+        }
+        JSON_RPC_SERVER_ERROR_NODE_UNHEALTHY => {
+            serde_json::from_value::<NodeUnhealthyErrorData>(error["data"].clone()).map_or(
+                RpcResponseErrorData::Empty,
+                |NodeUnhealthyErrorData { num_slots_behind }| RpcResponseErrorData::NodeUnhealthy {
+                    num_slots_behind,
+                },
+            )
+        }
+        _ => RpcResponseErrorData::Empty,
+    };
+    RpcError::RpcResponseError {
+        code,
+        message: message.to_string(),
+        data,
+    }
+}
+
+#[async_trait]
+impl RpcSender for LimitedSender {
+    async fn send(
+        &self,
+        request: RpcRequest,
+        params: serde_json::Value,
+    ) -> ClientResult<serde_json::Value> {
+        let start = Instant::now();
+        let mut rate_limited_time = Duration::ZERO;
+        let request_id = self.request_id.fetch_add(1, Ordering::Relaxed);
+        let body = request.build_request_json(request_id, params).to_string();
+        let mut retries = TOO_MANY_REQUESTS_RETRIES;
+        let result = loop {
+            let permit = self.limiter.acquire().await;
+            let result = self.post(&body).await;
+            drop(permit);
+            match result {
+                Ok(Err(retry_after)) if retries > 0 => {
+                    retries -= 1;
+                    // the next acquire waits the pause out together with
+                    // every other request
+                    self.limiter.back_off(retry_after);
+                    rate_limited_time += retry_after.min(MAX_RETRY_AFTER);
+                }
+                Ok(Err(_)) => {
+                    break Err(RpcError::RpcRequestError(format!(
+                        "{} is still rate limiting after {} retries",
+                        self.url, TOO_MANY_REQUESTS_RETRIES
+                    ))
+                    .into())
+                }
+                Ok(Ok(value)) => {
+                    self.limiter.record_success();
+                    break Ok(value);
+                }
+                Err(e) => break Err(e),
+            }
+        };
+        let mut stats = self.stats.lock().unwrap();
+        stats.request_count += 1;
+        stats.elapsed_time += start.elapsed();
+        stats.rate_limited_time += rate_limited_time;
+        result
+    }
+    fn get_transport_stats(&self) -> RpcTransportStats {
+        self.stats.lock().unwrap().clone()
+    }
+    fn url(&self) -> String {
+        self.url.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_throttle_halves_the_rate_once() {
+        let limiter = RateLimiter::new(16, 8);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| limiter.back_off(Duration::from_secs(1)));
+            }
+        });
+        assert_eq!(limiter.state.lock().unwrap().rps, 8.0);
+    }
+
+    #[test]
+    fn a_429_after_the_pause_halves_again() {
+        let limiter = RateLimiter::new(16, 8);
+        limiter.back_off(Duration::ZERO);
+        limiter.back_off(Duration::ZERO);
+        assert_eq!(limiter.state.lock().unwrap().rps, 4.0);
+    }
+}
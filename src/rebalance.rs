@@ -0,0 +1,422 @@
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+
+use anyhow::anyhow;
+use log::{error, info, warn};
+use solana_program::program_pack::Pack;
+use solana_sdk::pubkey::Pubkey;
+use spl_associated_token_account::get_associated_token_address;
+
+use crate::error::{Error, Result};
+use crate::plan::RunSummary;
+use crate::report::{join_entries, Status, Transfer, TransferEntry, TransferReport};
+use crate::send_and_check::token_transfer_instructions;
+use crate::wallet_set::WalletSet;
+use crate::Tool;
+
+/// Even out the sol, or a token, of a set of wallets by moving it between
+/// them, the main wallet is not involved.
+#[derive(Default)]
+pub struct Rebalance {
+    target: Option<u64>,
+    token: Option<(Pubkey, u8)>,
+}
+
+/// What one wallet holds before the rebalance.
+struct Holding {
+    owner: Pubkey,
+    balance: u64,
+    /// Whether the token account exists, always true for sol.
+    has_account: bool,
+}
+
+impl Rebalance {
+    /// Give every wallet an equal share of what they hold together.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Bring every wallet to this amount instead, lamports or the raw token
+    /// amount. Wallets are filled as far as the surplus of the others goes.
+    pub fn target(mut self, amount: u64) -> Self {
+        self.target = Some(amount);
+        self
+    }
+    /// Rebalance this spl token instead of sol.
+    pub fn token(mut self, mint: Pubkey, decimals: u8) -> Self {
+        self.token = Some((mint, decimals));
+        self
+    }
+    async fn holdings(&self, tool: &Tool, wallets: &WalletSet) -> Result<Vec<Holding>> {
+        let mint = self.token.map(|(mint, _)| mint);
+        let addresses: Vec<Pubkey> = wallets
+            .iter()
+            .map(|wallet| match mint {
+                Some(ref mint) => get_associated_token_address(&wallet.pubkey(), mint),
+                None => wallet.pubkey(),
+            })
+            .collect();
+        let accounts = tool.get_accounts(&addresses).await?;
+        let mut holdings = Vec::with_capacity(accounts.len());
+        for (wallet, (address, account)) in wallets.iter().zip(addresses.iter().zip(accounts)) {
+            let balance = match (mint, account.as_ref()) {
+                (_, None) => 0,
+                (None, Some(account)) => account.lamports,
+                (Some(_), Some(account)) => {
+                    let state =
+                        spl_token::state::Account::unpack(&account.data).map_err(|source| {
+                            Error::NotTokenAccount {
+                                account: address.to_string(),
+                                source,
+                            }
+                        })?;
+                    // a frozen account can neither give nor take
+                    if state.is_frozen() {
+                        warn!(
+                            "The token account {} of {} is frozen, leave it out",
+                            address,
+                            wallet.pubkey()
+                        );
+                        continue;
+                    }
+                    state.amount
+                }
+            };
+            holdings.push(Holding {
+                owner: wallet.pubkey(),
+                balance,
+                has_account: mint.is_none() || account.is_some(),
+            });
+        }
+        Ok(holdings)
+    }
+    /// Match the biggest surplus with the biggest shortfall until one side
+    /// runs out, at most one transfer less than the wallets involved.
+    /// `fee` is kept back by a sender of sol for every transfer it sends.
+    /// Wallets which already have the token account are filled first.
+    fn transfers(&self, holdings: &[Holding], fee: u64) -> Vec<Transfer> {
+        let targets: Vec<u64> = match self.target {
+            Some(target) => vec![target; holdings.len()],
+            None => {
+                let total: u64 = holdings.iter().map(|holding| holding.balance).sum();
+                let count = holdings.len() as u64;
+                let mut targets = vec![total / count; holdings.len()];
+                // the remainder stays with the richest, they are the ones giving
+                let mut richest: Vec<usize> = (0..holdings.len()).collect();
+                richest.sort_by_key(|&index| Reverse(holdings[index].balance));
+                for &index in richest.iter().take((total % count) as usize) {
+                    targets[index] += 1;
+                }
+                targets
+            }
+        };
+        let mut surplus: Vec<(usize, u64)> = vec![];
+        let mut shortfall: Vec<(usize, u64)> = vec![];
+        for (index, (holding, target)) in holdings.iter().zip(targets).enumerate() {
+            if holding.balance > target + fee {
+                surplus.push((index, holding.balance - target));
+            } else if holding.balance < target {
+                shortfall.push((index, target - holding.balance));
+            }
+        }
+        surplus.sort_by_key(|&(_, amount)| Reverse(amount));
+        shortfall.sort_by_key(|&(index, amount)| {
+            (Reverse(holdings[index].has_account), Reverse(amount))
+        });
+        let mut transfers = vec![];
+        let (mut giver, mut taker) = (0, 0);
+        while giver < surplus.len() && taker < shortfall.len() {
+            // what is left of the surplus has to pay the fee of one more transfer
+            if surplus[giver].1 <= fee {
+                giver += 1;
+                continue;
+            }
+            let amount = (surplus[giver].1 - fee).min(shortfall[taker].1);
+            transfers.push(Transfer {
+                from: holdings[surplus[giver].0].owner,
+                to: holdings[shortfall[taker].0].owner,
+                mint: self.token.map(|(mint, _)| mint),
+                amount,
+            });
+            surplus[giver].1 -= amount + fee;
+            shortfall[taker].1 -= amount;
+            if shortfall[taker].1 == 0 {
+                taker += 1;
+            }
+        }
+        transfers
+    }
+    /// Work out the transfers, nothing is sent. Pass the result to `run`.
+    pub async fn plan(&self, tool: &Tool, wallets: &WalletSet) -> Result<RunSummary> {
+        let minimum = match self.token {
+            Some(_) => 0,
+            None => tool.minimum_balance(0).await?,
+        };
+        if let Some(target) = self.target.filter(|target| *target < minimum) {
+            return Err(anyhow!(
+                "a target of {} lamports is less than the rent-exempt minimum of {}",
+                target,
+                minimum
+            )
+            .into());
+        }
+        let holdings = self.holdings(tool, wallets).await?;
+        let mut summary = RunSummary {
+            cluster: tool.cluster().await?,
+            recipients: 0,
+            total_amount: 0,
+            mint: self.token.map(|(mint, _)| mint),
+            decimals: self.token.map_or(9, |(_, decimals)| decimals),
+            accounts_to_create: 0,
+            account_rent: 0,
+            estimated_fee: 0,
+            allocation: vec![],
+            transfers: vec![],
+            frozen: vec![],
+        };
+        let [first, second, ..] = holdings.as_slice() else {
+            return Ok(summary);
+        };
+        let (sample, fee) = match self.token {
+            Some((mint, decimals)) => {
+                let instructions = token_transfer_instructions(
+                    &first.owner,
+                    &first.owner,
+                    &second.owner,
+                    &mint,
+                    0,
+                    decimals,
+                )?;
+                (instructions, 0)
+            }
+            None => {
+                let instructions = vec![solana_sdk::system_instruction::transfer(
+                    &first.owner,
+                    &second.owner,
+                    0,
+                )];
+                let fee = tool
+                    .estimate_fee(instructions.clone(), &first.owner)
+                    .await?;
+                (instructions, fee)
+            }
+        };
+        let transfers = self.transfers(&holdings, fee);
+        if transfers.is_empty() {
+            return Ok(summary);
+        }
+        check_rent(&holdings, &transfers, fee, minimum)?;
+        let receivers: HashSet<Pubkey> = transfers.iter().map(|transfer| transfer.to).collect();
+        summary.recipients = receivers.len();
+        summary.total_amount = transfers.iter().map(|transfer| transfer.amount).sum();
+        summary.accounts_to_create = holdings
+            .iter()
+            .filter(|holding| !holding.has_account && receivers.contains(&holding.owner))
+            .count();
+        summary.account_rent = tool.token_account_rent(summary.accounts_to_create).await?;
+        summary.estimated_fee +=
+            tool.estimate_fee(sample, &first.owner).await? * transfers.len() as u64;
+        summary.transfers = transfers;
+        Ok(summary)
+    }
+    /// Send the transfers `plan` worked out for the same wallets, every
+    /// sender pays its own fee.
+    pub async fn run(
+        &self,
+        tool: &Tool,
+        wallets: &WalletSet,
+        plan: &RunSummary,
+    ) -> Result<TransferReport> {
+        tool.authorize(&plan.transfers, false)?;
+        let signers: HashMap<Pubkey, _> = wallets
+            .iter()
+            .map(|wallet| (wallet.pubkey(), wallet.signer.clone()))
+            .collect();
+        let mut tasks = vec![];
+        for transfer in plan.transfers.iter().copied() {
+            let (Some(sender), true) = (
+                signers.get(&transfer.from).cloned(),
+                signers.contains_key(&transfer.to),
+            ) else {
+                warn!(
+                    "The planned transfer from {} to {} is not between these wallets, skip it",
+                    transfer.from, transfer.to
+                );
+                continue;
+            };
+            let tool = tool.clone();
+            let token = self.token;
+            let task = tokio::spawn(async move {
+                let instructions = match token {
+                    Some((mint, decimals)) => token_transfer_instructions(
+                        &transfer.from,
+                        &transfer.from,
+                        &transfer.to,
+                        &mint,
+                        transfer.amount,
+                        decimals,
+                    ),
+                    None => Ok(vec![solana_sdk::system_instruction::transfer(
+                        &transfer.from,
+                        &transfer.to,
+                        transfer.amount,
+                    )]),
+                };
+                let result = match instructions {
+                    Ok(instructions) => {
+                        tool.sendtxn_and_watch(instructions, &*sender, &*sender)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(ref signature) => info!(
+                        "Successfuly moved {} from {} to {}, check the info: {}",
+                        transfer.amount,
+                        transfer.from,
+                        transfer.to,
+                        tool.explorer_link(signature)
+                    ),
+                    Err(ref e) => error!(
+                        "Failed to move {} from {} to {} with error: {}",
+                        transfer.amount, transfer.from, transfer.to, e
+                    ),
+                }
+                let entry = TransferEntry {
+                    transfer,
+                    status: Status::of(transfer.from, result),
+                };
+                tool.output.emit(entry.record());
+                entry
+            });
+            tasks.push((transfer, task));
+        }
+        let transfers = join_entries(tasks, |transfer, failure| TransferEntry {
+            transfer,
+            status: Status::Failed(failure),
+        })
+        .await;
+        let mut report = TransferReport {
+            transfers,
+            failures: vec![],
+        };
+        let sent = report.sent();
+        report.failures.extend(tool.record_spending(&sent));
+        Ok(report)
+    }
+}
+
+/// Refuse transfers of sol which leave a sender with less than the
+/// rent-exempt `minimum`, or open a new account with less. The transfers
+/// land in any order, so each one to a new account has to cover the rent.
+fn check_rent(holdings: &[Holding], transfers: &[Transfer], fee: u64, minimum: u64) -> Result<()> {
+    let mut left: HashMap<Pubkey, u64> = holdings
+        .iter()
+        .map(|holding| (holding.owner, holding.balance))
+        .collect();
+    for transfer in transfers {
+        if left.get(&transfer.to) == Some(&0) && transfer.amount < minimum {
+            return Err(Error::BelowRentExemption {
+                account: transfer.to.to_string(),
+                lamports: transfer.amount,
+                minimum,
+            });
+        }
+    }
+    for transfer in transfers {
+        if let Some(balance) = left.get_mut(&transfer.from) {
+            *balance = balance.saturating_sub(transfer.amount + fee);
+        }
+    }
+    for transfer in transfers {
+        let lamports = left[&transfer.from];
+        if lamports > 0 && lamports < minimum {
+            return Err(Error::BelowRentExemption {
+                account: transfer.from.to_string(),
+                lamports,
+                minimum,
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn holdings(balances: &[u64]) -> Vec<Holding> {
+        balances
+            .iter()
+            .map(|balance| Holding {
+                owner: Pubkey::new_unique(),
+                balance: *balance,
+                has_account: true,
+            })
+            .collect()
+    }
+
+    fn moved(holdings: &[Holding], transfers: &[Transfer]) -> Vec<(usize, usize, u64)> {
+        let index = |owner| {
+            holdings
+                .iter()
+                .position(|holding| holding.owner == owner)
+                .unwrap()
+        };
+        transfers
+            .iter()
+            .map(|transfer| (index(transfer.from), index(transfer.to), transfer.amount))
+            .collect()
+    }
+
+    #[test]
+    fn the_remainder_stays_with_the_richest() {
+        let holdings = holdings(&[2, 11, 0]);
+        let transfers = Rebalance::new().transfers(&holdings, 0);
+        // 13 over 3 is 4 each and the 1 left stays with the giver, the
+        // biggest shortfall is filled first
+        assert_eq!(moved(&holdings, &transfers), [(1, 2, 4), (1, 0, 2)]);
+    }
+
+    #[test]
+    fn a_giver_keeps_back_a_fee_per_transfer() {
+        let holdings = holdings(&[100, 0, 0]);
+        let transfers = Rebalance::new().target(30).transfers(&holdings, 5);
+        assert_eq!(moved(&holdings, &transfers), [(0, 1, 30), (0, 2, 30)]);
+        // 40 over the target only pays for one fee and 30 more after it
+        let holdings = self::holdings(&[70, 0, 0]);
+        let transfers = Rebalance::new().target(30).transfers(&holdings, 5);
+        assert_eq!(moved(&holdings, &transfers), [(0, 1, 30)]);
+        let holdings = self::holdings(&[70, 0, 0]);
+        let transfers = Rebalance::new().target(20).transfers(&holdings, 5);
+        // 50 over the target, 20 and 20 plus two fees
+        assert_eq!(moved(&holdings, &transfers), [(0, 1, 20), (0, 2, 20)]);
+    }
+
+    #[test]
+    fn the_wallets_with_an_account_are_filled_first() {
+        let mut holdings = holdings(&[0, 5, 0, 20]);
+        holdings[0].has_account = false;
+        let transfers = Rebalance::new().target(10).transfers(&holdings, 0);
+        // the biggest shortfall with an account first, then the new account
+        assert_eq!(moved(&holdings, &transfers), [(3, 2, 10)]);
+        let transfers = Rebalance::new().target(8).transfers(&holdings, 0);
+        assert_eq!(
+            moved(&holdings, &transfers),
+            [(3, 2, 8), (3, 1, 3), (3, 0, 1)]
+        );
+    }
+
+    #[test]
+    fn refuses_to_leave_an_account_below_the_rent() {
+        let holdings = holdings(&[100, 0]);
+        let transfers = Rebalance::new().target(50).transfers(&holdings, 0);
+        assert!(check_rent(&holdings, &transfers, 0, 50).is_ok());
+        assert!(matches!(
+            check_rent(&holdings, &transfers, 0, 51),
+            Err(Error::BelowRentExemption { lamports: 50, .. })
+        ));
+        let transfers = Rebalance::new().target(40).transfers(&holdings, 0);
+        // the giver keeps 60, the new account gets less than the rent
+        assert!(check_rent(&holdings, &transfers, 0, 45).is_err());
+    }
+}
@@ -0,0 +1,295 @@
+use futures::future::join_all;
+use solana_sdk::{pubkey::Pubkey, signature::Signature};
+use tokio::task::JoinHandle;
+
+use crate::error::Error;
+use crate::outcome::{Failure, Outcome, Step};
+use crate::output::Record;
+
+/// How one transaction of a batch went.
+#[derive(Debug, Clone)]
+pub enum Status {
+    Sent(Signature),
+    /// Nothing to do, e.g. an empty wallet.
+    Skipped,
+    Failed(Failure),
+}
+
+impl Status {
+    pub fn of(target: impl std::fmt::Display, result: Result<Signature, Error>) -> Self {
+        match result {
+            Ok(signature) => Status::Sent(signature),
+            Err(e) => Status::Failed(Failure::new(target, e)),
+        }
+    }
+    pub fn signature(&self) -> Option<&Signature> {
+        match self {
+            Status::Sent(signature) => Some(signature),
+            _ => None,
+        }
+    }
+    fn error(&self) -> Option<String> {
+        match self {
+            Status::Failed(failure) => Some(failure.error.clone()),
+            _ => None,
+        }
+    }
+    fn step(self) -> Step {
+        match self {
+            Status::Sent(_) => Step::Succeeded,
+            Status::Skipped => Step::Skipped,
+            Status::Failed(failure) => Step::Failed(failure),
+        }
+    }
+}
+
+/// One transfer of a batch, before it is sent.
+#[derive(Debug, Clone, Copy)]
+pub struct Transfer {
+    pub from: Pubkey,
+    pub to: Pubkey,
+    /// `None` for sol.
+    pub mint: Option<Pubkey>,
+    /// Lamports, or the raw token amount.
+    pub amount: u64,
+}
+
+#[derive(Debug)]
+pub struct TransferEntry {
+    pub transfer: Transfer,
+    pub status: Status,
+}
+
+impl TransferEntry {
+    pub(crate) fn record(&self) -> Record {
+        Record::Transfer {
+            from: self.transfer.from.to_string(),
+            to: self.transfer.to.to_string(),
+            mint: self.transfer.mint.map(|mint| mint.to_string()),
+            amount: self.transfer.amount,
+            signature: self.status.signature().map(Signature::to_string),
+            error: self.status.error(),
+        }
+    }
+}
+
+/// What a `BatchTransfer`, `Sweep` or `TokenSweep` did, one entry per transfer.
+#[derive(Debug, Default)]
+pub struct TransferReport {
+    pub transfers: Vec<TransferEntry>,
+    /// Wallets whose token accounts could not be listed.
+    pub failures: Vec<Failure>,
+}
+
+impl TransferReport {
+    /// The transfers which were sent.
+    pub fn sent(&self) -> Vec<Transfer> {
+        self.transfers
+            .iter()
+            .filter(|entry| matches!(entry.status, Status::Sent(_)))
+            .map(|entry| entry.transfer)
+            .collect()
+    }
+    /// Lamports or token amount which arrived.
+    pub fn total_sent(&self) -> u64 {
+        self.transfers
+            .iter()
+            .filter(|entry| matches!(entry.status, Status::Sent(_)))
+            .map(|entry| entry.transfer.amount)
+            .sum()
+    }
+    pub fn outcome(self) -> Outcome {
+        outcome(
+            self.failures,
+            self.transfers.into_iter().map(|entry| entry.status),
+        )
+    }
+}
+
+#[derive(Debug)]
+pub struct CloseEntry {
+    pub wallet: Pubkey,
+    pub account: Pubkey,
+    pub status: Status,
+}
+
+impl CloseEntry {
+    pub(crate) fn record(&self) -> Record {
+        Record::Close {
+            wallet: self.wallet.to_string(),
+            account: self.account.to_string(),
+            signature: self.status.signature().map(Signature::to_string),
+            error: self.status.error(),
+        }
+    }
+}
+
+/// What a `CloseAccounts` did, one entry per token account.
+#[derive(Debug, Default)]
+pub struct CloseReport {
+    pub accounts: Vec<CloseEntry>,
+    /// Wallets whose token accounts could not be listed.
+    pub failures: Vec<Failure>,
+}
+
+impl CloseReport {
+    pub fn outcome(self) -> Outcome {
+        outcome(
+            self.failures,
+            self.accounts.into_iter().map(|entry| entry.status),
+        )
+    }
+}
+
+/// One approve or revoke of a batch, `delegate` and `amount` are what the
+/// account allows once it landed.
+#[derive(Debug)]
+pub struct ApprovalEntry {
+    pub wallet: Pubkey,
+    pub account: Pubkey,
+    pub mint: Pubkey,
+    pub delegate: Option<Pubkey>,
+    pub amount: u64,
+    pub status: Status,
+}
+
+impl ApprovalEntry {
+    pub(crate) fn record(&self) -> Record {
+        Record::Approval {
+            wallet: self.wallet.to_string(),
+            account: self.account.to_string(),
+            mint: self.mint.to_string(),
+            delegate: self.delegate.map(|delegate| delegate.to_string()),
+            amount: self.amount,
+            signature: self.status.signature().map(Signature::to_string),
+            error: self.status.error(),
+        }
+    }
+}
+
+/// What an `Approve` or `Revoke` did, one entry per token account.
+#[derive(Debug, Default)]
+pub struct ApprovalReport {
+    pub approvals: Vec<ApprovalEntry>,
+    /// Wallets whose token accounts could not be listed.
+    pub failures: Vec<Failure>,
+}
+
+impl ApprovalReport {
+    pub fn outcome(self) -> Outcome {
+        outcome(
+            self.failures,
+            self.approvals.into_iter().map(|entry| entry.status),
+        )
+    }
+}
+
+/// One freeze or thaw of a batch, `frozen` is the state the account is put
+/// in.
+#[derive(Debug)]
+pub struct FreezeEntry {
+    pub wallet: Pubkey,
+    pub account: Pubkey,
+    pub mint: Pubkey,
+    pub frozen: bool,
+    pub status: Status,
+}
+
+impl FreezeEntry {
+    pub(crate) fn record(&self) -> Record {
+        Record::Freeze {
+            wallet: self.wallet.to_string(),
+            account: self.account.to_string(),
+            mint: self.mint.to_string(),
+            frozen: self.frozen,
+            signature: self.status.signature().map(Signature::to_string),
+            error: self.status.error(),
+        }
+    }
+}
+
+/// What a `Freeze` did, one entry per token account, the ones already in
+/// the state are skipped.
+#[derive(Debug, Default)]
+pub struct FreezeReport {
+    pub accounts: Vec<FreezeEntry>,
+    /// Wallets whose token accounts could not be listed.
+    pub failures: Vec<Failure>,
+}
+
+impl FreezeReport {
+    pub fn outcome(self) -> Outcome {
+        outcome(
+            self.failures,
+            self.accounts.into_iter().map(|entry| entry.status),
+        )
+    }
+}
+
+/// One create, delegate, deactivate or withdraw of a stake account.
+#[derive(Debug)]
+pub struct StakeEntry {
+    pub action: &'static str,
+    pub wallet: Pubkey,
+    /// `None` when a new account could not be created.
+    pub stake_account: Option<Pubkey>,
+    /// What the account holds, or is created with.
+    pub lamports: u64,
+    pub status: Status,
+}
+
+impl StakeEntry {
+    pub(crate) fn record(&self) -> Record {
+        Record::Stake {
+            action: self.action.to_string(),
+            wallet: self.wallet.to_string(),
+            stake_account: self.stake_account.map(|account| account.to_string()),
+            lamports: self.lamports,
+            signature: self.status.signature().map(Signature::to_string),
+            error: self.status.error(),
+        }
+    }
+}
+
+/// What a `CreateStake` or `ManageStake` did, one entry per stake account.
+#[derive(Debug, Default)]
+pub struct StakeReport {
+    pub entries: Vec<StakeEntry>,
+    /// Wallets whose stake accounts could not be listed.
+    pub failures: Vec<Failure>,
+}
+
+impl StakeReport {
+    pub fn outcome(self) -> Outcome {
+        outcome(
+            self.failures,
+            self.entries.into_iter().map(|entry| entry.status),
+        )
+    }
+}
+
+/// The outcome of a batch, a step per entry on top of the failures which
+/// have no entry.
+fn outcome(failures: Vec<Failure>, statuses: impl Iterator<Item = Status>) -> Outcome {
+    let mut outcome = Outcome::from(failures);
+    for status in statuses {
+        outcome.add(status.step());
+    }
+    outcome
+}
+
+/// Wait for the spawned tasks, `panicked` makes the entry of a task which
+/// died from its key.
+pub(crate) async fn join_entries<K, T>(
+    tasks: Vec<(K, JoinHandle<T>)>,
+    panicked: impl Fn(K, Failure) -> T,
+) -> Vec<T> {
+    let (keys, handles): (Vec<K>, Vec<JoinHandle<T>>) = tasks.into_iter().unzip();
+    keys.into_iter()
+        .zip(join_all(handles).await)
+        .map(|(key, result)| match result {
+            Ok(entry) => entry,
+            Err(e) => panicked(key, Failure::internal(e)),
+        })
+        .collect()
+}
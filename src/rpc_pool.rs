@@ -0,0 +1,594 @@
+use std::fs;
+use std::future::Future;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use anyhow::{bail, Context, Result};
+use futures::future::join_all;
+use futures::stream::{BoxStream, StreamExt};
+use log::{info, warn};
+use solana_client::{
+    client_error::{ClientError, ClientErrorKind, Result as ClientResult},
+    nonblocking::{pubsub_client::PubsubClient, rpc_client::RpcClient},
+    rpc_config::RpcSignatureSubscribeConfig,
+    rpc_request::RpcError,
+    rpc_response::{Response as RpcResponse, RpcSignatureResult},
+};
+use solana_rpc_client::rpc_client::RpcClientConfig;
+use solana_sdk::{
+    commitment_config::CommitmentConfig,
+    hash::Hash,
+    signature::Signature,
+    transaction::{Result as TransactionResult, Transaction},
+};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout, Duration};
+
+use crate::rate_limiter::{LimitedSender, RateLimiter};
+
+/// How many failed calls in a row take an endpoint out of rotation until the
+/// next health check brings it back.
+const MAX_CONSECUTIVE_ERRORS: u32 = 3;
+/// JSON-RPC code returned by a node which is behind the cluster.
+const NODE_UNHEALTHY_CODE: i64 = -32005;
+/// How often a transaction watched over the websocket checks that its
+/// blockhash is still valid.
+const BLOCKHASH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+const WEBSOCKET_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// The highest `#WEIGHT` of an endpoint, the schedule has one entry per unit
+/// of weight.
+const MAX_WEIGHT: u32 = 100;
+
+/// One `--rpc` entry, written as `URL` or `URL#WEIGHT`.
+#[derive(Debug, Clone)]
+pub struct EndpointSpec {
+    pub url: String,
+    pub weight: u32,
+}
+
+impl FromStr for EndpointSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let (url, weight) = match s.rsplit_once('#') {
+            Some((url, weight)) => (
+                url,
+                weight
+                    .trim()
+                    .parse::<u32>()
+                    .with_context(|| format!("invalid weight in rpc endpoint {}", s))?,
+            ),
+            None => (s, 1),
+        };
+        if url.is_empty() {
+            bail!("empty rpc endpoint url");
+        }
+        if weight == 0 || weight > MAX_WEIGHT {
+            bail!(
+                "rpc endpoint {} must have a weight from 1 to {}",
+                url,
+                MAX_WEIGHT
+            );
+        }
+        Ok(Self {
+            url: url.to_string(),
+            weight,
+        })
+    }
+}
+
+pub struct RpcEndpoint {
+    pub url: String,
+    pub weight: u32,
+    pub client: Arc<RpcClient>,
+    healthy: AtomicBool,
+    slot: AtomicU64,
+    errors: AtomicU64,
+    consecutive_errors: AtomicU32,
+}
+
+impl RpcEndpoint {
+    fn new(spec: EndpointSpec, commitment: CommitmentConfig, limiter: Arc<RateLimiter>) -> Self {
+        let client = RpcClient::new_sender(
+            LimitedSender::new(spec.url.clone(), limiter),
+            RpcClientConfig::with_commitment(commitment),
+        );
+        Self {
+            url: spec.url,
+            weight: spec.weight,
+            client: Arc::new(client),
+            healthy: AtomicBool::new(true),
+            slot: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            consecutive_errors: AtomicU32::new(0),
+        }
+    }
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+    fn record_success(&self) {
+        self.consecutive_errors.store(0, Ordering::Relaxed);
+    }
+    fn record_error(&self, e: &ClientError) {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        let consecutive = self.consecutive_errors.fetch_add(1, Ordering::Relaxed) + 1;
+        if consecutive >= MAX_CONSECUTIVE_ERRORS && self.healthy.swap(false, Ordering::Relaxed) {
+            warn!(
+                "Rpc endpoint {} failed {} times in a row, take it out of rotation, the last error is {}",
+                self.url, consecutive, e
+            );
+        }
+    }
+}
+
+/// A set of rpc endpoints used as one client: reads are spread over the
+/// healthy endpoints by weight and fail over to the next one, transactions
+/// are sent to every healthy endpoint.
+pub struct RpcPool {
+    endpoints: Vec<Arc<RpcEndpoint>>,
+    schedule: Vec<usize>,
+    cursor: AtomicUsize,
+    max_slot_lag: u64,
+    commitment: CommitmentConfig,
+    pubsub: Option<Arc<PubsubClient>>,
+}
+
+impl RpcPool {
+    pub fn new(
+        specs: Vec<EndpointSpec>,
+        commitment: CommitmentConfig,
+        max_slot_lag: u64,
+        rps: u32,
+        concurrency: usize,
+    ) -> Result<Self> {
+        if specs.is_empty() {
+            bail!("at least one rpc endpoint is required");
+        }
+        let endpoints: Vec<Arc<RpcEndpoint>> = specs
+            .into_iter()
+            .map(|spec| {
+                // a throttling endpoint only slows down its own requests
+                let limiter = Arc::new(RateLimiter::new(rps, concurrency));
+                Arc::new(RpcEndpoint::new(spec, commitment, limiter))
+            })
+            .collect();
+        let weights: Vec<u32> = endpoints.iter().map(|endpoint| endpoint.weight).collect();
+        let schedule = weighted_schedule(&weights);
+        Ok(Self {
+            endpoints,
+            schedule,
+            cursor: AtomicUsize::new(0),
+            max_slot_lag,
+            commitment,
+            pubsub: None,
+        })
+    }
+    /// Confirm transactions over this websocket connection instead of
+    /// polling.
+    pub fn with_pubsub(mut self, pubsub: PubsubClient) -> Self {
+        self.pubsub = Some(Arc::new(pubsub));
+        self
+    }
+    pub fn pubsub(&self) -> Option<Arc<PubsubClient>> {
+        self.pubsub.clone()
+    }
+    pub fn commitment(&self) -> CommitmentConfig {
+        self.commitment
+    }
+    /// The first endpoint, used to work out the default websocket url.
+    pub fn primary_url(&self) -> &str {
+        &self.endpoints[0].url
+    }
+    /// Read the endpoint list file, one `URL` or `URL#WEIGHT` per line, blank
+    /// lines and lines starting with `#` are skipped.
+    pub fn load_endpoint_file(path: &str) -> Result<Vec<EndpointSpec>> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read the rpc endpoint file {}", path))?;
+        content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(EndpointSpec::from_str)
+            .collect()
+    }
+    /// Run a read against the pool, starting at the next endpoint in the
+    /// round-robin order and failing over when the endpoint itself is the
+    /// problem. Errors coming from the chain are returned as they are.
+    pub async fn call<T, F, Fut>(&self, f: F) -> ClientResult<T>
+    where
+        F: Fn(Arc<RpcClient>) -> Fut,
+        Fut: Future<Output = ClientResult<T>>,
+    {
+        let mut last_error = None;
+        for endpoint in self.candidates() {
+            match f(endpoint.client.clone()).await {
+                Ok(value) => {
+                    endpoint.record_success();
+                    return Ok(value);
+                }
+                Err(e) if is_endpoint_error(&e) => {
+                    endpoint.record_error(&e);
+                    warn!(
+                        "Rpc endpoint {} failed with error: {}, fail over to the next one",
+                        endpoint.url, e
+                    );
+                    last_error = Some(e);
+                }
+                Err(e) => {
+                    endpoint.record_success();
+                    return Err(e);
+                }
+            }
+        }
+        Err(last_error.expect("the pool always has one endpoint"))
+    }
+    /// Send the transaction through every healthy endpoint at once and return
+    /// the signature when any of them accepted it.
+    pub async fn send_transaction(&self, transaction: &Transaction) -> ClientResult<Signature> {
+        let mut endpoints = self.healthy_endpoints();
+        if endpoints.is_empty() {
+            endpoints = self.endpoints.clone();
+        }
+        let results = join_all(
+            endpoints
+                .iter()
+                .map(|endpoint| endpoint.client.send_transaction(transaction)),
+        )
+        .await;
+        let mut first_error = None;
+        for (endpoint, result) in endpoints.iter().zip(results) {
+            match result {
+                Ok(signature) => {
+                    endpoint.record_success();
+                    return Ok(signature);
+                }
+                Err(e) => {
+                    if is_endpoint_error(&e) {
+                        endpoint.record_error(&e);
+                    }
+                    // keep the chain's answer over a transport error
+                    if first_error.is_none() || !is_endpoint_error(&e) {
+                        first_error = Some(e);
+                    }
+                }
+            }
+        }
+        Err(first_error.expect("the pool always has one endpoint"))
+    }
+    /// Fan the transaction out and wait until it lands or its blockhash
+    /// expires. With a websocket the signature is watched through
+    /// `signatureSubscribe`, otherwise the pool is polled for its status.
+    pub async fn send_and_confirm_transaction(
+        &self,
+        transaction: &Transaction,
+    ) -> ClientResult<Signature> {
+        let signature = transaction.signatures[0];
+        let recent_blockhash = transaction.message.recent_blockhash;
+        if let Some(ref pubsub) = self.pubsub {
+            let config = RpcSignatureSubscribeConfig {
+                commitment: Some(self.commitment),
+                enable_received_notification: Some(false),
+            };
+            // subscribe before sending so the notification can not be missed
+            match pubsub.signature_subscribe(&signature, Some(config)).await {
+                Ok((mut notifications, unsubscribe)) => {
+                    let result = match self.send_transaction(transaction).await {
+                        Ok(_) => {
+                            self.watch_signature(&mut notifications, &signature, &recent_blockhash)
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    unsubscribe().await;
+                    return result;
+                }
+                Err(e) => {
+                    warn!(
+                        "Failed to subscribe the signature {} with error: {}, fall back to polling",
+                        signature, e
+                    );
+                }
+            }
+        }
+        self.send_transaction(transaction).await?;
+        self.poll_signature(&signature, &recent_blockhash).await
+    }
+    async fn watch_signature(
+        &self,
+        notifications: &mut BoxStream<'_, RpcResponse<RpcSignatureResult>>,
+        signature: &Signature,
+        recent_blockhash: &Hash,
+    ) -> ClientResult<Signature> {
+        loop {
+            tokio::select! {
+                notification = notifications.next() => match notification {
+                    Some(RpcResponse {
+                        value: RpcSignatureResult::ProcessedSignature(result),
+                        ..
+                    }) => {
+                        return match result.err {
+                            Some(e) => Err(e.into()),
+                            None => Ok(*signature),
+                        };
+                    }
+                    Some(_) => {}
+                    None => {
+                        warn!(
+                            "The websocket closed while watching {}, fall back to polling",
+                            signature
+                        );
+                        return self.poll_signature(signature, recent_blockhash).await;
+                    }
+                },
+                _ = sleep(BLOCKHASH_CHECK_INTERVAL) => {
+                    if !self.is_blockhash_valid(recent_blockhash).await? {
+                        // the notification may be racing the expiry, ask once more
+                        return match self.signature_status(signature).await? {
+                            Some(Ok(())) => Ok(*signature),
+                            Some(Err(e)) => Err(e.into()),
+                            None => Err(blockhash_expired()),
+                        };
+                    }
+                }
+            }
+        }
+    }
+    async fn poll_signature(
+        &self,
+        signature: &Signature,
+        recent_blockhash: &Hash,
+    ) -> ClientResult<Signature> {
+        loop {
+            match self.signature_status(signature).await? {
+                Some(Ok(())) => return Ok(*signature),
+                Some(Err(e)) => return Err(e.into()),
+                None => {
+                    if !self.is_blockhash_valid(recent_blockhash).await? {
+                        return Err(blockhash_expired());
+                    }
+                    sleep(Duration::from_millis(500)).await;
+                }
+            }
+        }
+    }
+    pub(crate) async fn signature_status(
+        &self,
+        signature: &Signature,
+    ) -> ClientResult<Option<TransactionResult<()>>> {
+        let signature = *signature;
+        self.call(|client| async move { client.get_signature_status(&signature).await })
+            .await
+    }
+    pub(crate) async fn is_blockhash_valid(&self, recent_blockhash: &Hash) -> ClientResult<bool> {
+        let recent_blockhash = *recent_blockhash;
+        self.call(|client| async move {
+            client
+                .is_blockhash_valid(&recent_blockhash, CommitmentConfig::processed())
+                .await
+        })
+        .await
+    }
+    /// Ask every endpoint for its slot and mark the ones which failed or lag
+    /// too far behind the best slot as unhealthy.
+    pub async fn check_health(&self) {
+        let slots = join_all(
+            self.endpoints
+                .iter()
+                .map(|endpoint| endpoint.client.get_slot()),
+        )
+        .await;
+        let best_slot = slots
+            .iter()
+            .filter_map(|slot| slot.as_ref().ok())
+            .max()
+            .copied()
+            .unwrap_or_default();
+        for (endpoint, slot) in self.endpoints.iter().zip(slots) {
+            match slot {
+                Ok(slot) => {
+                    endpoint.slot.store(slot, Ordering::Relaxed);
+                    let lag = best_slot.saturating_sub(slot);
+                    let healthy = lag <= self.max_slot_lag;
+                    let was_healthy = endpoint.healthy.swap(healthy, Ordering::Relaxed);
+                    if healthy {
+                        endpoint.record_success();
+                        if !was_healthy {
+                            info!("Rpc endpoint {} is healthy again", endpoint.url);
+                        }
+                    } else if was_healthy {
+                        warn!(
+                            "Rpc endpoint {} is {} slots behind, take it out of rotation",
+                            endpoint.url, lag
+                        );
+                    }
+                }
+                Err(e) => {
+                    endpoint.errors.fetch_add(1, Ordering::Relaxed);
+                    if endpoint.healthy.swap(false, Ordering::Relaxed) {
+                        warn!(
+                            "Rpc endpoint {} failed the health check with error: {}",
+                            endpoint.url, e
+                        );
+                    }
+                }
+            }
+        }
+    }
+    /// Keep checking the endpoints in the background, only useful when there
+    /// is more than one of them.
+    pub fn spawn_health_check(self: &Arc<Self>, interval: Duration) -> Option<JoinHandle<()>> {
+        if self.endpoints.len() < 2 {
+            return None;
+        }
+        let pool = self.clone();
+        Some(tokio::spawn(async move {
+            loop {
+                pool.check_health().await;
+                sleep(interval).await;
+            }
+        }))
+    }
+    fn healthy_endpoints(&self) -> Vec<Arc<RpcEndpoint>> {
+        self.endpoints
+            .iter()
+            .filter(|endpoint| endpoint.is_healthy())
+            .cloned()
+            .collect()
+    }
+    /// Endpoints in the order a call should try them: the next scheduled one,
+    /// the other healthy ones, then the unhealthy ones as a last resort.
+    fn candidates(&self) -> Vec<Arc<RpcEndpoint>> {
+        let turn = self.cursor.fetch_add(1, Ordering::Relaxed);
+        let first = self.schedule[turn % self.schedule.len()];
+        let len = self.endpoints.len();
+        let ordered = (0..len).map(|i| &self.endpoints[(first + i) % len]);
+        let (healthy, unhealthy): (Vec<_>, Vec<_>) =
+            ordered.cloned().partition(|endpoint| endpoint.is_healthy());
+        healthy.into_iter().chain(unhealthy).collect()
+    }
+}
+
+fn blockhash_expired() -> ClientError {
+    RpcError::ForUser("unable to confirm transaction, the blockhash is expired".to_string()).into()
+}
+
+/// The websocket url next to an rpc url, the same way the solana cli works it
+/// out: `http` becomes `ws`, `https` becomes `wss` and an explicit port is
+/// moved up by one.
+pub fn websocket_url(rpc_url: &str) -> String {
+    let (scheme, rest) = match rpc_url.split_once("://") {
+        Some(("https", rest)) => ("wss", rest),
+        Some((_, rest)) => ("ws", rest),
+        None => ("ws", rpc_url),
+    };
+    let (host, path) = match rest.find('/') {
+        Some(index) => rest.split_at(index),
+        None => (rest, ""),
+    };
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) => match port.parse::<u16>() {
+            Ok(port) => format!("{}:{}", name, port.saturating_add(1)),
+            Err(_) => host.to_string(),
+        },
+        None => host.to_string(),
+    };
+    format!("{}://{}{}", scheme, host, path)
+}
+
+/// Connect to the websocket endpoint, `None` when it can not be reached so
+/// the caller falls back to polling.
+pub async fn connect_pubsub(ws_url: &str) -> Option<PubsubClient> {
+    match timeout(WEBSOCKET_CONNECT_TIMEOUT, PubsubClient::new(ws_url)).await {
+        Ok(Ok(pubsub)) => {
+            info!("Connected to the websocket endpoint {}", ws_url);
+            Some(pubsub)
+        }
+        Ok(Err(e)) => {
+            warn!(
+                "Failed to connect the websocket endpoint {} with error: {}, fall back to polling",
+                ws_url, e
+            );
+            None
+        }
+        Err(_) => {
+            warn!(
+                "Timed out connecting the websocket endpoint {}, fall back to polling",
+                ws_url
+            );
+            None
+        }
+    }
+}
+
+/// Errors which say the endpoint could not serve the request, as opposed to
+/// the chain rejecting it.
+fn is_endpoint_error(e: &ClientError) -> bool {
+    match e.kind() {
+        ClientErrorKind::Io(_) | ClientErrorKind::Reqwest(_) => true,
+        ClientErrorKind::RpcError(RpcError::RpcRequestError(_)) => true,
+        ClientErrorKind::RpcError(RpcError::RpcResponseError { code, .. }) => {
+            *code == NODE_UNHEALTHY_CODE
+        }
+        _ => false,
+    }
+}
+
+/// Smooth weighted round-robin order, so an endpoint with weight 3 gets three
+/// turns in the cycle, interleaved with the others where possible. The
+/// weights are reduced by their greatest common divisor first.
+fn weighted_schedule(weights: &[u32]) -> Vec<usize> {
+    let divisor = weights.iter().copied().fold(0, gcd).max(1);
+    let weights: Vec<u32> = weights.iter().map(|weight| weight / divisor).collect();
+    let total: i64 = weights.iter().map(|weight| *weight as i64).sum();
+    let mut current = vec![0i64; weights.len()];
+    let mut schedule = Vec::with_capacity(total as usize);
+    for _ in 0..total {
+        for (i, weight) in weights.iter().enumerate() {
+            current[i] += *weight as i64;
+        }
+        let (best, _) = current
+            .iter()
+            .enumerate()
+            .max_by_key(|(i, weight)| (**weight, std::cmp::Reverse(*i)))
+            .expect("the pool always has one endpoint");
+        current[best] -= total;
+        schedule.push(best);
+    }
+    schedule
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    match b {
+        0 => a,
+        _ => gcd(b, a % b),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn websocket_url_follows_the_rpc_url() {
+        assert_eq!(
+            websocket_url("https://api.mainnet-beta.solana.com"),
+            "wss://api.mainnet-beta.solana.com"
+        );
+        assert_eq!(
+            websocket_url("http://127.0.0.1:8899"),
+            "ws://127.0.0.1:8900"
+        );
+        assert_eq!(
+            websocket_url("https://rpc.example.com:443/key/abc"),
+            "wss://rpc.example.com:444/key/abc"
+        );
+        assert_eq!(websocket_url("localhost:8899"), "ws://localhost:8900");
+        assert_eq!(websocket_url("http://[::1]:8899"), "ws://[::1]:8900");
+    }
+
+    #[test]
+    fn weighted_schedule_spreads_the_turns() {
+        assert_eq!(weighted_schedule(&[1]), vec![0]);
+        assert_eq!(weighted_schedule(&[1, 1]), vec![0, 1]);
+        // an endpoint with weight 3 gets three turns, the other one's turn
+        // falls between them
+        assert_eq!(weighted_schedule(&[3, 1]), vec![0, 0, 1, 0]);
+        assert_eq!(weighted_schedule(&[60, 20]), vec![0, 0, 1, 0]);
+        let schedule = weighted_schedule(&[5, 1, 1]);
+        assert_eq!(schedule, vec![0, 0, 1, 0, 2, 0, 0]);
+        assert_eq!(schedule.iter().filter(|i| **i == 0).count(), 5);
+    }
+
+    #[test]
+    fn the_weight_of_an_endpoint_is_bounded() {
+        let spec: EndpointSpec = "http://127.0.0.1:8899#100".parse().unwrap();
+        assert_eq!(spec.weight, 100);
+        assert!("http://127.0.0.1:8899#101".parse::<EndpointSpec>().is_err());
+        assert!("http://127.0.0.1:8899#4000000000"
+            .parse::<EndpointSpec>()
+            .is_err());
+        assert!("http://127.0.0.1:8899#0".parse::<EndpointSpec>().is_err());
+    }
+}
@@ -1,145 +1,326 @@
-use std::sync::Arc;
-
+use crate::config::PriorityFee;
+use crate::error::{Error, Result};
+use crate::outcome::FailureKind;
+use crate::wallet_set::WalletSigner;
 use crate::Tool;
-use anyhow::{Context, Result};
-use log::{error, info};
-use solana_client::nonblocking::rpc_client::RpcClient;
+use anyhow::anyhow;
+use log::error;
+use solana_client::client_error::ClientError;
 use solana_program::{instruction::Instruction, program_pack::Pack};
 use solana_sdk::{
-    pubkey::Pubkey,
-    signature::{Keypair, Signature},
-    signer::Signer,
-    system_transaction,
-    transaction::Transaction,
+    compute_budget::ComputeBudgetInstruction, hash::Hash, pubkey::Pubkey, signature::Signature,
+    signer::Signer, system_instruction, transaction::Transaction,
+};
+use spl_associated_token_account::{
+    get_associated_token_address, instruction::create_associated_token_account_idempotent,
 };
-use spl_associated_token_account::instruction::create_associated_token_account;
+use spl_token::instruction::transfer_checked;
 use tokio::time::{self, Duration};
 
+/// How often a transaction is signed and sent again before giving up.
+const MAX_SEND_ATTEMPTS: u32 = 5;
+/// Percentile of the recent prioritization fees used by `PriorityFee::Auto`.
+const AUTO_PRIORITY_FEE_PERCENTILE: usize = 75;
+
 impl Tool {
+    /// Send `instructions` in one transaction and wait for it to land,
+    /// retrying what the network dropped.
     pub async fn sendtxn_and_watch(
-        rpc_client: &RpcClient,
-        instruction: Instruction,
-        sender_keypair: &Keypair,
-        payer_keypair: &Keypair,
+        &self,
+        instructions: Vec<Instruction>,
+        sender_keypair: &WalletSigner,
+        payer_keypair: &WalletSigner,
     ) -> Result<Signature> {
+        let mut transaction_instructions = self.priority_fee_instructions(&instructions).await;
+        transaction_instructions.extend(instructions);
+        self.send_with_retries(&transaction_instructions, sender_keypair, payer_keypair)
+            .await
+    }
+    /// Send `instructions` as they are, compute budget included, so the fee
+    /// stays the same over the retries.
+    async fn send_with_retries(
+        &self,
+        instructions: &[Instruction],
+        sender_keypair: &WalletSigner,
+        payer_keypair: &WalletSigner,
+    ) -> Result<Signature> {
+        let rpc_pool = &self.rpc_pool;
+        let mut attempts = 0;
+        // the last transaction sent, while it may still land it is sent again
+        // as it is rather than signed anew, so it can only land once
+        let mut pending: Option<Transaction> = None;
         loop {
-            let recent_blockhash = rpc_client
-                .get_latest_blockhash()
-                .await
-                .context("Failed to get recent blockhash")?;
-            let transaction = Transaction::new_signed_with_payer(
-                &[instruction.clone()],
-                Some(&payer_keypair.pubkey()),
-                &[sender_keypair, payer_keypair],
-                recent_blockhash,
-            );
-            // let fee = rpc_client
-            //     .get_fee_for_message(&transaction.message)
-            //     .await
-            //     .context("Failed to get the transaction fee")?;
-            match rpc_client.send_and_confirm_transaction(&transaction).await {
-                Ok(signature) => {
-                    return Ok(signature);
+            attempts += 1;
+            let transaction = match pending.take() {
+                Some(transaction) => transaction,
+                None => {
+                    let recent_blockhash = rpc_pool
+                        .call(|client| async move { client.get_latest_blockhash().await })
+                        .await?;
+                    sign_transaction(
+                        instructions,
+                        payer_keypair,
+                        sender_keypair,
+                        recent_blockhash,
+                    )?
                 }
-                Err(e) => {
-                    error!(
-                        "Failed to landed {} transaction waiting for retries , the error is {}",
-                        &sender_keypair.pubkey(),
-                        // fee,
-                        e
-                    );
-                    time::sleep(Duration::from_secs(1)).await;
+            };
+            let result = rpc_pool.send_and_confirm_transaction(&transaction).await;
+            if let Some(ref ledger) = self.ledger {
+                ledger.record(rpc_pool, &transaction, &result);
+            }
+            let e = match result {
+                Ok(signature) => return Ok(signature),
+                Err(e) => Error::from(e),
+            };
+            // a failed transaction fails again, only retry what the network dropped
+            if e.kind() != FailureKind::Rpc || attempts >= MAX_SEND_ATTEMPTS {
+                return Err(e);
+            }
+            error!(
+                "Failed to land the transaction of {}, retry it, the error is {}",
+                &sender_keypair.pubkey(),
+                e
+            );
+            time::sleep(Duration::from_secs(1)).await;
+            // the error may have hidden a transaction which landed after all
+            let signature = transaction.signatures[0];
+            match rpc_pool.signature_status(&signature).await? {
+                Some(Ok(())) => return Ok(signature),
+                Some(Err(e)) => return Err(ClientError::from(e).into()),
+                None => {
+                    if rpc_pool
+                        .is_blockhash_valid(&transaction.message.recent_blockhash)
+                        .await?
+                    {
+                        pending = Some(transaction);
+                    }
                 }
             }
         }
     }
-    pub async fn check_token_account(
-        rpc_client: &RpcClient,
-        payer_keypair: &Keypair,
-        recipient_keypair: &Keypair,
-        token_address: &Pubkey,
-    ) -> Pubkey {
-        let recipient_token_account_info =
-            spl_associated_token_account::get_associated_token_address(
-                &recipient_keypair.pubkey(),
-                &token_address,
-            );
-        match rpc_client.get_account(&recipient_token_account_info).await {
-            Ok(_) => {
-                info!("Token account exist: {}", &recipient_token_account_info);
-                return recipient_token_account_info;
-            }
-            Err(_e) => {
-                error!("Token account is not exist, create right now");
-                let create_account_instruction = create_associated_token_account(
-                    &payer_keypair.pubkey(),
-                    &recipient_keypair.pubkey(),
-                    &token_address,
-                    &spl_token::id(),
-                );
-                match Tool::sendtxn_and_watch(
-                    rpc_client,
-                    create_account_instruction,
-                    &payer_keypair,
-                    &payer_keypair,
-                )
-                .await
+    /// The compute budget instruction which sets the priority fee for a
+    /// transaction running `instructions`, empty without a priority fee.
+    pub async fn priority_fee_instructions(
+        &self,
+        instructions: &[Instruction],
+    ) -> Vec<Instruction> {
+        let micro_lamports = match self.priority_fee {
+            PriorityFee::None => return vec![],
+            PriorityFee::Fixed(micro_lamports) => micro_lamports,
+            PriorityFee::Auto => {
+                let writable: Vec<Pubkey> = instructions
+                    .iter()
+                    .flat_map(|instruction| instruction.accounts.iter())
+                    .filter(|account| account.is_writable)
+                    .map(|account| account.pubkey)
+                    .collect();
+                let writable = &writable;
+                match self
+                    .rpc_pool
+                    .call(|client| async move {
+                        client.get_recent_prioritization_fees(writable).await
+                    })
+                    .await
                 {
-                    Ok(signature) => {
-                        info!("Successfuly to generate a new spl token account for address: {}, check the info: https://solscan.io/tx/{}",&recipient_keypair.pubkey(),signature);
+                    Ok(recent) => {
+                        let mut fees: Vec<u64> =
+                            recent.iter().map(|fee| fee.prioritization_fee).collect();
+                        fees.sort_unstable();
+                        match fees.len() {
+                            0 => 0,
+                            len => fees[(len - 1) * AUTO_PRIORITY_FEE_PERCENTILE / 100],
+                        }
                     }
                     Err(e) => {
-                        error!("Failed to create a new spl token account with error: {}", e)
+                        error!(
+                            "Failed to get the recent priority fees with error: {}, send without one",
+                            e
+                        );
+                        0
                     }
                 }
-                return recipient_token_account_info;
             }
+        };
+        if micro_lamports == 0 {
+            return vec![];
         }
+        vec![ComputeBudgetInstruction::set_compute_unit_price(
+            micro_lamports,
+        )]
+    }
+    pub fn explorer_link(&self, signature: &Signature) -> String {
+        self.explorer.replace("{signature}", &signature.to_string())
     }
-    pub async fn get_spl_token_amount(
-        rpc_client: Arc<RpcClient>,
+    pub async fn get_spl_token_amount(&self, token_account_address: &Pubkey) -> Result<u64> {
+        Ok(self.token_account(token_account_address).await?.amount)
+    }
+    /// The full state of a token account: balance, state, delegate and
+    /// close authority.
+    pub async fn token_account(
+        &self,
         token_account_address: &Pubkey,
-    ) -> Result<u64> {
-        let account_info = rpc_client
-            .get_account(&token_account_address)
-            .await
-            .context("Failed to fetching account data")?;
+    ) -> Result<spl_token::state::Account> {
+        let token_account_address = *token_account_address;
+        let account_info = self
+            .rpc_pool
+            .call(|client| async move { client.get_account(&token_account_address).await })
+            .await?;
+        unpack_token_account(&token_account_address, &account_info.data)
+    }
+    /// Which of the token accounts `addresses` are frozen, the missing ones
+    /// are not.
+    pub(crate) async fn frozen_accounts(&self, addresses: &[Pubkey]) -> Result<Vec<Pubkey>> {
+        let accounts = self.get_accounts(addresses).await?;
+        let mut frozen = vec![];
+        for (address, account) in addresses.iter().zip(accounts) {
+            if let Some(account) = account.filter(|account| account.owner == spl_token::id()) {
+                if unpack_token_account(address, &account.data)?.is_frozen() {
+                    frozen.push(*address);
+                }
+            }
+        }
+        Ok(frozen)
+    }
+    /// Move every lamport of `sender_keypair` to `recipient`. When the sender
+    /// pays the fee itself, the fee of the compiled message, priority fee
+    /// included, is kept back so the wallet ends at zero. Returns what was
+    /// moved, after checking nothing is left.
+    pub async fn sweep_sol(
+        &self,
+        sender_keypair: &WalletSigner,
+        recipient: &Pubkey,
+        payer_keypair: &WalletSigner,
+        at_most: u64,
+    ) -> Result<(Signature, u64)> {
+        let rpc_pool = &self.rpc_pool;
+        let sender_pubkey = sender_keypair.pubkey();
+        let balance = rpc_pool
+            .call(|client| async move { client.get_balance(&sender_pubkey).await })
+            .await?;
+        let probe = system_instruction::transfer(&sender_pubkey, recipient, balance);
+        let mut instructions = self
+            .priority_fee_instructions(std::slice::from_ref(&probe))
+            .await;
+        let amount = match payer_keypair.pubkey() == sender_pubkey {
+            true => {
+                let mut message_instructions = instructions.clone();
+                message_instructions.push(probe);
+                let fee = self
+                    .fee_for_message(&message_instructions, &sender_pubkey)
+                    .await?;
+                balance
+                    .checked_sub(fee)
+                    .filter(|amount| *amount > 0)
+                    .ok_or_else(|| Error::InsufficientBalance {
+                        account: sender_pubkey.to_string(),
+                        balance,
+                        needed: fee,
+                    })?
+            }
+            false => balance,
+        };
+        if amount > at_most {
+            return Err(anyhow!(
+                "{} holds more than planned, it would send {} lamports instead of {}, plan the sweep again",
+                sender_pubkey,
+                amount,
+                at_most
+            )
+            .into());
+        }
+        instructions.push(system_instruction::transfer(
+            &sender_pubkey,
+            recipient,
+            amount,
+        ));
+        let signature = self
+            .send_with_retries(&instructions, sender_keypair, payer_keypair)
+            .await?;
+        let left = rpc_pool
+            .call(|client| async move { client.get_balance(&sender_pubkey).await })
+            .await?;
+        if left != 0 {
+            return Err(Error::NotDrained {
+                account: sender_pubkey.to_string(),
+                lamports: left,
+                signature: signature.to_string(),
+            });
+        }
+        Ok((signature, amount))
+    }
+}
+
+pub(crate) fn unpack_token_account(
+    address: &Pubkey,
+    data: &[u8],
+) -> Result<spl_token::state::Account> {
+    spl_token::state::Account::unpack(data).map_err(|source| Error::NotTokenAccount {
+        account: address.to_string(),
+        source,
+    })
+}
+
+/// Move `amount` of `mint` from the associated token account of `owner` to
+/// the one of `recipient`, which is created in the same transaction when it
+/// is missing. `payer` pays the rent of a new account.
+pub(crate) fn token_transfer_instructions(
+    payer: &Pubkey,
+    owner: &Pubkey,
+    recipient: &Pubkey,
+    mint: &Pubkey,
+    amount: u64,
+    decimals: u8,
+) -> Result<Vec<Instruction>> {
+    let source = get_associated_token_address(owner, mint);
+    token_sweep_instructions(payer, owner, &[(source, amount)], recipient, mint, decimals)
+}
 
-        let token_info = spl_token::state::Account::unpack(&account_info.data)
-            .context("Failed to pares token data")?;
-        Ok(token_info.amount)
+/// Like `token_transfer_instructions`, moving the given amount out of each
+/// of the token accounts `sources` of `owner` in one transaction.
+pub(crate) fn token_sweep_instructions(
+    payer: &Pubkey,
+    owner: &Pubkey,
+    sources: &[(Pubkey, u64)],
+    recipient: &Pubkey,
+    mint: &Pubkey,
+    decimals: u8,
+) -> Result<Vec<Instruction>> {
+    let destination = get_associated_token_address(recipient, mint);
+    let mut instructions = vec![create_associated_token_account_idempotent(
+        payer,
+        recipient,
+        mint,
+        &spl_token::id(),
+    )];
+    for (source, amount) in sources {
+        instructions.push(transfer_checked(
+            &spl_token::id(),
+            source,
+            mint,
+            &destination,
+            owner,
+            &[owner],
+            *amount,
+            decimals,
+        )?);
     }
-    pub async fn get_sol_amount_except_gas(
-        rpc_client: Arc<RpcClient>,
-        sender_keypair: &Keypair,
-        recipient_keypair: &Keypair,
-    ) -> Result<u64> {
-        let rent_minium = rpc_client
-            .get_minimum_balance_for_rent_exemption(0)
-            .await
-            .context("Failed to get minimal rent")?;
-        let sender_balance_before = rpc_client
-            .get_balance(&sender_keypair.pubkey())
-            .await
-            .context("Failed to get sol balance")?;
-        let recent_blockhash = rpc_client
-            .get_latest_blockhash()
-            .await
-            .context("Failed to get recent blockhash")?;
-        let transaction = system_transaction::transfer(
-            sender_keypair,
-            &recipient_keypair.pubkey(),
-            sender_balance_before,
-            recent_blockhash,
-        );
-        let fee = rpc_client
-            .get_fee_for_message(&transaction.message)
-            .await
-            .context("Failed to get the fee")?;
-        let total_fee = rent_minium + fee;
-        let balance = sender_balance_before
-            .checked_sub(total_fee)
-            .context("account's sol balance is not enough to pay the gas")?;
-        Ok(balance)
+    Ok(instructions)
+}
+
+/// Sign a transaction paid by `payer`, a signer which refuses, like a remote
+/// signer outside its policy, fails it instead of panicking.
+pub(crate) fn sign_transaction(
+    instructions: &[Instruction],
+    payer: &dyn Signer,
+    sender: &dyn Signer,
+    recent_blockhash: Hash,
+) -> Result<Transaction> {
+    let mut transaction = Transaction::new_with_payer(instructions, Some(&payer.pubkey()));
+    if sender.pubkey() == payer.pubkey() {
+        transaction.try_sign(&[payer], recent_blockhash)?;
+    } else {
+        transaction.try_sign(&[sender, payer], recent_blockhash)?;
     }
+    Ok(transaction)
 }
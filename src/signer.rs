@@ -0,0 +1,546 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, DirBuilder};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use solana_sdk::{
+    compute_budget,
+    message::Message,
+    pubkey::Pubkey,
+    signature::{Keypair, Signature},
+    signer::{Signer, SignerError},
+    system_instruction::SystemInstruction,
+    system_program,
+};
+use spl_associated_token_account::get_associated_token_address;
+use spl_token::instruction::TokenInstruction;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use tokio::net::UnixListener;
+use tokio::runtime::{Handle, RuntimeFlavor};
+use tokio::sync::Mutex;
+use tokio::task;
+
+use crate::error::{parse_pubkey, Error, Result};
+use crate::outcome::{Failure, Outcome, Step};
+use crate::output::Record;
+use crate::wallet_set::WalletSet;
+use crate::Tool;
+
+/// Where `signer serve` listens when no socket is given.
+pub fn default_socket_path() -> String {
+    match env::var("HOME") {
+        Ok(home) => format!("{}/.solana_tool/signer.sock", home),
+        Err(_) => "signer.sock".to_string(),
+    }
+}
+
+/// One line of json from the cli to the daemon.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "method", rename_all = "snake_case")]
+enum Request {
+    Pubkeys,
+    /// `message` is the bs58 of the serialized transaction message.
+    Sign {
+        pubkey: String,
+        message: String,
+    },
+}
+
+/// One line of json back, `error` is set when the request was refused.
+#[derive(Default, Serialize, Deserialize)]
+struct Response {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pubkeys: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    signature: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    allowed_programs: Option<Vec<String>>,
+    max_lamports: Option<u64>,
+    max_token_amount: Option<u64>,
+    #[serde(default)]
+    allowed_destinations: Vec<String>,
+    allow_own_wallets: Option<bool>,
+    allow_any_destination: Option<bool>,
+}
+
+/// What the daemon agrees to sign. Every instruction must run one of the
+/// allowed programs; system and token transfers must stay under the
+/// amounts and go to an allowed destination, token accounts may only be
+/// created by and for allowed wallets; instructions which hand over an
+/// account, like `Assign` or `SetAuthority`, are always refused.
+pub struct SigningPolicy {
+    pub allowed_programs: Vec<Pubkey>,
+    /// Per system transfer.
+    pub max_lamports: Option<u64>,
+    /// Per token transfer, in the raw amount.
+    pub max_token_amount: Option<u64>,
+    /// Owners or accounts transfers may go to.
+    pub allowed_destinations: Vec<Pubkey>,
+    /// Also allow transfers to the wallets the daemon holds.
+    pub allow_own_wallets: bool,
+    /// Let transfers go anywhere, the destinations above are not checked.
+    pub allow_any_destination: bool,
+}
+
+impl Default for SigningPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_programs: vec![
+                system_program::id(),
+                spl_token::id(),
+                spl_associated_token_account::id(),
+                compute_budget::id(),
+            ],
+            max_lamports: None,
+            max_token_amount: None,
+            allowed_destinations: vec![],
+            allow_own_wallets: true,
+            allow_any_destination: false,
+        }
+    }
+}
+
+impl SigningPolicy {
+    pub fn load(path: &str) -> Result<Self> {
+        let content = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+        let file: PolicyFile = toml::from_str(&content)
+            .map_err(|e| Error::Config(anyhow::anyhow!("invalid policy {}: {}", path, e)))?;
+        let parse_all = |inputs: Vec<String>| {
+            inputs
+                .iter()
+                .map(|input| parse_pubkey(input))
+                .collect::<Result<Vec<_>>>()
+        };
+        let default = SigningPolicy::default();
+        Ok(Self {
+            allowed_programs: match file.allowed_programs {
+                Some(programs) => parse_all(programs)?,
+                None => default.allowed_programs,
+            },
+            max_lamports: file.max_lamports,
+            max_token_amount: file.max_token_amount,
+            allowed_destinations: parse_all(file.allowed_destinations)?,
+            allow_own_wallets: file.allow_own_wallets.unwrap_or(default.allow_own_wallets),
+            allow_any_destination: file
+                .allow_any_destination
+                .unwrap_or(default.allow_any_destination),
+        })
+    }
+    /// Why the message may not be signed, `None` when it may.
+    fn check(&self, message: &Message, own_wallets: &[Pubkey]) -> Option<String> {
+        for instruction in &message.instructions {
+            let program = match message
+                .account_keys
+                .get(instruction.program_id_index as usize)
+            {
+                Some(program) => *program,
+                None => return Some("a bad program index".to_string()),
+            };
+            if !self.allowed_programs.contains(&program) {
+                return Some(format!("the program {} is not allowed", program));
+            }
+            let account = |index: usize| {
+                instruction
+                    .accounts
+                    .get(index)
+                    .and_then(|key| message.account_keys.get(*key as usize))
+                    .copied()
+                    .unwrap_or_default()
+            };
+            let refusal = if program == system_program::id() {
+                match bincode::deserialize::<SystemInstruction>(&instruction.data) {
+                    Ok(SystemInstruction::Transfer { lamports }) => {
+                        over(lamports, self.max_lamports, "lamports")
+                            .or_else(|| self.check_destination(&account(1), None, own_wallets))
+                    }
+                    Ok(other) => Some(format!("the system instruction {:?} is not allowed", other)),
+                    Err(_) => Some("an unknown system instruction".to_string()),
+                }
+            } else if program == spl_token::id() {
+                match TokenInstruction::unpack(&instruction.data) {
+                    Ok(TokenInstruction::Transfer { amount }) => {
+                        over(amount, self.max_token_amount, "tokens")
+                            .or_else(|| self.check_destination(&account(1), None, own_wallets))
+                    }
+                    Ok(TokenInstruction::TransferChecked { amount, .. }) => {
+                        over(amount, self.max_token_amount, "tokens").or_else(|| {
+                            self.check_destination(&account(2), Some(&account(1)), own_wallets)
+                        })
+                    }
+                    // the rent goes back to the owner
+                    Ok(TokenInstruction::CloseAccount) if account(1) == account(2) => None,
+                    Ok(TokenInstruction::CloseAccount) => {
+                        self.check_destination(&account(1), None, own_wallets)
+                    }
+                    Ok(TokenInstruction::SyncNative)
+                    | Ok(TokenInstruction::InitializeAccount3 { .. }) => None,
+                    Ok(other) => Some(format!("the token instruction {:?} is not allowed", other)),
+                    Err(_) => Some("an unknown token instruction".to_string()),
+                }
+            } else if program == spl_associated_token_account::id() {
+                match instruction.data.first() {
+                    // `Create` and `CreateIdempotent`, the payer funds the
+                    // rent of an account the wallet will own
+                    None | Some(0) | Some(1) => self
+                        .check_destination(&account(0), None, own_wallets)
+                        .or_else(|| self.check_destination(&account(2), None, own_wallets)),
+                    Some(_) => Some("the associated token instruction is not allowed".to_string()),
+                }
+            } else {
+                None
+            };
+            if refusal.is_some() {
+                return refusal;
+            }
+        }
+        None
+    }
+    /// `destination` is a wallet, or the token account of `mint` for one.
+    fn check_destination(
+        &self,
+        destination: &Pubkey,
+        mint: Option<&Pubkey>,
+        own_wallets: &[Pubkey],
+    ) -> Option<String> {
+        if self.allow_any_destination {
+            return None;
+        }
+        let own_wallets = if self.allow_own_wallets {
+            own_wallets
+        } else {
+            &[]
+        };
+        let allowed = self
+            .allowed_destinations
+            .iter()
+            .chain(own_wallets)
+            .any(|owner| {
+                owner == destination
+                    || mint.is_some_and(|mint| {
+                        get_associated_token_address(owner, mint) == *destination
+                    })
+            });
+        if allowed {
+            None
+        } else {
+            Some(format!("the destination {} is not allowed", destination))
+        }
+    }
+}
+
+fn over(amount: u64, max: Option<u64>, unit: &str) -> Option<String> {
+    match max {
+        Some(max) if amount > max => Some(format!(
+            "{} {} is over the limit of {} per transfer",
+            amount, unit, max
+        )),
+        _ => None,
+    }
+}
+
+struct Daemon {
+    keypairs: HashMap<Pubkey, Arc<Keypair>>,
+    policy: SigningPolicy,
+    outcome: Mutex<Outcome>,
+}
+
+impl Daemon {
+    async fn handle(&self, tool: &Tool, request: &str) -> Response {
+        let request: Request = match serde_json::from_str(request) {
+            Ok(request) => request,
+            Err(e) => {
+                return Response {
+                    error: Some(format!("bad request: {}", e)),
+                    ..Response::default()
+                }
+            }
+        };
+        match request {
+            Request::Pubkeys => Response {
+                pubkeys: Some(self.keypairs.keys().map(Pubkey::to_string).collect()),
+                ..Response::default()
+            },
+            Request::Sign { pubkey, message } => {
+                let result = self.sign(&pubkey, &message);
+                match result {
+                    Ok(ref signature) => info!("Signed for {}: {}", pubkey, signature),
+                    Err(ref e) => warn!("Refused to sign for {}: {}", pubkey, e),
+                }
+                let (signature, error) = match result {
+                    Ok(signature) => (Some(signature.to_string()), None),
+                    Err(e) => (None, Some(e)),
+                };
+                tool.output.emit(Record::SignRequest {
+                    wallet: pubkey.clone(),
+                    signature: signature.clone(),
+                    error: error.clone(),
+                });
+                self.outcome.lock().await.add(match error {
+                    None => Step::Succeeded,
+                    Some(ref e) => Step::Failed(Failure::new(
+                        &pubkey,
+                        Error::Signing(SignerError::Custom(e.clone())),
+                    )),
+                });
+                Response {
+                    signature,
+                    error,
+                    ..Response::default()
+                }
+            }
+        }
+    }
+    fn sign(&self, pubkey: &str, message: &str) -> std::result::Result<Signature, String> {
+        let pubkey = parse_pubkey(pubkey).map_err(|e| e.to_string())?;
+        let keypair = self
+            .keypairs
+            .get(&pubkey)
+            .ok_or_else(|| format!("{} is not held by this signer", pubkey))?;
+        let bytes = bs58::decode(message)
+            .into_vec()
+            .map_err(|e| format!("bad message: {}", e))?;
+        let parsed: Message =
+            bincode::deserialize(&bytes).map_err(|e| format!("bad message: {}", e))?;
+        let signs = parsed
+            .account_keys
+            .iter()
+            .position(|key| *key == pubkey)
+            .is_some_and(|index| parsed.is_signer(index));
+        if !signs {
+            return Err(format!(
+                "the message does not need a signature of {}",
+                pubkey
+            ));
+        }
+        let own_wallets: Vec<Pubkey> = self.keypairs.keys().copied().collect();
+        if let Some(refusal) = self.policy.check(&parsed, &own_wallets) {
+            return Err(refusal);
+        }
+        Ok(keypair.sign_message(&bytes))
+    }
+}
+
+impl Tool {
+    /// Keep the wallets unlocked in this process and sign, over a unix
+    /// socket, the messages the policy allows, until ctrl-c.
+    pub async fn serve_signer(
+        &self,
+        wallets: &WalletSet,
+        policy: SigningPolicy,
+        socket: String,
+    ) -> Result<Outcome> {
+        let keypairs = wallets
+            .iter()
+            .map(|wallet| Ok((wallet.pubkey(), Arc::new(wallet.secret()?.insecure_clone()))))
+            .collect::<Result<HashMap<_, _>>>()?;
+        let path = Path::new(&socket);
+        if let Some(parent) = path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+        {
+            fs::create_dir_all(parent).map_err(|e| Error::io(parent.display(), e))?;
+        }
+        remove_stale_socket(path)?;
+        let listener = bind_private(path)?;
+        info!(
+            "Signing for {} wallets on {}, press ctrl-c to stop",
+            keypairs.len(),
+            socket
+        );
+        let daemon = Arc::new(Daemon {
+            keypairs,
+            policy,
+            outcome: Mutex::new(Outcome::default()),
+        });
+        loop {
+            let stream = tokio::select! {
+                _ = tokio::signal::ctrl_c() => break,
+                accepted = listener.accept() => match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        error!("Failed to accept a connection with error: {}", e);
+                        continue;
+                    }
+                }
+            };
+            let daemon = daemon.clone();
+            let tool = self.clone();
+            tokio::spawn(async move {
+                let (reader, mut writer) = stream.into_split();
+                let mut lines = tokio::io::BufReader::new(reader).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let response = daemon.handle(&tool, &line).await;
+                    let mut response = serde_json::to_string(&response).unwrap_or_default();
+                    response.push('\n');
+                    if writer.write_all(response.as_bytes()).await.is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        let _ = fs::remove_file(path);
+        let outcome = std::mem::take(&mut *daemon.outcome.lock().await);
+        Ok(outcome)
+    }
+}
+
+/// Remove the socket a previous daemon left at `path`, anything else found
+/// there is kept and refused.
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => {
+            fs::remove_file(path).map_err(|e| Error::io(path.display(), e))
+        }
+        Ok(_) => Err(Error::io(
+            path.display(),
+            io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "it is not a socket, refuse to replace it",
+            ),
+        )),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(Error::io(path.display(), e)),
+    }
+}
+
+/// Bind the socket in a folder only this user can enter, make it 600 and
+/// only then move it to `path`, so no other user can connect in between.
+fn bind_private(path: &Path) -> Result<UnixListener> {
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let private = parent.join(format!(".signer-{}", std::process::id()));
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&private)
+        .map_err(|e| Error::io(private.display(), e))?;
+    let staged = private.join("signer.sock");
+    let bound = UnixListener::bind(&staged)
+        .and_then(|listener| {
+            fs::set_permissions(&staged, fs::Permissions::from_mode(0o600))?;
+            fs::rename(&staged, path)?;
+            Ok(listener)
+        })
+        .map_err(|e| Error::io(path.display(), e));
+    let _ = fs::remove_dir_all(&private);
+    bound
+}
+
+/// A wallet whose key stays in the `signer serve` daemon.
+pub struct RemoteSigner {
+    socket: PathBuf,
+    pubkey: Pubkey,
+}
+
+impl RemoteSigner {
+    /// Every wallet the daemon on `socket` holds.
+    pub fn connect(socket: &Path) -> std::result::Result<Vec<RemoteSigner>, SignerError> {
+        let pubkeys = request(socket, &Request::Pubkeys)?
+            .pubkeys
+            .unwrap_or_default();
+        pubkeys
+            .iter()
+            .map(|pubkey| {
+                Ok(RemoteSigner {
+                    socket: socket.to_path_buf(),
+                    pubkey: pubkey
+                        .parse()
+                        .map_err(|_| SignerError::Protocol(format!("bad pubkey {}", pubkey)))?,
+                })
+            })
+            .collect()
+    }
+}
+
+impl Signer for RemoteSigner {
+    fn try_pubkey(&self) -> std::result::Result<Pubkey, SignerError> {
+        Ok(self.pubkey)
+    }
+    fn try_sign_message(&self, message: &[u8]) -> std::result::Result<Signature, SignerError> {
+        let sign = Request::Sign {
+            pubkey: self.pubkey.to_string(),
+            message: bs58::encode(message).into_string(),
+        };
+        // signing is called from async code, hand the worker thread over
+        // while waiting on the daemon so the other tasks keep running
+        let response = match Handle::try_current() {
+            Ok(handle) if handle.runtime_flavor() == RuntimeFlavor::MultiThread => {
+                task::block_in_place(|| request(&self.socket, &sign))
+            }
+            _ => request(&self.socket, &sign),
+        }?;
+        match (response.signature, response.error) {
+            (_, Some(error)) => Err(SignerError::Custom(format!(
+                "the remote signer refused: {}",
+                error
+            ))),
+            (Some(signature), None) => signature
+                .parse()
+                .map_err(|_| SignerError::Protocol(format!("bad signature {}", signature))),
+            (None, None) => Err(SignerError::Protocol("empty response".to_string())),
+        }
+    }
+    fn is_interactive(&self) -> bool {
+        false
+    }
+}
+
+/// One request and its response on a fresh connection.
+fn request(socket: &Path, request: &Request) -> std::result::Result<Response, SignerError> {
+    let connection_error =
+        |e: std::io::Error| SignerError::Connection(format!("{}: {}", socket.display(), e));
+    let mut stream = UnixStream::connect(socket).map_err(connection_error)?;
+    let mut line =
+        serde_json::to_string(request).map_err(|e| SignerError::Protocol(e.to_string()))?;
+    line.push('\n');
+    stream
+        .write_all(line.as_bytes())
+        .map_err(connection_error)?;
+    let mut response = String::new();
+    BufReader::new(stream)
+        .read_line(&mut response)
+        .map_err(connection_error)?;
+    serde_json::from_str(&response).map_err(|e| SignerError::Protocol(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("solana_tool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn a_file_at_the_socket_path_is_kept() {
+        let path = temp_path("keystore.json");
+        fs::write(&path, "secret").unwrap();
+        assert!(matches!(remove_stale_socket(&path), Err(Error::Io { .. })));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "secret");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_stale_socket_is_removed() {
+        let path = temp_path("stale.sock");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        remove_stale_socket(&path).unwrap();
+        assert!(fs::symlink_metadata(&path).is_err());
+        remove_stale_socket(&path).unwrap();
+    }
+}
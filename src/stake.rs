@@ -0,0 +1,791 @@
+use std::fmt;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use futures::future::join_all;
+use log::{error, info, warn};
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig};
+use solana_client::rpc_filter::{Memcmp, RpcFilterType};
+use solana_sdk::{
+    clock::Epoch,
+    feature,
+    feature_set::reduce_stake_warmup_cooldown,
+    instruction::Instruction,
+    pubkey::Pubkey,
+    stake::{
+        self, instruction as stake_instruction,
+        state::{Authorized, Lockup, StakeStateV2},
+    },
+    stake_history::StakeHistory,
+    sysvar::stake_history,
+};
+
+use crate::error::Result;
+use crate::outcome::{Failure, Outcome, Step};
+use crate::output::Record;
+use crate::plan::RunSummary;
+use crate::report::{join_entries, StakeEntry, StakeReport, Status, Transfer};
+use crate::wallet_set::{SharedSigner, WalletSet};
+use crate::Tool;
+
+/// Where the withdraw authority sits in the stake account data.
+const WITHDRAWER_OFFSET: usize = 44;
+/// How many stake instructions of a wallet go in one transaction.
+const MAX_STAKE_INSTRUCTIONS_PER_TRANSACTION: usize = 10;
+/// How many `stake:<n>` addresses are looked up at once for a free one.
+const STAKE_SEEDS_PER_LOOKUP: usize = 10;
+
+/// Where a stake account is in its life, going by its delegation and how
+/// far the cluster has warmed up or cooled down the stake since.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activation {
+    /// Never delegated, or deactivated for good, it can be withdrawn.
+    Inactive,
+    Activating,
+    Active,
+    Deactivating,
+}
+
+impl fmt::Display for Activation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let activation = match self {
+            Activation::Inactive => "inactive",
+            Activation::Activating => "activating",
+            Activation::Active => "active",
+            Activation::Deactivating => "deactivating",
+        };
+        f.write_str(activation)
+    }
+}
+
+/// A stake account a wallet can withdraw from.
+#[derive(Debug, Clone, Copy)]
+pub struct StakeAccount {
+    pub address: Pubkey,
+    pub wallet: Pubkey,
+    /// Everything the account holds, rent included.
+    pub lamports: u64,
+    /// The delegated lamports, 0 before the first delegation.
+    pub stake: u64,
+    pub vote_account: Option<Pubkey>,
+    pub activation: Activation,
+}
+
+impl StakeAccount {
+    fn parse(
+        wallet: Pubkey,
+        address: Pubkey,
+        lamports: u64,
+        data: &[u8],
+        at: &StakeEpoch,
+    ) -> Result<Self> {
+        let state: StakeStateV2 = bincode::deserialize(data)
+            .map_err(|e| anyhow!("the stake account {} can not be decoded: {}", address, e))?;
+        let delegation = state.delegation();
+        let activation = match delegation {
+            None => Activation::Inactive,
+            Some(delegation) => {
+                // the stake warms up and cools down over several epochs
+                let status = delegation.stake_activating_and_deactivating(
+                    at.epoch,
+                    &at.history,
+                    at.new_rate_activation_epoch,
+                );
+                if status.activating > 0 {
+                    Activation::Activating
+                } else if status.deactivating > 0 {
+                    Activation::Deactivating
+                } else if status.effective > 0 {
+                    Activation::Active
+                } else {
+                    Activation::Inactive
+                }
+            }
+        };
+        Ok(Self {
+            address,
+            wallet,
+            lamports,
+            stake: delegation.map_or(0, |delegation| delegation.stake),
+            vote_account: delegation.map(|delegation| delegation.voter_pubkey),
+            activation,
+        })
+    }
+    pub(crate) fn record(&self) -> Record {
+        Record::StakeAccount {
+            wallet: self.wallet.to_string(),
+            stake_account: self.address.to_string(),
+            lamports: self.lamports,
+            stake: self.stake,
+            vote_account: self
+                .vote_account
+                .map(|vote_account| vote_account.to_string()),
+            activation: self.activation.to_string(),
+        }
+    }
+}
+
+/// The current epoch and the stake history of the cluster, which tell how
+/// far the stake of an account has warmed up or cooled down.
+pub struct StakeEpoch {
+    epoch: Epoch,
+    history: StakeHistory,
+    /// The epoch the lower warmup and cooldown rate took effect in.
+    new_rate_activation_epoch: Option<Epoch>,
+}
+
+impl Tool {
+    /// Read what the activation of the stake accounts is worked out from,
+    /// once for every wallet of a run.
+    pub async fn stake_epoch(&self) -> Result<StakeEpoch> {
+        let epoch = self
+            .rpc_pool
+            .call(|client| async move { client.get_epoch_info().await })
+            .await?
+            .epoch;
+        let accounts = self
+            .get_accounts(&[stake_history::id(), reduce_stake_warmup_cooldown::id()])
+            .await?;
+        let history = match accounts[0] {
+            Some(ref account) => bincode::deserialize(&account.data)
+                .map_err(|e| anyhow!("the stake history can not be decoded: {}", e))?,
+            None => return Err(anyhow!("the cluster has no stake history").into()),
+        };
+        let activated_at = accounts[1]
+            .as_ref()
+            .and_then(feature::from_account)
+            .and_then(|feature| feature.activated_at);
+        let new_rate_activation_epoch = match activated_at {
+            Some(slot) => Some(
+                self.rpc_pool
+                    .call(|client| async move { client.get_epoch_schedule().await })
+                    .await?
+                    .get_epoch(slot),
+            ),
+            None => None,
+        };
+        Ok(StakeEpoch {
+            epoch,
+            history,
+            new_rate_activation_epoch,
+        })
+    }
+    /// The stake accounts `owner` is the withdraw authority of.
+    pub async fn stake_accounts(
+        &self,
+        owner: &Pubkey,
+        at: &StakeEpoch,
+    ) -> Result<Vec<StakeAccount>> {
+        let owner = *owner;
+        let accounts = self
+            .rpc_pool
+            .call(|client| async move {
+                let config = RpcProgramAccountsConfig {
+                    filters: Some(vec![RpcFilterType::Memcmp(Memcmp::new_raw_bytes(
+                        WITHDRAWER_OFFSET,
+                        owner.to_bytes().to_vec(),
+                    ))]),
+                    account_config: RpcAccountInfoConfig {
+                        encoding: Some(UiAccountEncoding::Base64),
+                        commitment: Some(client.commitment()),
+                        ..RpcAccountInfoConfig::default()
+                    },
+                    ..RpcProgramAccountsConfig::default()
+                };
+                client
+                    .get_program_accounts_with_config(&stake::program::id(), config)
+                    .await
+            })
+            .await?;
+        accounts
+            .iter()
+            .map(|(address, account)| {
+                StakeAccount::parse(owner, *address, account.lamports, &account.data, at)
+            })
+            .collect()
+    }
+    /// Log and emit every stake account of every wallet of a set.
+    pub async fn show_stake_accounts(&self, wallets: &WalletSet) -> Result<Outcome> {
+        let stake_epoch = Arc::new(self.stake_epoch().await?);
+        let mut tasks = vec![];
+        for wallet in wallets.iter() {
+            let owner = wallet.pubkey();
+            let tool = self.clone();
+            let stake_epoch = stake_epoch.clone();
+            tasks.push(tokio::spawn(async move {
+                let result = tool.stake_accounts(&owner, &stake_epoch).await;
+                match result {
+                    Ok(ref accounts) => {
+                        for account in accounts {
+                            info!(
+                                "{} of {}: {} lamports, {} staked to {}, {}",
+                                account.address,
+                                owner,
+                                account.lamports,
+                                account.stake,
+                                account
+                                    .vote_account
+                                    .map_or_else(|| "none".to_string(), |vote| vote.to_string()),
+                                account.activation
+                            );
+                            tool.output.emit(account.record());
+                        }
+                    }
+                    Err(ref e) => error!(
+                        "Failed to get the stake accounts of {} with error: {}",
+                        owner, e
+                    ),
+                }
+                Step::of(owner, result)
+            }));
+        }
+        let mut outcome = Outcome::default();
+        outcome.join(join_all(tasks).await);
+        Ok(outcome)
+    }
+}
+
+/// The stake accounts worked out by `CreateStake::plan`, `CreateStake::run`
+/// creates exactly them.
+pub struct CreateStakePlan {
+    /// What to confirm.
+    pub summary: RunSummary,
+    /// The wallets, the address of their new account, its seed and the
+    /// signer.
+    pending: Vec<(Pubkey, Pubkey, String, SharedSigner)>,
+    /// The wallets no address could be found for.
+    settled: Vec<StakeEntry>,
+}
+
+/// Create a new stake account for every wallet of a set out of its own sol,
+/// at an address derived from the wallet so no new keypair is needed. Each
+/// wallet is the stake and withdraw authority and pays its own fee.
+pub struct CreateStake {
+    amount: u64,
+    vote_account: Option<Pubkey>,
+}
+
+impl CreateStake {
+    /// `amount` is the lamports put in each stake account, its rent included.
+    pub fn new(amount: u64) -> Self {
+        Self {
+            amount,
+            vote_account: None,
+        }
+    }
+    /// Delegate the new accounts to this vote account in the same
+    /// transaction.
+    pub fn delegate_to(mut self, vote_account: Pubkey) -> Self {
+        self.vote_account = Some(vote_account);
+        self
+    }
+    fn instructions(&self, owner: &Pubkey, address: &Pubkey, seed: &str) -> Vec<Instruction> {
+        let authorized = Authorized::auto(owner);
+        match self.vote_account {
+            Some(ref vote_account) => {
+                stake_instruction::create_account_with_seed_and_delegate_stake(
+                    owner,
+                    address,
+                    owner,
+                    seed,
+                    vote_account,
+                    &authorized,
+                    &Lockup::default(),
+                    self.amount,
+                )
+            }
+            None => stake_instruction::create_account_with_seed(
+                owner,
+                address,
+                owner,
+                seed,
+                &authorized,
+                &Lockup::default(),
+                self.amount,
+            ),
+        }
+    }
+    /// Check the amount, find the address of the new account of every
+    /// wallet and work out what `run` is going to send, nothing is sent.
+    pub async fn plan(&self, tool: &Tool, wallets: &WalletSet) -> Result<CreateStakePlan> {
+        let rent = tool.minimum_balance(StakeStateV2::size_of()).await?;
+        let minimum = match self.vote_account {
+            Some(_) => {
+                rent + tool
+                    .rpc_pool
+                    .call(|client| async move { client.get_stake_minimum_delegation().await })
+                    .await?
+            }
+            None => rent,
+        };
+        if self.amount < minimum {
+            return Err(anyhow!(
+                "a stake account needs at least {} lamports ({} for the rent{})",
+                minimum,
+                rent,
+                match self.vote_account {
+                    Some(_) => ", the rest for the minimum delegation",
+                    None => "",
+                }
+            )
+            .into());
+        }
+        let mut settled = vec![];
+        let mut pending = vec![];
+        for wallet in wallets.iter() {
+            let owner = wallet.pubkey();
+            match next_stake_address(tool, &owner).await {
+                Ok((address, seed)) => pending.push((owner, address, seed, wallet.signer.clone())),
+                Err(e) => {
+                    error!(
+                        "Failed to find a stake address for {} with error: {}",
+                        owner, e
+                    );
+                    settled.push(self.entry(owner, None, Status::Failed(Failure::new(owner, e))));
+                }
+            }
+        }
+        let transfers: Vec<Transfer> = pending
+            .iter()
+            .map(|(owner, address, _, _)| Transfer {
+                from: *owner,
+                to: *address,
+                mint: None,
+                amount: self.amount,
+            })
+            .collect();
+        let mut summary = RunSummary {
+            cluster: tool.cluster().await?,
+            recipients: transfers.len(),
+            total_amount: self.amount * transfers.len() as u64,
+            mint: None,
+            decimals: 9,
+            accounts_to_create: 0,
+            account_rent: 0,
+            estimated_fee: 0,
+            allocation: vec![],
+            transfers,
+            frozen: vec![],
+        };
+        if let Some((owner, address, seed, _)) = pending.first() {
+            let instructions = self.instructions(owner, address, seed);
+            summary.estimated_fee =
+                tool.estimate_fee(instructions, owner).await? * pending.len() as u64;
+        }
+        Ok(CreateStakePlan {
+            summary,
+            pending,
+            settled,
+        })
+    }
+    pub async fn run(&self, tool: &Tool, plan: CreateStakePlan) -> Result<StakeReport> {
+        tool.authorize(&plan.summary.transfers, false)?;
+        for entry in &plan.settled {
+            tool.output.emit(entry.record());
+        }
+        let mut report = StakeReport {
+            entries: plan.settled,
+            ..StakeReport::default()
+        };
+        let pending = plan.pending;
+        let mut tasks = vec![];
+        for (owner, address, seed, signer) in pending {
+            let tool = tool.clone();
+            let amount = self.amount;
+            let instructions = self.instructions(&owner, &address, &seed);
+            let task = tokio::spawn(async move {
+                let result = tool
+                    .sendtxn_and_watch(instructions, &*signer, &*signer)
+                    .await;
+                let status = match result {
+                    Ok(signature) => {
+                        info!(
+                            "Successfuly staked {} lamports of {} in {}, check the info: {}",
+                            amount,
+                            owner,
+                            address,
+                            tool.explorer_link(&signature)
+                        );
+                        Status::Sent(signature)
+                    }
+                    Err(e) => {
+                        error!("Failed to stake the sol of {} with error: {}", owner, e);
+                        Status::Failed(Failure::new(owner, e))
+                    }
+                };
+                let entry = StakeEntry {
+                    action: "create",
+                    wallet: owner,
+                    stake_account: Some(address),
+                    lamports: amount,
+                    status,
+                };
+                tool.output.emit(entry.record());
+                entry
+            });
+            tasks.push(((owner, address), task));
+        }
+        report.entries.extend(
+            join_entries(tasks, |(wallet, address), failure| {
+                self.entry(wallet, Some(address), Status::Failed(failure))
+            })
+            .await,
+        );
+        let sent: Vec<Transfer> = report.entries.iter().filter_map(sent_stake).collect();
+        report.failures.extend(tool.record_spending(&sent));
+        Ok(report)
+    }
+    fn entry(&self, wallet: Pubkey, stake_account: Option<Pubkey>, status: Status) -> StakeEntry {
+        StakeEntry {
+            action: "create",
+            wallet,
+            stake_account,
+            lamports: self.amount,
+            status,
+        }
+    }
+}
+
+/// The first `stake:<n>` address of `owner` with no account on it, the
+/// address may hold an account which is not a stake account of `owner`.
+async fn next_stake_address(tool: &Tool, owner: &Pubkey) -> Result<(Pubkey, String)> {
+    for start in (0..).step_by(STAKE_SEEDS_PER_LOOKUP) {
+        let seeds: Vec<String> = (start..start + STAKE_SEEDS_PER_LOOKUP)
+            .map(|index| format!("stake:{}", index))
+            .collect();
+        let addresses = seeds
+            .iter()
+            .map(|seed| Pubkey::create_with_seed(owner, seed, &stake::program::id()))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("Failed to derive the stake address of {}: {}", owner, e))?;
+        let accounts = tool.get_accounts(&addresses).await?;
+        if let Some(index) = accounts.iter().position(Option::is_none) {
+            return Ok((addresses[index], seeds[index].clone()));
+        }
+    }
+    unreachable!("the seeds of a wallet never run out")
+}
+
+/// What to do with the existing stake accounts of the wallets.
+#[derive(Debug, Clone, Copy)]
+pub enum StakeAction {
+    /// Delegate the inactive accounts to this vote account.
+    Delegate(Pubkey),
+    /// Deactivate the activating and active accounts.
+    Deactivate,
+    /// Withdraw everything the inactive accounts hold back to the wallet,
+    /// which closes them.
+    Withdraw,
+}
+
+impl StakeAction {
+    fn name(self) -> &'static str {
+        match self {
+            StakeAction::Delegate(_) => "delegate",
+            StakeAction::Deactivate => "deactivate",
+            StakeAction::Withdraw => "withdraw",
+        }
+    }
+    fn applies_to(self, account: &StakeAccount) -> bool {
+        match self {
+            StakeAction::Delegate(_) | StakeAction::Withdraw => {
+                account.activation == Activation::Inactive
+            }
+            StakeAction::Deactivate => matches!(
+                account.activation,
+                Activation::Activating | Activation::Active
+            ),
+        }
+    }
+    fn instruction(self, account: &StakeAccount) -> Instruction {
+        match self {
+            StakeAction::Delegate(vote_account) => {
+                stake_instruction::delegate_stake(&account.address, &account.wallet, &vote_account)
+            }
+            StakeAction::Deactivate => {
+                stake_instruction::deactivate_stake(&account.address, &account.wallet)
+            }
+            StakeAction::Withdraw => stake_instruction::withdraw(
+                &account.address,
+                &account.wallet,
+                &account.wallet,
+                account.lamports,
+                None,
+            ),
+        }
+    }
+}
+
+/// Delegate, deactivate or withdraw the stake accounts of every wallet of a
+/// set, the ones the action applies to. The stake accounts of a wallet are
+/// batched in as few transactions as possible, each wallet pays its own
+/// fee.
+pub struct ManageStake {
+    action: StakeAction,
+    stake_account: Option<Pubkey>,
+}
+
+impl ManageStake {
+    pub fn new(action: StakeAction) -> Self {
+        Self {
+            action,
+            stake_account: None,
+        }
+    }
+    /// Only touch this stake account.
+    pub fn stake_account(mut self, stake_account: Pubkey) -> Self {
+        self.stake_account = Some(stake_account);
+        self
+    }
+    pub async fn run(&self, tool: &Tool, wallets: &WalletSet) -> Result<StakeReport> {
+        let action = self.action;
+        let mut report = StakeReport::default();
+        let mut batches = vec![];
+        let stake_epoch = tool.stake_epoch().await?;
+        for wallet in wallets.iter() {
+            let owner = wallet.pubkey();
+            let accounts: Vec<StakeAccount> = match tool.stake_accounts(&owner, &stake_epoch).await
+            {
+                Ok(accounts) => accounts
+                    .into_iter()
+                    .filter(|account| {
+                        self.stake_account
+                            .is_none_or(|stake_account| stake_account == account.address)
+                    })
+                    .collect(),
+                Err(e) => {
+                    error!(
+                        "Failed to get the stake accounts of {} with error: {}",
+                        owner, e
+                    );
+                    report.failures.push(Failure::new(owner, e));
+                    continue;
+                }
+            };
+            let (selected, left): (Vec<_>, Vec<_>) = accounts
+                .into_iter()
+                .partition(|account| action.applies_to(account));
+            for account in &left {
+                warn!(
+                    "{} of {} is {}, it can not {}, skip it",
+                    account.address,
+                    owner,
+                    account.activation,
+                    action.name()
+                );
+            }
+            report
+                .entries
+                .extend(stake_entries(action, &left, Status::Skipped));
+            if selected.is_empty() {
+                info!("{} has nothing to {}", owner, action.name());
+                continue;
+            }
+            for chunk in selected.chunks(MAX_STAKE_INSTRUCTIONS_PER_TRANSACTION) {
+                batches.push((owner, chunk.to_vec(), wallet.signer.clone()));
+            }
+        }
+        // a withdraw moves the sol out of the stake accounts
+        if let StakeAction::Withdraw = action {
+            let planned: Vec<Transfer> = batches
+                .iter()
+                .flat_map(|(_, chunk, _)| chunk.iter().map(withdrawal))
+                .collect();
+            tool.authorize(&planned, false)?;
+        }
+        let mut tasks = vec![];
+        for (owner, chunk, signer) in batches {
+            let tool = tool.clone();
+            let task = tokio::spawn({
+                let chunk = chunk.clone();
+                async move {
+                    let instructions = chunk
+                        .iter()
+                        .map(|account| action.instruction(account))
+                        .collect();
+                    let result = tool
+                        .sendtxn_and_watch(instructions, &*signer, &*signer)
+                        .await;
+                    match result {
+                        Ok(ref signature) => info!(
+                            "Successfuly sent {} {} of {}, check the info: {}",
+                            chunk.len(),
+                            action.name(),
+                            owner,
+                            tool.explorer_link(signature)
+                        ),
+                        Err(ref e) => error!(
+                            "Failed to {} the stake accounts of {} with error: {}",
+                            action.name(),
+                            owner,
+                            e
+                        ),
+                    }
+                    let entries = stake_entries(action, &chunk, Status::of(owner, result));
+                    for entry in &entries {
+                        tool.output.emit(entry.record());
+                    }
+                    entries
+                }
+            });
+            tasks.push((chunk, task));
+        }
+        report.entries.extend(
+            join_entries(tasks, |chunk, failure| {
+                stake_entries(action, &chunk, Status::Failed(failure))
+            })
+            .await
+            .into_iter()
+            .flatten(),
+        );
+        if let StakeAction::Withdraw = action {
+            let sent: Vec<Transfer> = report.entries.iter().filter_map(sent_stake).collect();
+            report.failures.extend(tool.record_spending(&sent));
+        }
+        Ok(report)
+    }
+}
+
+/// The entries of `accounts`, sent together in one transaction.
+fn stake_entries(
+    action: StakeAction,
+    accounts: &[StakeAccount],
+    status: Status,
+) -> Vec<StakeEntry> {
+    accounts
+        .iter()
+        .map(|account| StakeEntry {
+            action: action.name(),
+            wallet: account.wallet,
+            stake_account: Some(account.address),
+            lamports: account.lamports,
+            status: status.clone(),
+        })
+        .collect()
+}
+
+/// Withdrawing everything a stake account holds back to its wallet.
+fn withdrawal(account: &StakeAccount) -> Transfer {
+    Transfer {
+        from: account.address,
+        to: account.wallet,
+        mint: None,
+        amount: account.lamports,
+    }
+}
+
+/// The sol a sent create or withdraw moved, for the spending policy.
+fn sent_stake(entry: &StakeEntry) -> Option<Transfer> {
+    let Status::Sent(_) = entry.status else {
+        return None;
+    };
+    let stake_account = entry.stake_account?;
+    let (from, to) = match entry.action {
+        "create" => (entry.wallet, stake_account),
+        _ => (stake_account, entry.wallet),
+    };
+    Some(Transfer {
+        from,
+        to,
+        mint: None,
+        amount: entry.lamports,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::stake::{
+        stake_flags::StakeFlags,
+        state::{Delegation, Meta, Stake},
+    };
+    use solana_sdk::stake_history::StakeHistoryEntry;
+
+    const STAKE: u64 = 1_000_000_000;
+
+    fn activation(delegation: Delegation, epoch: Epoch, history: StakeHistory) -> Activation {
+        let state = StakeStateV2::Stake(
+            Meta::default(),
+            Stake {
+                delegation,
+                credits_observed: 0,
+            },
+            StakeFlags::empty(),
+        );
+        let at = StakeEpoch {
+            epoch,
+            history,
+            new_rate_activation_epoch: None,
+        };
+        let data = bincode::serialize(&state).unwrap();
+        StakeAccount::parse(
+            Pubkey::new_unique(),
+            Pubkey::new_unique(),
+            STAKE,
+            &data,
+            &at,
+        )
+        .unwrap()
+        .activation
+    }
+
+    fn history(epoch: Epoch, entry: StakeHistoryEntry) -> StakeHistory {
+        let mut history = StakeHistory::default();
+        history.add(epoch, entry);
+        history
+    }
+
+    #[test]
+    fn a_deactivated_stake_cools_down_before_it_is_inactive() {
+        let mut delegation = Delegation::new(&Pubkey::new_unique(), STAKE, 0);
+        delegation.deactivation_epoch = 10;
+        // the cluster cools down a quarter of its stake in an epoch, shared by
+        // everything deactivating
+        let busy = history(
+            10,
+            StakeHistoryEntry {
+                effective: 10 * STAKE,
+                activating: 0,
+                deactivating: 10 * STAKE,
+            },
+        );
+        assert_eq!(activation(delegation, 11, busy), Activation::Deactivating);
+        let quiet = history(
+            10,
+            StakeHistoryEntry {
+                effective: 100 * STAKE,
+                activating: 0,
+                deactivating: STAKE,
+            },
+        );
+        assert_eq!(activation(delegation, 11, quiet), Activation::Inactive);
+        assert_eq!(
+            activation(delegation, 10, StakeHistory::default()),
+            Activation::Deactivating
+        );
+    }
+
+    #[test]
+    fn a_delegated_stake_warms_up_before_it_is_active() {
+        let delegation = Delegation::new(&Pubkey::new_unique(), STAKE, 10);
+        let busy = history(
+            10,
+            StakeHistoryEntry {
+                effective: 10 * STAKE,
+                activating: 10 * STAKE,
+                deactivating: 0,
+            },
+        );
+        assert_eq!(activation(delegation, 11, busy), Activation::Activating);
+        let quiet = history(
+            10,
+            StakeHistoryEntry {
+                effective: 100 * STAKE,
+                activating: STAKE,
+                deactivating: 0,
+            },
+        );
+        assert_eq!(activation(delegation, 11, quiet), Activation::Active);
+    }
+}
@@ -0,0 +1,700 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::anyhow;
+use log::{error, info, warn};
+use solana_program::program_pack::Pack;
+use solana_sdk::{
+    instruction::Instruction, pubkey::Pubkey, signature::Signature, system_instruction,
+};
+use spl_associated_token_account::{
+    get_associated_token_address, instruction::create_associated_token_account_idempotent,
+};
+use spl_token::instruction::{
+    burn_checked, freeze_account, initialize_mint2, mint_to_checked, set_authority, thaw_account,
+    AuthorityType,
+};
+use spl_token::state::Mint;
+
+use crate::error::{parse_pubkey, Error, Result};
+use crate::outcome::{Failure, Outcome, Step};
+use crate::output::Record;
+use crate::plan::RunSummary;
+use crate::report::{
+    join_entries, FreezeEntry, FreezeReport, Status, Transfer, TransferEntry, TransferReport,
+};
+use crate::wallet_set::{SharedSigner, WalletSet, WalletSigner};
+use crate::Tool;
+
+/// How many freezes or thaws go in one transaction.
+const MAX_FREEZES_PER_TRANSACTION: usize = 20;
+
+/// The state of a mint.
+#[derive(Debug, Clone)]
+pub struct MintInfo {
+    pub address: Pubkey,
+    /// The raw amount in circulation.
+    pub supply: u64,
+    pub decimals: u8,
+    pub mint_authority: Option<Pubkey>,
+    pub freeze_authority: Option<Pubkey>,
+}
+
+impl MintInfo {
+    pub fn record(&self) -> Record {
+        Record::Mint {
+            mint: self.address.to_string(),
+            supply: self.supply,
+            decimals: self.decimals,
+            mint_authority: self.mint_authority.map(|authority| authority.to_string()),
+            freeze_authority: self.freeze_authority.map(|authority| authority.to_string()),
+        }
+    }
+}
+
+/// Create a new mint, the payer is its mint authority unless told otherwise.
+pub struct CreateMint {
+    decimals: u8,
+    mint_authority: Option<Pubkey>,
+    freeze_authority: Option<Pubkey>,
+}
+
+impl CreateMint {
+    pub fn new(decimals: u8) -> Self {
+        Self {
+            decimals,
+            mint_authority: None,
+            freeze_authority: None,
+        }
+    }
+    pub fn mint_authority(mut self, authority: Pubkey) -> Self {
+        self.mint_authority = Some(authority);
+        self
+    }
+    /// Without one the token accounts of the mint can never be frozen.
+    pub fn freeze_authority(mut self, authority: Pubkey) -> Self {
+        self.freeze_authority = Some(authority);
+        self
+    }
+    /// Create the account of `mint` and initialize it in one transaction.
+    pub async fn run(
+        &self,
+        tool: &Tool,
+        payer: &WalletSigner,
+        mint: &WalletSigner,
+    ) -> Result<MintInfo> {
+        let rent = tool.minimum_balance(Mint::LEN).await?;
+        let mint_authority = self.mint_authority.unwrap_or_else(|| payer.pubkey());
+        let instructions = vec![
+            system_instruction::create_account(
+                &payer.pubkey(),
+                &mint.pubkey(),
+                rent,
+                Mint::LEN as u64,
+                &spl_token::id(),
+            ),
+            initialize_mint2(
+                &spl_token::id(),
+                &mint.pubkey(),
+                &mint_authority,
+                self.freeze_authority.as_ref(),
+                self.decimals,
+            )?,
+        ];
+        let signature = tool.sendtxn_and_watch(instructions, mint, payer).await?;
+        info!(
+            "Successfuly created the mint {}, check the info: {}",
+            mint.pubkey(),
+            tool.explorer_link(&signature)
+        );
+        Ok(MintInfo {
+            address: mint.pubkey(),
+            supply: 0,
+            decimals: self.decimals,
+            mint_authority: Some(mint_authority),
+            freeze_authority: self.freeze_authority,
+        })
+    }
+}
+
+/// The wallets of a mint-to and the raw amount each of them gets.
+pub type Recipients = Vec<(Pubkey, u64)>;
+
+/// Read the recipients of a mint-to from a csv file of `address,amount`
+/// lines, the amount may be left out for the default one. A header line is
+/// skipped.
+pub fn read_recipients(
+    path: impl AsRef<Path>,
+    default_amount: Option<u64>,
+) -> Result<(Recipients, Vec<Failure>)> {
+    let path = path.as_ref();
+    let content = fs::read_to_string(path).map_err(|e| Error::io(path.display(), e))?;
+    let mut recipients = vec![];
+    let mut unreadable = vec![];
+    for (index, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let origin = format!("{}:{}", path.display(), index + 1);
+        let mut fields = line.split(',').map(|field| field.trim().trim_matches('"'));
+        let address = match parse_pubkey(fields.next().unwrap_or_default()) {
+            Ok(address) => address,
+            // the header
+            Err(_) if index == 0 => continue,
+            Err(e) => {
+                unreadable.push(Failure::new(origin, e));
+                continue;
+            }
+        };
+        let amount = match fields.next().filter(|field| !field.is_empty()) {
+            Some(field) => field
+                .parse()
+                .map_err(|_| anyhow!("invalid amount {}", field).into()),
+            None => default_amount.ok_or_else(|| Error::from(anyhow!("no amount in the line"))),
+        };
+        match amount {
+            Ok(amount) => recipients.push((address, amount)),
+            Err(e) => unreadable.push(Failure::new(origin, e)),
+        }
+    }
+    Ok((recipients, unreadable))
+}
+
+/// Mint a token to a list of wallets, their token accounts are created
+/// where missing.
+pub struct MintTo {
+    authority: SharedSigner,
+    mint: Pubkey,
+    decimals: u8,
+}
+
+impl MintTo {
+    /// `authority` is the mint authority and pays the fees and the rent.
+    pub fn new(authority: SharedSigner, mint: Pubkey, decimals: u8) -> Self {
+        Self {
+            authority,
+            mint,
+            decimals,
+        }
+    }
+    fn instructions(&self, recipient: &Pubkey, amount: u64) -> Result<Vec<Instruction>> {
+        mint_to_instructions(
+            &self.authority.pubkey(),
+            recipient,
+            &self.mint,
+            amount,
+            self.decimals,
+        )
+    }
+    /// Work out what `run` is going to mint, nothing is sent.
+    pub async fn plan(&self, tool: &Tool, recipients: &[(Pubkey, u64)]) -> Result<RunSummary> {
+        let authority = self.authority.pubkey();
+        let accounts: Vec<Pubkey> = recipients
+            .iter()
+            .map(|(recipient, _)| get_associated_token_address(recipient, &self.mint))
+            .collect();
+        let accounts_to_create = tool.count_missing(&accounts).await?;
+        let mut summary = RunSummary {
+            cluster: tool.cluster().await?,
+            recipients: recipients.len(),
+            total_amount: recipients.iter().map(|(_, amount)| amount).sum(),
+            mint: Some(self.mint),
+            decimals: self.decimals,
+            accounts_to_create,
+            account_rent: tool.token_account_rent(accounts_to_create).await?,
+            estimated_fee: 0,
+            allocation: vec![],
+            transfers: vec![],
+            frozen: vec![],
+        };
+        if let Some((sample, amount)) = recipients.first() {
+            let instructions = self.instructions(sample, *amount)?;
+            summary.estimated_fee =
+                tool.estimate_fee(instructions, &authority).await? * recipients.len() as u64;
+        }
+        Ok(summary)
+    }
+    pub async fn run(&self, tool: &Tool, recipients: &[(Pubkey, u64)]) -> Result<TransferReport> {
+        let planned: Vec<Transfer> = recipients
+            .iter()
+            .map(|(recipient, amount)| Transfer {
+                from: self.mint,
+                to: *recipient,
+                mint: Some(self.mint),
+                amount: *amount,
+            })
+            .collect();
+        tool.authorize(&planned, false)?;
+        let mut tasks = vec![];
+        for transfer in planned {
+            let (recipient, amount) = (transfer.to, transfer.amount);
+            let instructions = self.instructions(&recipient, amount);
+            let authority = self.authority.clone();
+            let tool = tool.clone();
+            let task = tokio::spawn(async move {
+                let result = match instructions {
+                    Ok(instructions) => {
+                        tool.sendtxn_and_watch(instructions, &*authority, &*authority)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(ref signature) => info!(
+                        "Successfuly minted {} to {}, check the info: {}",
+                        amount,
+                        recipient,
+                        tool.explorer_link(signature)
+                    ),
+                    Err(ref e) => error!("Failed to mint to {} with error: {}", recipient, e),
+                }
+                let entry = TransferEntry {
+                    transfer,
+                    status: Status::of(recipient, result),
+                };
+                tool.output.emit(entry.record());
+                entry
+            });
+            tasks.push((transfer, task));
+        }
+        let transfers = join_entries(tasks, |transfer, failure| TransferEntry {
+            transfer,
+            status: Status::Failed(failure),
+        })
+        .await;
+        let mut report = TransferReport {
+            transfers,
+            failures: vec![],
+        };
+        let sent = report.sent();
+        report.failures.extend(tool.record_spending(&sent));
+        Ok(report)
+    }
+}
+
+/// Burn a token out of every wallet of a set.
+pub struct Burn {
+    payer: SharedSigner,
+    mint: Pubkey,
+    decimals: u8,
+    amount: Option<u64>,
+}
+
+/// The burns worked out by `Burn::plan`, `Burn::run` sends exactly them.
+pub struct BurnPlan {
+    /// What to confirm.
+    pub summary: RunSummary,
+    burns: Vec<(Transfer, Result<Vec<Instruction>>, SharedSigner)>,
+    /// The wallets with nothing to burn or not enough, and those whose
+    /// balance could not be read.
+    settled: TransferReport,
+}
+
+impl Burn {
+    /// `payer` pays the fees, by default every wallet burns all it holds.
+    pub fn new(payer: SharedSigner, mint: Pubkey, decimals: u8) -> Self {
+        Self {
+            payer,
+            mint,
+            decimals,
+            amount: None,
+        }
+    }
+    /// Burn this raw amount of every wallet instead.
+    pub fn amount(mut self, amount: u64) -> Self {
+        self.amount = Some(amount);
+        self
+    }
+    /// Read the balance of every wallet and work out what `run` is going to
+    /// burn, nothing is sent.
+    pub async fn plan(&self, tool: &Tool, wallets: &WalletSet) -> Result<BurnPlan> {
+        let mut settled = TransferReport::default();
+        let mut burns = vec![];
+        for wallet in wallets.iter() {
+            let owner = wallet.pubkey();
+            let mut transfer = Transfer {
+                from: owner,
+                to: self.mint,
+                mint: Some(self.mint),
+                amount: 0,
+            };
+            let holdings = match tool.token_holdings(&owner, Some(&self.mint)).await {
+                Ok(holdings) => holdings,
+                Err(e) => {
+                    error!("Failed to get the balance of {} with error: {}", owner, e);
+                    settled.failures.push(Failure::new(owner, e));
+                    continue;
+                }
+            };
+            // take the amount out of the accounts one after the other
+            let mut left = self.amount.unwrap_or(u64::MAX);
+            let mut sources = vec![];
+            for holding in &holdings {
+                let amount = left.min(holding.amount);
+                if amount > 0 {
+                    sources.push((holding.address, amount));
+                    left -= amount;
+                }
+            }
+            transfer.amount = sources.iter().map(|(_, amount)| amount).sum();
+            let held: u64 = holdings.iter().map(|holding| holding.amount).sum();
+            let status = match self.amount {
+                Some(amount) if held < amount => {
+                    let e = Error::from(anyhow!(
+                        "{} holds {} of the token {}, not enough to burn {}",
+                        owner,
+                        held,
+                        self.mint,
+                        amount
+                    ));
+                    error!("Refuse to burn the token of {} with error: {}", owner, e);
+                    Some(Status::of(owner, Err(e)))
+                }
+                _ if sources.is_empty() => Some(Status::Skipped),
+                _ => None,
+            };
+            if let Some(status) = status {
+                settled.transfers.push(TransferEntry { transfer, status });
+                continue;
+            }
+            let instructions = sources
+                .iter()
+                .map(|(source, amount)| {
+                    Ok(burn_checked(
+                        &spl_token::id(),
+                        source,
+                        &self.mint,
+                        &owner,
+                        &[&owner],
+                        *amount,
+                        self.decimals,
+                    )?)
+                })
+                .collect::<Result<Vec<_>>>();
+            burns.push((transfer, instructions, wallet.signer.clone()));
+        }
+        let transfers: Vec<Transfer> = burns.iter().map(|(transfer, _, _)| *transfer).collect();
+        let mut summary = RunSummary {
+            cluster: tool.cluster().await?,
+            recipients: transfers.len(),
+            total_amount: transfers.iter().map(|transfer| transfer.amount).sum(),
+            mint: Some(self.mint),
+            decimals: self.decimals,
+            accounts_to_create: 0,
+            account_rent: 0,
+            estimated_fee: 0,
+            allocation: vec![],
+            transfers,
+            frozen: vec![],
+        };
+        if let Some(Ok(instructions)) = burns.first().map(|(_, instructions, _)| instructions) {
+            summary.estimated_fee = tool
+                .estimate_fee(instructions.clone(), &self.payer.pubkey())
+                .await?
+                * burns.len() as u64;
+        }
+        Ok(BurnPlan {
+            summary,
+            burns,
+            settled,
+        })
+    }
+    pub async fn run(&self, tool: &Tool, plan: BurnPlan) -> Result<TransferReport> {
+        tool.authorize(&plan.summary.transfers, false)?;
+        let mut report = plan.settled;
+        for entry in &report.transfers {
+            if let Status::Failed(_) = entry.status {
+                tool.output.emit(entry.record());
+            }
+        }
+        let mut tasks = vec![];
+        for (transfer, instructions, signer) in plan.burns {
+            let owner = transfer.from;
+            let payer = self.payer.clone();
+            let tool = tool.clone();
+            let task = tokio::spawn(async move {
+                let result = match instructions {
+                    Ok(instructions) => {
+                        tool.sendtxn_and_watch(instructions, &*signer, &*payer)
+                            .await
+                    }
+                    Err(e) => Err(e),
+                };
+                match result {
+                    Ok(ref signature) => info!(
+                        "Successfuly burned {} of {}, check the info: {}",
+                        transfer.amount,
+                        owner,
+                        tool.explorer_link(signature)
+                    ),
+                    Err(ref e) => error!("Failed to burn the token of {} with error: {}", owner, e),
+                }
+                let entry = TransferEntry {
+                    transfer,
+                    status: Status::of(owner, result),
+                };
+                tool.output.emit(entry.record());
+                entry
+            });
+            tasks.push((transfer, task));
+        }
+        report.transfers.extend(
+            join_entries(tasks, |transfer, failure| TransferEntry {
+                transfer,
+                status: Status::Failed(failure),
+            })
+            .await,
+        );
+        let sent = report.sent();
+        report.failures.extend(tool.record_spending(&sent));
+        Ok(report)
+    }
+}
+
+/// Freeze, or thaw, the token accounts of a mint of every wallet of a set.
+/// Only the freeze authority signs, so the accounts of several wallets go in
+/// one transaction.
+pub struct Freeze {
+    authority: SharedSigner,
+    mint: Pubkey,
+    thaw: bool,
+}
+
+impl Freeze {
+    /// `authority` is the freeze authority of `mint` and pays the fees.
+    pub fn new(authority: SharedSigner, mint: Pubkey) -> Self {
+        Self {
+            authority,
+            mint,
+            thaw: false,
+        }
+    }
+    /// Thaw the frozen accounts instead.
+    pub fn thaw(mut self) -> Self {
+        self.thaw = true;
+        self
+    }
+    pub async fn run(&self, tool: &Tool, wallets: &WalletSet) -> Result<FreezeReport> {
+        let frozen = !self.thaw;
+        let action = match frozen {
+            true => "freeze",
+            false => "thaw",
+        };
+        let mut report = FreezeReport::default();
+        let mut pending = vec![];
+        for wallet in wallets.iter() {
+            let owner = wallet.pubkey();
+            let holdings = match tool.token_holdings(&owner, Some(&self.mint)).await {
+                Ok(holdings) => holdings,
+                Err(e) => {
+                    error!(
+                        "Failed to list the token accounts of {} with error: {}",
+                        owner, e
+                    );
+                    report.failures.push(Failure::new(owner, e));
+                    continue;
+                }
+            };
+            if holdings.is_empty() {
+                warn!("{} has no token account of {}, skip it", owner, self.mint);
+            }
+            for holding in holdings {
+                let entry = FreezeEntry {
+                    wallet: owner,
+                    account: holding.address,
+                    mint: self.mint,
+                    frozen,
+                    status: Status::Skipped,
+                };
+                // already where it should be
+                match holding.frozen == frozen {
+                    true => report.accounts.push(entry),
+                    false => pending.push(entry),
+                }
+            }
+        }
+        let mut tasks = vec![];
+        for chunk in pending.chunks(MAX_FREEZES_PER_TRANSACTION) {
+            let accounts: Vec<(Pubkey, Pubkey)> = chunk
+                .iter()
+                .map(|entry| (entry.wallet, entry.account))
+                .collect();
+            let authority = self.authority.clone();
+            let mint = self.mint;
+            let tool = tool.clone();
+            let task = tokio::spawn({
+                let accounts = accounts.clone();
+                async move {
+                    let instructions = accounts
+                        .iter()
+                        .map(|(_, account)| {
+                            let authority = authority.pubkey();
+                            let instruction = match frozen {
+                                true => freeze_account(
+                                    &spl_token::id(),
+                                    account,
+                                    &mint,
+                                    &authority,
+                                    &[&authority],
+                                ),
+                                false => thaw_account(
+                                    &spl_token::id(),
+                                    account,
+                                    &mint,
+                                    &authority,
+                                    &[&authority],
+                                ),
+                            };
+                            Ok(instruction?)
+                        })
+                        .collect::<Result<Vec<_>>>();
+                    let result = match instructions {
+                        Ok(instructions) => {
+                            tool.sendtxn_and_watch(instructions, &*authority, &*authority)
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(ref signature) => info!(
+                            "Successfuly {} {} token accounts, check the info: {}",
+                            match frozen {
+                                true => "froze",
+                                false => "thawed",
+                            },
+                            accounts.len(),
+                            tool.explorer_link(signature)
+                        ),
+                        Err(ref e) => error!(
+                            "Failed to {} {} token accounts with error: {}",
+                            action,
+                            accounts.len(),
+                            e
+                        ),
+                    }
+                    let status = Status::of(mint, result);
+                    let entries = freeze_entries(&accounts, mint, frozen, status);
+                    for entry in &entries {
+                        tool.output.emit(entry.record());
+                    }
+                    entries
+                }
+            });
+            tasks.push((accounts, task));
+        }
+        report.accounts.extend(
+            join_entries(tasks, |accounts, failure| {
+                freeze_entries(&accounts, self.mint, frozen, Status::Failed(failure))
+            })
+            .await
+            .into_iter()
+            .flatten(),
+        );
+        Ok(report)
+    }
+}
+
+/// The entries of the `(wallet, account)` pairs sent together in one
+/// transaction.
+fn freeze_entries(
+    accounts: &[(Pubkey, Pubkey)],
+    mint: Pubkey,
+    frozen: bool,
+    status: Status,
+) -> Vec<FreezeEntry> {
+    accounts
+        .iter()
+        .map(|(wallet, account)| FreezeEntry {
+            wallet: *wallet,
+            account: *account,
+            mint,
+            frozen,
+            status: status.clone(),
+        })
+        .collect()
+}
+
+/// Create the token account of `recipient` when missing and mint `amount`
+/// into it.
+fn mint_to_instructions(
+    authority: &Pubkey,
+    recipient: &Pubkey,
+    mint: &Pubkey,
+    amount: u64,
+    decimals: u8,
+) -> Result<Vec<Instruction>> {
+    Ok(vec![
+        create_associated_token_account_idempotent(authority, recipient, mint, &spl_token::id()),
+        mint_to_checked(
+            &spl_token::id(),
+            mint,
+            &get_associated_token_address(recipient, mint),
+            authority,
+            &[authority],
+            amount,
+            decimals,
+        )?,
+    ])
+}
+
+impl Tool {
+    pub async fn mint_info(&self, mint: &Pubkey) -> Result<MintInfo> {
+        let address = *mint;
+        let account = self
+            .rpc_pool
+            .call(|client| async move { client.get_account(&address).await })
+            .await?;
+        let state = Mint::unpack(&account.data).map_err(|source| Error::NotMint {
+            account: address.to_string(),
+            source,
+        })?;
+        Ok(MintInfo {
+            address,
+            supply: state.supply,
+            decimals: state.decimals,
+            mint_authority: state.mint_authority.into(),
+            freeze_authority: state.freeze_authority.into(),
+        })
+    }
+    /// Log the state of `mint` and emit it as a record.
+    pub async fn show_mint_info(&self, mint: &Pubkey) -> Result<Outcome> {
+        let info = self.mint_info(mint).await?;
+        let authority = |authority: Option<Pubkey>| {
+            authority.map_or_else(|| "none".to_string(), |authority| authority.to_string())
+        };
+        info!(
+            "{}: supply {} ({} raw), {} decimals, mint authority {}, freeze authority {}",
+            mint,
+            spl_token::amount_to_ui_amount_string(info.supply, info.decimals),
+            info.supply,
+            info.decimals,
+            authority(info.mint_authority),
+            authority(info.freeze_authority)
+        );
+        self.output.emit(info.record());
+        let mut outcome = Outcome::default();
+        outcome.add(Step::Succeeded);
+        Ok(outcome)
+    }
+    /// Hand the mint or freeze authority of `mint` to `new_authority`, `None`
+    /// gives it up for good.
+    pub async fn set_mint_authority(
+        &self,
+        mint: &Pubkey,
+        authority_type: AuthorityType,
+        new_authority: Option<&Pubkey>,
+        authority: &WalletSigner,
+    ) -> Result<Signature> {
+        let instruction = set_authority(
+            &spl_token::id(),
+            mint,
+            new_authority,
+            authority_type,
+            &authority.pubkey(),
+            &[&authority.pubkey()],
+        )?;
+        self.sendtxn_and_watch(vec![instruction], authority, authority)
+            .await
+    }
+}
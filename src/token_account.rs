@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use serde_json::json;
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::rpc_config::RpcAccountInfoConfig;
+use solana_client::rpc_request::RpcRequest;
+use solana_client::rpc_response::{Response, RpcKeyedAccount};
+use solana_program::program_pack::Pack;
+use solana_sdk::{account::Account, pubkey::Pubkey};
+use spl_token::state::Mint;
+
+use crate::error::{Error, Result};
+use crate::send_and_check::unpack_token_account;
+use crate::Tool;
+
+/// A token account of a wallet with the decimals of its mint.
+#[derive(Debug, Clone)]
+pub struct TokenHolding {
+    pub address: Pubkey,
+    pub mint: Pubkey,
+    /// The raw token amount.
+    pub amount: u64,
+    pub decimals: u8,
+    /// The freeze authority of the mint froze the account, nothing can be
+    /// moved out of or into it.
+    pub frozen: bool,
+}
+
+impl TokenHolding {
+    pub fn ui_amount(&self) -> f64 {
+        spl_token::amount_to_ui_amount(self.amount, self.decimals)
+    }
+}
+
+impl Tool {
+    /// Every spl token account `owner` has, empty ones included, only the
+    /// ones of `mint` when it is given.
+    pub async fn token_holdings(
+        &self,
+        owner: &Pubkey,
+        mint: Option<&Pubkey>,
+    ) -> Result<Vec<TokenHolding>> {
+        let accounts = self.token_accounts(owner, mint).await?;
+        let mut mints: Vec<Pubkey> = accounts.iter().map(|(_, state)| state.mint).collect();
+        mints.sort();
+        mints.dedup();
+        let mut decimals = HashMap::new();
+        for (mint, account) in mints.iter().zip(self.get_accounts(&mints).await?) {
+            let account = account.ok_or_else(|| anyhow!("the mint {} does not exist", mint))?;
+            let state = Mint::unpack(&account.data).map_err(|source| Error::NotMint {
+                account: mint.to_string(),
+                source,
+            })?;
+            decimals.insert(*mint, state.decimals);
+        }
+        Ok(accounts
+            .into_iter()
+            .map(|(address, state)| TokenHolding {
+                address,
+                mint: state.mint,
+                amount: state.amount,
+                decimals: decimals[&state.mint],
+                frozen: state.is_frozen(),
+            })
+            .collect())
+    }
+    /// What `owner` holds of `mint` over all its token accounts, not only
+    /// the associated one.
+    pub async fn token_balance(&self, owner: &Pubkey, mint: &Pubkey) -> Result<u64> {
+        let holdings = self.token_holdings(owner, Some(mint)).await?;
+        Ok(holdings.iter().map(|holding| holding.amount).sum())
+    }
+    /// Every spl token account of `owner` with its full state, decoded from
+    /// the raw account data, only the ones of `mint` when it is given.
+    pub async fn token_accounts(
+        &self,
+        owner: &Pubkey,
+        mint: Option<&Pubkey>,
+    ) -> Result<Vec<(Pubkey, spl_token::state::Account)>> {
+        let owner = *owner;
+        let filter = match mint {
+            Some(mint) => json!({ "mint": mint.to_string() }),
+            None => json!({ "programId": spl_token::id().to_string() }),
+        };
+        let filter = &filter;
+        let accounts = self
+            .rpc_pool
+            .call(|client| async move {
+                let config = RpcAccountInfoConfig {
+                    encoding: Some(UiAccountEncoding::Base64),
+                    commitment: Some(client.commitment()),
+                    ..RpcAccountInfoConfig::default()
+                };
+                client
+                    .send::<Response<Vec<RpcKeyedAccount>>>(
+                        RpcRequest::GetTokenAccountsByOwner,
+                        json!([owner.to_string(), filter, config]),
+                    )
+                    .await
+            })
+            .await?;
+        accounts
+            .value
+            .iter()
+            .map(|keyed| {
+                let invalid = || anyhow!("the token account {} can not be decoded", keyed.pubkey);
+                let address = Pubkey::from_str(&keyed.pubkey).map_err(|_| invalid())?;
+                let account: Account = keyed.account.decode().ok_or_else(invalid)?;
+                Ok((address, unpack_token_account(&address, &account.data)?))
+            })
+            .collect()
+    }
+}
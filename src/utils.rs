@@ -1,18 +1,23 @@
+use std::path::Path;
+
 use anyhow::Result;
 use fern::colors::*;
 use log::LevelFilter;
+use solana_sdk::signature::{read_keypair_file, Keypair};
+
+use crate::error::Error;
 
-pub fn setup_logger() -> Result<()> {
+/// Log to stdout, or to stderr when stdout carries the json output.
+pub fn setup_logger(to_stderr: bool) -> Result<()> {
     let colors = ColoredLevelConfig {
         trace: Color::Cyan,
         debug: Color::Magenta,
         info: Color::Green,
         warn: Color::Red,
         error: Color::BrightRed,
-        ..ColoredLevelConfig::new()
     };
 
-    fern::Dispatch::new()
+    let dispatch = fern::Dispatch::new()
         .format(move |out, message, record| {
             out.finish(format_args!(
                 "{}[{}] {}",
@@ -21,11 +26,27 @@ pub fn setup_logger() -> Result<()> {
                 message
             ))
         })
-        .chain(std::io::stdout())
         .level(log::LevelFilter::Info)
-        .level_for("Silly Solana Tool", LevelFilter::Info)
-        .apply()
-        .unwrap();
+        .level_for("Silly Solana Tool", LevelFilter::Info);
+    if to_stderr {
+        dispatch.chain(std::io::stderr()).apply()?;
+    } else {
+        dispatch.chain(std::io::stdout()).apply()?;
+    }
 
     Ok(())
 }
+
+/// Read a keypair json file, the error names the file.
+pub fn read_keypair(path: impl AsRef<Path>) -> Result<Keypair, Error> {
+    let path = path.as_ref();
+    read_keypair_file(path).map_err(|e| Error::keypair(path.display(), e))
+}
+
+/// Parse a bs58 secret key like the wallets export it.
+pub fn keypair_from_bs58(secret: &str) -> Result<Keypair, Error> {
+    let bytes = bs58::decode(secret.trim())
+        .into_vec()
+        .map_err(|e| Error::InvalidKey(e.to_string()))?;
+    Keypair::from_bytes(&bytes).map_err(|e| Error::InvalidKey(e.to_string()))
+}
@@ -1,23 +1,25 @@
+use crate::error::{Error, Result};
+use crate::outcome::{Failure, Outcome, Step};
+use crate::output::{PortfolioToken, Record};
+use crate::stake::Activation;
+use crate::wallet_set::WalletSet;
 use crate::Tool;
-use core::sync::atomic::AtomicUsize;
+use anyhow::anyhow;
 use futures::future::join_all;
-use log::{error, info};
+use log::{error, info, warn};
 use solana_sdk::{
+    program_option::COption,
     pubkey::Pubkey,
-    signature::{read_keypair_file, write_keypair_file, Keypair},
+    signature::{write_keypair_file, Keypair},
     signer::Signer,
 };
-use std::sync::atomic::Ordering;
+use std::fs;
 use std::sync::Arc;
-use std::{fs, str::FromStr};
 use tokio::sync::Mutex;
-use tokio::sync::Semaphore;
-use tokio::time::sleep;
-use tokio::time::Duration;
-use tokio::time::Instant;
 
 impl Tool {
-    pub async fn generate_wallet(&self, amount: u64, output_folder: String) {
+    pub async fn generate_wallet(&self, amount: u64, output_folder: String) -> Result<Outcome> {
+        let mut outcome = Outcome::default();
         match fs::create_dir_all(&output_folder) {
             Ok(_folder) => {
                 for _ in 0..amount {
@@ -27,128 +29,254 @@ impl Tool {
                     let file_path = format!("{}/{}.json", &output_folder, pubkey);
                     match write_keypair_file(&new_keypair, &file_path) {
                         Ok(_) => {
-                            info!("successfully write the keypair to {:?}", &file_path)
+                            info!("successfully write the keypair to {:?}", &file_path);
+                            self.output.emit(Record::Wallet {
+                                pubkey: pubkey.to_string(),
+                                file: file_path,
+                            });
+                            outcome.add(Step::Succeeded);
                         }
                         Err(e) => {
-                            error!("can not write the keypair file with error: {:?}", e)
+                            error!("can not write the keypair file with error: {:?}", e);
+                            outcome.add(Step::Failed(Failure::new(
+                                &file_path,
+                                Error::keypair(&file_path, e),
+                            )));
                         }
                     }
                 }
+                Ok(outcome)
             }
-            Err(e) => {
-                error!("can not create or detect the folder with error: {:?}", e)
-            }
+            Err(e) => Err(Error::io(&output_folder, e)),
         }
     }
     pub async fn check_wallet_balance(
         &self,
-        sub_keypair_folder: String,
-        token_address: Option<String>,
-    ) {
+        wallets: &WalletSet,
+        coin_pubkey: Option<Pubkey>,
+    ) -> Result<Outcome> {
         let balance_accumulator = Arc::new(Mutex::new(0));
-        let semaphore = Arc::new(Semaphore::new(20));
-        match fs::read_dir(sub_keypair_folder) {
-            Ok(folder) => {
-                let mut tasks = vec![];
-                for file in folder {
-                    let file_entry = file.expect("Failed to entry the file");
-                    let file_path = file_entry.path();
-                    match read_keypair_file(file_path.clone()) {
-                        Ok(sub_keypair) => {
-                            let rpc_client_clone = self.rpc_client.clone();
-                            let balance_accumulator_clone = balance_accumulator.clone();
-                            let semaphore_clone = semaphore.clone();
+        let mut tasks = vec![];
+        for wallet in wallets.iter() {
+            let sub_keypair = wallet.signer.clone();
+            let tool = self.clone();
+            let balance_accumulator_clone = balance_accumulator.clone();
 
-                            match token_address {
-                                Some(ref coin_address) => {
-                                    let rpc_clone = rpc_client_clone.clone();
-                                    let coin_pubkey = Pubkey::from_str(&coin_address)
-                                        .expect("Failed to translate token address to pubkey");
-                                    let task = tokio::spawn(async move {
-                                        let sub_token_account = spl_associated_token_account::get_associated_token_address(&sub_keypair.pubkey(), &coin_pubkey);
-                                        let _permit = semaphore_clone.acquire().await.unwrap();
-                                        match Tool::get_spl_token_amount(
-                                            rpc_clone,
-                                            &sub_token_account,
-                                        )
-                                        .await
-                                        {
-                                            Ok(balance) => {
-                                                info!(
-                                                    "Successfully get {} spl token balance: {}",
-                                                    &sub_keypair.pubkey(),
-                                                    balance
-                                                );
-                                                let mut total_balance =
-                                                    balance_accumulator_clone.lock().await;
-                                                *total_balance += balance;
-                                            }
-                                            Err(e) => {
-                                                error!(
-                                                    "Failed to get {} token balance with error: {}",
-                                                    &sub_keypair.pubkey(),
-                                                    e
-                                                );
-                                            }
-                                        }
-                                        sleep(Duration::from_millis(50)).await;
-                                    });
-                                    tasks.push(task);
-                                }
-                                None => {
-                                    let task = tokio::spawn(async move {
-                                        let _permit = semaphore_clone.acquire().await.unwrap();
-                                        match rpc_client_clone
-                                            .get_balance(&sub_keypair.pubkey())
-                                            .await
+            match coin_pubkey {
+                Some(coin_pubkey) => {
+                    let task = tokio::spawn(async move {
+                        let owner = sub_keypair.pubkey();
+                        let result =
+                            tool.token_accounts(&owner, Some(&coin_pubkey))
+                                .await
+                                .map(|accounts| {
+                                    for (address, account) in &accounts {
+                                        if account.is_frozen()
+                                            || account.delegate.is_some()
+                                            || account.close_authority.is_some()
                                         {
-                                            Ok(balance) => {
-                                                info!(
-                                                    "Successfully to get {} sol balance: {}",
-                                                    &sub_keypair.pubkey(),
-                                                    balance
-                                                );
-                                                let mut total_balance =
-                                                    balance_accumulator_clone.lock().await;
-                                                *total_balance += balance;
-                                            }
-                                            Err(e) => {
-                                                error!(
-                                                    "Failed to get {} sol balance with error: {}",
-                                                    &sub_keypair.pubkey(),
-                                                    e
-                                                )
-                                            }
+                                            warn!(
+                                                "{} of {}: {:?}, delegate {}, close authority {}",
+                                                address,
+                                                owner,
+                                                account.state,
+                                                optional(account.delegate),
+                                                optional(account.close_authority)
+                                            );
                                         }
-                                        sleep(Duration::from_millis(50)).await;
-                                    });
-                                    tasks.push(task);
-                                }
+                                        tool.output
+                                            .emit(Record::token_account(&owner, address, account));
+                                    }
+                                    accounts.iter().map(|(_, account)| account.amount).sum()
+                                });
+                        tool.output.emit(Record::balance(
+                            &sub_keypair.pubkey(),
+                            Some(&coin_pubkey),
+                            &result,
+                        ));
+                        match result {
+                            Ok(balance) => {
+                                info!(
+                                    "Successfully get {} spl token balance: {}",
+                                    &sub_keypair.pubkey(),
+                                    balance
+                                );
+                                let mut total_balance = balance_accumulator_clone.lock().await;
+                                *total_balance += balance;
+                            }
+                            Err(ref e) => {
+                                error!(
+                                    "Failed to get {} token balance with error: {}",
+                                    &sub_keypair.pubkey(),
+                                    e
+                                );
                             }
                         }
-                        Err(e) => {
-                            error!(
-                                "Faild to read keypair from {:?} with error: {}",
-                                &file_path, e
-                            )
-                        }
-                    }
+                        Step::of(sub_keypair.pubkey(), result)
+                    });
+                    tasks.push(task);
                 }
-                let results = join_all(tasks).await;
-                for result in results {
-                    match result {
-                        Ok(_) => {}
-                        Err(e) => {
-                            error!("task handle failed with error: {}", e)
+                None => {
+                    let task = tokio::spawn(async move {
+                        let sub_pubkey = sub_keypair.pubkey();
+                        let result = tool
+                            .rpc_pool
+                            .call(|client| async move { client.get_balance(&sub_pubkey).await })
+                            .await;
+                        tool.output
+                            .emit(Record::balance(&sub_pubkey, None, &result));
+                        match result {
+                            Ok(balance) => {
+                                info!(
+                                    "Successfully to get {} sol balance: {}",
+                                    &sub_keypair.pubkey(),
+                                    balance
+                                );
+                                let mut total_balance = balance_accumulator_clone.lock().await;
+                                *total_balance += balance;
+                            }
+                            Err(ref e) => {
+                                error!(
+                                    "Failed to get {} sol balance with error: {}",
+                                    &sub_keypair.pubkey(),
+                                    e
+                                )
+                            }
                         }
-                    }
+                        Step::of(sub_pubkey, result)
+                    });
+                    tasks.push(task);
                 }
-                let total_balance = balance_accumulator.lock().await;
-                info!("Total balance of all sub wallets: {}", total_balance);
             }
+        }
+        let mut outcome = Outcome::default();
+        outcome.join(join_all(tasks).await);
+        let total_balance = balance_accumulator.lock().await;
+        info!("Total balance of all sub wallets: {}", total_balance);
+        Ok(outcome)
+    }
+    /// Log and emit what every wallet of a set holds: sol, every token
+    /// and the stake accounts. A wallet whose stake accounts can not be read
+    /// still gets its sol and tokens, and counts as failed.
+    pub async fn wallet_portfolio(&self, wallets: &WalletSet) -> Result<Outcome> {
+        let stake_epoch = match self.stake_epoch().await {
+            Ok(stake_epoch) => Some(Arc::new(stake_epoch)),
             Err(e) => {
-                error!("Failed to open the folder with error: {}", e)
+                error!("Failed to get the stake history with error: {}", e);
+                None
             }
+        };
+        let totals = Arc::new(Mutex::new((0u64, 0u64)));
+        let mut tasks = vec![];
+        for wallet in wallets.iter() {
+            let owner = wallet.pubkey();
+            let tool = self.clone();
+            let totals = totals.clone();
+            let stake_epoch = stake_epoch.clone();
+            tasks.push(tokio::spawn(async move {
+                let result = async {
+                    let lamports = tool
+                        .rpc_pool
+                        .call(|client| async move { client.get_balance(&owner).await })
+                        .await?;
+                    let holdings = tool.token_holdings(&owner, None).await?;
+                    Ok::<_, Error>((lamports, holdings))
+                }
+                .await;
+                let (lamports, holdings) = match result {
+                    Ok(portfolio) => portfolio,
+                    Err(e) => {
+                        error!("Failed to get the portfolio of {} with error: {}", owner, e);
+                        return Step::Failed(Failure::new(owner, e));
+                    }
+                };
+                let stake_lookup = match stake_epoch {
+                    Some(ref at) => tool.stake_accounts(&owner, at).await,
+                    None => Err(anyhow!("the stake history could not be read").into()),
+                };
+                if let Err(ref e) = stake_lookup {
+                    error!(
+                        "Failed to get the stake accounts of {} with error: {}",
+                        owner, e
+                    );
+                }
+                let stake_accounts = stake_lookup.as_ref().ok();
+                let staked = stake_accounts
+                    .map(|accounts| accounts.iter().map(|account| account.lamports).sum::<u64>());
+                // what the wallet holds of each mint over all its token accounts
+                let mut mints: Vec<(Pubkey, u8, u64)> = vec![];
+                for holding in holdings.iter().filter(|holding| holding.amount > 0) {
+                    match mints.iter_mut().find(|(mint, _, _)| *mint == holding.mint) {
+                        Some((_, _, amount)) => *amount += holding.amount,
+                        None => mints.push((holding.mint, holding.decimals, holding.amount)),
+                    }
+                }
+                let tokens: Vec<String> = mints
+                    .iter()
+                    .map(|(mint, decimals, amount)| {
+                        format!(
+                            "{} {}",
+                            spl_token::amount_to_ui_amount_string(*amount, *decimals),
+                            mint
+                        )
+                    })
+                    .collect();
+                let stake = match (stake_accounts, staked) {
+                    (Some(accounts), Some(staked)) => {
+                        format!("{} lamports in {} stake accounts", staked, accounts.len())
+                    }
+                    _ => "unknown stake".to_string(),
+                };
+                info!(
+                    "{}: {} lamports, {}, tokens: [{}]",
+                    owner,
+                    lamports,
+                    stake,
+                    tokens.join(", ")
+                );
+                tool.output.emit(Record::Portfolio {
+                    wallet: owner.to_string(),
+                    lamports,
+                    tokens: holdings
+                        .iter()
+                        .map(|holding| PortfolioToken {
+                            account: holding.address.to_string(),
+                            mint: holding.mint.to_string(),
+                            amount: holding.amount,
+                            decimals: holding.decimals,
+                        })
+                        .collect(),
+                    stake_accounts: stake_accounts.map(Vec::len),
+                    staked_lamports: staked,
+                    active_stake: stake_accounts.map(|accounts| {
+                        accounts
+                            .iter()
+                            .filter(|account| account.activation == Activation::Active)
+                            .map(|account| account.stake)
+                            .sum()
+                    }),
+                });
+                let mut totals = totals.lock().await;
+                totals.0 += lamports;
+                totals.1 += staked.unwrap_or(0);
+                Step::of(owner, stake_lookup)
+            }));
         }
+        let mut outcome = Outcome::default();
+        outcome.join(join_all(tasks).await);
+        let (lamports, staked) = *totals.lock().await;
+        info!(
+            "Total of all sub wallets: {} lamports, {} lamports staked",
+            lamports, staked
+        );
+        Ok(outcome)
+    }
+}
+
+/// A `COption` address of a token account for the logs.
+fn optional(address: COption<Pubkey>) -> String {
+    match address {
+        COption::Some(address) => address.to_string(),
+        COption::None => "none".to_string(),
     }
 }
@@ -0,0 +1,138 @@
+use std::fs;
+use std::path::Path;
+use std::sync::Arc;
+
+use log::{debug, error};
+use solana_sdk::{pubkey::Pubkey, signature::Keypair, signer::Signer};
+
+use crate::error::{Error, Result};
+use crate::outcome::Failure;
+use crate::utils::read_keypair;
+
+/// Signs for a wallet, a local keypair or a remote signer.
+pub type WalletSigner = dyn Signer + Send + Sync;
+pub type SharedSigner = Arc<WalletSigner>;
+
+/// A sub wallet and where it was read from.
+#[derive(Clone)]
+pub struct Wallet {
+    /// The file, line or derivation path of the keypair.
+    pub origin: String,
+    pub signer: SharedSigner,
+    /// The secret key, `None` when a remote signer holds it.
+    pub keypair: Option<Arc<Keypair>>,
+}
+
+impl Wallet {
+    pub fn pubkey(&self) -> Pubkey {
+        self.signer.pubkey()
+    }
+    /// The secret key, for the commands which export it.
+    pub fn secret(&self) -> Result<&Keypair> {
+        self.keypair.as_deref().ok_or_else(|| {
+            Error::keypair(
+                &self.origin,
+                "the key is held by a remote signer and can not be exported",
+            )
+        })
+    }
+}
+
+/// The sub wallets a batch runs over.
+#[derive(Default)]
+pub struct WalletSet {
+    pub wallets: Vec<Wallet>,
+    /// The files or lines of the source which are not a keypair.
+    pub unreadable: Vec<Failure>,
+}
+
+impl WalletSet {
+    /// Read every keypair json file of `folder`, the files which can not be
+    /// read are kept in `unreadable`.
+    pub fn load(folder: impl AsRef<Path>) -> Result<Self> {
+        let folder = folder.as_ref();
+        let entries = fs::read_dir(folder).map_err(|e| Error::io(folder.display(), e))?;
+        let mut wallet_set = WalletSet::default();
+        for entry in entries {
+            let path = entry.map_err(|e| Error::io(folder.display(), e))?.path();
+            // a keypair is a json file, anything else in the folder is not one
+            if !path.is_file() || path.extension().is_none_or(|extension| extension != "json") {
+                debug!("Skip {:?}, it is not a keypair json file", &path);
+                continue;
+            }
+            match read_keypair(&path) {
+                Ok(keypair) => wallet_set.push(path.display(), keypair),
+                Err(e) => {
+                    error!("Faild to read keypair from {:?} with error: {}", &path, e);
+                    wallet_set.unreadable.push(Failure::new(path.display(), e));
+                }
+            }
+        }
+        Ok(wallet_set)
+    }
+    pub fn from_keypairs(keypairs: impl IntoIterator<Item = Keypair>) -> Self {
+        WalletSet {
+            wallets: keypairs
+                .into_iter()
+                .map(|keypair| {
+                    let keypair = Arc::new(keypair);
+                    Wallet {
+                        origin: keypair.pubkey().to_string(),
+                        signer: keypair.clone(),
+                        keypair: Some(keypair),
+                    }
+                })
+                .collect(),
+            unreadable: vec![],
+        }
+    }
+    pub fn push(&mut self, origin: impl ToString, keypair: Keypair) {
+        let keypair = Arc::new(keypair);
+        self.wallets.push(Wallet {
+            origin: origin.to_string(),
+            signer: keypair.clone(),
+            keypair: Some(keypair),
+        });
+    }
+    /// Add a wallet whose secret key is not in this process.
+    pub fn push_signer(&mut self, origin: impl ToString, signer: SharedSigner) {
+        self.wallets.push(Wallet {
+            origin: origin.to_string(),
+            signer,
+            keypair: None,
+        });
+    }
+    pub fn len(&self) -> usize {
+        self.wallets.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.wallets.is_empty()
+    }
+    pub fn iter(&self) -> impl Iterator<Item = &Wallet> {
+        self.wallets.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_sdk::signature::write_keypair_file;
+
+    #[test]
+    fn loads_the_json_files_of_a_folder_only() {
+        let folder = std::env::temp_dir().join(format!("solana_tool-subs-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&folder);
+        fs::create_dir_all(folder.join("nested")).unwrap();
+        let keypair = Keypair::new();
+        write_keypair_file(&keypair, folder.join("wallet.json")).unwrap();
+        fs::write(folder.join(".DS_Store"), "junk").unwrap();
+        fs::write(folder.join("README"), "notes").unwrap();
+        fs::write(folder.join("broken.json"), "[1, 2]").unwrap();
+        let wallet_set = WalletSet::load(&folder).unwrap();
+        fs::remove_dir_all(&folder).unwrap();
+        assert_eq!(wallet_set.len(), 1);
+        assert_eq!(wallet_set.iter().next().unwrap().pubkey(), keypair.pubkey());
+        // a json file which is not a keypair still counts as unreadable
+        assert_eq!(wallet_set.unreadable.len(), 1);
+    }
+}
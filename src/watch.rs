@@ -0,0 +1,202 @@
+use anyhow::Context;
+use futures::stream::{select_all, StreamExt};
+use log::{error, info, warn};
+use solana_account_decoder::UiAccountEncoding;
+use solana_client::rpc_config::RpcAccountInfoConfig;
+use solana_program::program_pack::Pack;
+use solana_sdk::{account::Account, pubkey::Pubkey};
+use tokio::time::{sleep, Duration};
+
+use crate::error::{Error, Result};
+use crate::outcome::Outcome;
+use crate::output::{Output, Record};
+use crate::wallet_set::WalletSet;
+use crate::Tool;
+
+/// `getMultipleAccounts` takes at most this many keys.
+const MAX_MULTIPLE_ACCOUNTS: usize = 100;
+
+#[derive(Clone, Copy)]
+enum WatchKind {
+    Sol,
+    Token,
+}
+
+struct WatchTarget {
+    owner: Pubkey,
+    account: Pubkey,
+    kind: WatchKind,
+    mint: Option<Pubkey>,
+}
+
+impl WatchTarget {
+    fn balance(&self, account: Option<&Account>) -> u64 {
+        match (self.kind, account) {
+            (_, None) => 0,
+            (WatchKind::Sol, Some(account)) => account.lamports,
+            (WatchKind::Token, Some(account)) => spl_token::state::Account::unpack(&account.data)
+                .map(|token_account| token_account.amount)
+                .unwrap_or_default(),
+        }
+    }
+    fn unit(&self) -> &'static str {
+        match self.kind {
+            WatchKind::Sol => "sol",
+            WatchKind::Token => "spl token",
+        }
+    }
+}
+
+impl Tool {
+    /// Stream the sol balance, and the token balance when a mint is given, of
+    /// every sub wallet until ctrl-c. Uses `accountSubscribe` and falls back
+    /// to polling when there is no websocket.
+    pub async fn watch_wallet_balance(
+        &self,
+        wallets: &WalletSet,
+        token_pubkey: Option<Pubkey>,
+        interval: u64,
+    ) -> Result<Outcome> {
+        let mut targets = vec![];
+        for wallet in wallets.iter() {
+            let owner = wallet.pubkey();
+            targets.push(WatchTarget {
+                owner,
+                account: owner,
+                kind: WatchKind::Sol,
+                mint: None,
+            });
+            if let Some(ref token_pubkey) = token_pubkey {
+                targets.push(WatchTarget {
+                    owner,
+                    account: spl_associated_token_account::get_associated_token_address(
+                        &owner,
+                        token_pubkey,
+                    ),
+                    kind: WatchKind::Token,
+                    mint: Some(*token_pubkey),
+                });
+            }
+        }
+        let mut balances = self.fetch_watch_balances(&targets).await?;
+        for (target, balance) in targets.iter().zip(&balances) {
+            info!("{} {} balance: {}", &target.owner, target.unit(), balance);
+            self.output.emit(Record::balance(
+                &target.owner,
+                target.mint.as_ref(),
+                &Ok::<_, Error>(*balance),
+            ));
+        }
+        info!("Watching {} accounts, press ctrl-c to stop", targets.len());
+        match self.rpc_pool.pubsub() {
+            Some(pubsub) => {
+                let config = RpcAccountInfoConfig {
+                    encoding: Some(UiAccountEncoding::Base64),
+                    commitment: Some(self.rpc_pool.commitment()),
+                    ..RpcAccountInfoConfig::default()
+                };
+                let mut streams = vec![];
+                let mut unsubscribes = vec![];
+                for (index, target) in targets.iter().enumerate() {
+                    let (stream, unsubscribe) = pubsub
+                        .account_subscribe(&target.account, Some(config.clone()))
+                        .await
+                        .with_context(|| {
+                            format!("Failed to subscribe the account {}", &target.account)
+                        })?;
+                    streams.push(stream.map(move |response| (index, response)).boxed());
+                    unsubscribes.push(unsubscribe);
+                }
+                let mut notifications = select_all(streams);
+                loop {
+                    tokio::select! {
+                        _ = tokio::signal::ctrl_c() => break,
+                        notification = notifications.next() => match notification {
+                            Some((index, response)) => {
+                                let account = response.value.decode::<Account>();
+                                let balance = targets[index].balance(account.as_ref());
+                                report_change(&self.output, &targets[index], &mut balances[index], balance);
+                            }
+                            None => {
+                                warn!("The websocket closed, fall back to polling");
+                                self.poll_watch_balances(&targets, &mut balances, interval)
+                                    .await?;
+                                break;
+                            }
+                        }
+                    }
+                }
+                for unsubscribe in unsubscribes {
+                    unsubscribe().await;
+                }
+            }
+            None => {
+                self.poll_watch_balances(&targets, &mut balances, interval)
+                    .await?
+            }
+        }
+        Ok(Outcome::default())
+    }
+    async fn poll_watch_balances(
+        &self,
+        targets: &[WatchTarget],
+        balances: &mut [u64],
+        interval: u64,
+    ) -> Result<()> {
+        info!("Polling the balances every {} seconds", interval);
+        loop {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => return Ok(()),
+                _ = sleep(Duration::from_secs(interval)) => {}
+            }
+            match self.fetch_watch_balances(targets).await {
+                Ok(latest) => {
+                    for ((target, balance), latest) in
+                        targets.iter().zip(balances.iter_mut()).zip(latest)
+                    {
+                        report_change(&self.output, target, balance, latest);
+                    }
+                }
+                Err(e) => {
+                    error!("Failed to poll the balances with error: {}", e)
+                }
+            }
+        }
+    }
+    async fn fetch_watch_balances(&self, targets: &[WatchTarget]) -> Result<Vec<u64>> {
+        let mut balances = Vec::with_capacity(targets.len());
+        for chunk in targets.chunks(MAX_MULTIPLE_ACCOUNTS) {
+            let pubkeys: Vec<Pubkey> = chunk.iter().map(|target| target.account).collect();
+            let pubkeys = &pubkeys;
+            let accounts = self
+                .rpc_pool
+                .call(|client| async move { client.get_multiple_accounts(pubkeys).await })
+                .await?;
+            for (target, account) in chunk.iter().zip(accounts) {
+                balances.push(target.balance(account.as_ref()));
+            }
+        }
+        Ok(balances)
+    }
+}
+
+fn report_change(output: &Output, target: &WatchTarget, balance: &mut u64, latest: u64) {
+    if latest != *balance {
+        info!(
+            "{} {} balance changed: {} -> {} ({:+})",
+            &target.owner,
+            target.unit(),
+            balance,
+            latest,
+            latest as i128 - *balance as i128
+        );
+        output.emit(Record::BalanceChange {
+            wallet: target.owner.to_string(),
+            account: target.account.to_string(),
+            mint: target.mint.map(|mint| mint.to_string()),
+            before: *balance,
+            after: latest,
+        });
+        *balance = latest;
+    }
+}
@@ -0,0 +1,238 @@
+use log::{error, info, warn};
+use solana_sdk::{instruction::Instruction, pubkey::Pubkey, system_instruction};
+use spl_associated_token_account::{
+    get_associated_token_address, instruction::create_associated_token_account_idempotent,
+};
+use spl_token::{
+    instruction::{close_account, sync_native},
+    native_mint,
+};
+
+use crate::error::Result;
+use crate::outcome::Failure;
+use crate::plan::RunSummary;
+use crate::report::{
+    join_entries, CloseEntry, CloseReport, Status, Transfer, TransferEntry, TransferReport,
+};
+use crate::wallet_set::WalletSet;
+use crate::Tool;
+
+/// Whether `mint` is wrapped sol, whose token accounts hold their balance as
+/// lamports.
+pub fn is_native(mint: &Pubkey) -> bool {
+    *mint == native_mint::id()
+}
+
+/// Put `amount` lamports of `payer` into the wsol account of `owner`, which is
+/// created when missing.
+pub(crate) fn wrap_instructions(
+    payer: &Pubkey,
+    owner: &Pubkey,
+    amount: u64,
+) -> Result<Vec<Instruction>> {
+    let account = get_associated_token_address(owner, &native_mint::id());
+    Ok(vec![
+        create_associated_token_account_idempotent(
+            payer,
+            owner,
+            &native_mint::id(),
+            &spl_token::id(),
+        ),
+        system_instruction::transfer(payer, &account, amount),
+        sync_native(&spl_token::id(), &account)?,
+    ])
+}
+
+/// Close the wsol account `account` of `owner`, its balance and rent go to
+/// `destination` as sol.
+pub(crate) fn unwrap_instruction(
+    account: &Pubkey,
+    owner: &Pubkey,
+    destination: &Pubkey,
+) -> Result<Instruction> {
+    Ok(close_account(
+        &spl_token::id(),
+        account,
+        destination,
+        owner,
+        &[owner],
+    )?)
+}
+
+/// Wrap the same amount of sol in every wallet of a set, each wallet pays
+/// its own fee.
+pub struct WrapSol {
+    amount: u64,
+}
+
+impl WrapSol {
+    /// `amount` is in lamports.
+    pub fn new(amount: u64) -> Self {
+        Self { amount }
+    }
+    /// Work out what `run` is going to wrap, nothing is sent.
+    pub async fn plan(&self, tool: &Tool, wallets: &WalletSet) -> Result<RunSummary> {
+        let accounts: Vec<Pubkey> = wallets
+            .iter()
+            .map(|wallet| get_associated_token_address(&wallet.pubkey(), &native_mint::id()))
+            .collect();
+        let accounts_to_create = tool.count_missing(&accounts).await?;
+        let mut summary = RunSummary {
+            cluster: tool.cluster().await?,
+            recipients: wallets.len(),
+            total_amount: self.amount * wallets.len() as u64,
+            mint: Some(native_mint::id()),
+            decimals: native_mint::DECIMALS,
+            accounts_to_create,
+            account_rent: tool.token_account_rent(accounts_to_create).await?,
+            estimated_fee: 0,
+            allocation: vec![],
+            transfers: vec![],
+            frozen: vec![],
+        };
+        if let Some(sample) = wallets.iter().next() {
+            let owner = sample.pubkey();
+            let instructions = wrap_instructions(&owner, &owner, self.amount)?;
+            summary.estimated_fee =
+                tool.estimate_fee(instructions, &owner).await? * wallets.len() as u64;
+        }
+        Ok(summary)
+    }
+    pub async fn run(&self, tool: &Tool, wallets: &WalletSet) -> Result<TransferReport> {
+        let planned: Vec<Transfer> = wallets
+            .iter()
+            .map(|wallet| Transfer {
+                from: wallet.pubkey(),
+                to: wallet.pubkey(),
+                mint: Some(native_mint::id()),
+                amount: self.amount,
+            })
+            .collect();
+        tool.authorize(&planned, false)?;
+        let mut tasks = vec![];
+        for (wallet, transfer) in wallets.iter().zip(planned) {
+            let signer = wallet.signer.clone();
+            let tool = tool.clone();
+            let task = tokio::spawn(async move {
+                let result =
+                    match wrap_instructions(&transfer.from, &transfer.from, transfer.amount) {
+                        Ok(instructions) => {
+                            tool.sendtxn_and_watch(instructions, &*signer, &*signer)
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    };
+                match result {
+                    Ok(ref signature) => info!(
+                        "Successfuly wrapped {} lamports of {}, check the info: {}",
+                        transfer.amount,
+                        transfer.from,
+                        tool.explorer_link(signature)
+                    ),
+                    Err(ref e) => error!(
+                        "Failed to wrap the sol of {} with error: {}",
+                        transfer.from, e
+                    ),
+                }
+                let entry = TransferEntry {
+                    transfer,
+                    status: Status::of(transfer.from, result),
+                };
+                tool.output.emit(entry.record());
+                entry
+            });
+            tasks.push((transfer, task));
+        }
+        let transfers = join_entries(tasks, |transfer, failure| TransferEntry {
+            transfer,
+            status: Status::Failed(failure),
+        })
+        .await;
+        let mut report = TransferReport {
+            transfers,
+            failures: vec![],
+        };
+        let sent = report.sent();
+        report.failures.extend(tool.record_spending(&sent));
+        Ok(report)
+    }
+}
+
+/// Close every wsol account of every wallet of a set, the sol goes back to
+/// the wallet, which pays the fee.
+#[derive(Default)]
+pub struct UnwrapSol;
+
+impl UnwrapSol {
+    pub fn new() -> Self {
+        Self
+    }
+    pub async fn run(&self, tool: &Tool, wallets: &WalletSet) -> Result<CloseReport> {
+        let mut report = CloseReport::default();
+        let mut skipped = vec![];
+        let mut tasks = vec![];
+        for wallet in wallets.iter() {
+            let owner = wallet.pubkey();
+            let holdings = match tool.token_holdings(&owner, Some(&native_mint::id())).await {
+                Ok(holdings) => holdings,
+                Err(e) => {
+                    error!(
+                        "Failed to list the wsol accounts of {} with error: {}",
+                        owner, e
+                    );
+                    report.failures.push(Failure::new(owner, e));
+                    continue;
+                }
+            };
+            if holdings.is_empty() {
+                warn!("{} has no wsol account, skip it", owner);
+                skipped.push(CloseEntry {
+                    wallet: owner,
+                    account: get_associated_token_address(&owner, &native_mint::id()),
+                    status: Status::Skipped,
+                });
+            }
+            for holding in holdings {
+                let signer = wallet.signer.clone();
+                let tool = tool.clone();
+                let task = tokio::spawn(async move {
+                    let result = match unwrap_instruction(&holding.address, &owner, &owner) {
+                        Ok(instruction) => {
+                            tool.sendtxn_and_watch(vec![instruction], &*signer, &*signer)
+                                .await
+                        }
+                        Err(e) => Err(e),
+                    };
+                    match result {
+                        Ok(ref signature) => info!(
+                            "Successfuly unwrapped {} lamports of {}, check the info: {}",
+                            holding.amount,
+                            owner,
+                            tool.explorer_link(signature)
+                        ),
+                        Err(ref e) => error!(
+                            "Failed to unwrap {} of {} with error: {}",
+                            holding.address, owner, e
+                        ),
+                    }
+                    let entry = CloseEntry {
+                        wallet: owner,
+                        account: holding.address,
+                        status: Status::of(holding.address, result),
+                    };
+                    tool.output.emit(entry.record());
+                    entry
+                });
+                tasks.push(((owner, holding.address), task));
+            }
+        }
+        report.accounts = join_entries(tasks, |(wallet, account), failure| CloseEntry {
+            wallet,
+            account,
+            status: Status::Failed(failure),
+        })
+        .await;
+        report.accounts.extend(skipped);
+        Ok(report)
+    }
+}